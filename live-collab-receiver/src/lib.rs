@@ -1,23 +1,68 @@
+use live_collab_ui::*;
 use shared::*;
 
-use crossbeam::queue::SegQueue;
+use crossbeam::queue::{ArrayQueue, SegQueue};
 use nih_plug::prelude::*;
 use nih_plug_egui::{
     create_egui_editor,
     egui::{self, Color32, CornerRadius, Vec2},
     resizable_window::ResizableWindow,
+    widgets::ParamSlider,
     EguiState,
 };
-use tokio::{io::AsyncReadExt, runtime::Runtime};
-use webrtc::{data_channel::data_channel_message::DataChannelMessage, peer_connection::peer_connection_state::RTCPeerConnectionState};
-use std::{io::Cursor, sync::{Arc, LazyLock, Mutex}};
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use std::{io, sync::{atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering}, Arc, LazyLock, Mutex, RwLock}, time::{Duration, SystemTime}};
 
 static TEXT_VALUE_ENTRY_MEMORY_ID: LazyLock<egui::Id> = LazyLock::new(|| egui::Id::new((file!(), 3)));
-static PAGE_MEMORY_ID: LazyLock<egui::Id> = LazyLock::new(|| egui::Id::new((file!(), 4)));
-static WEBRTC_MEMORY_ID: LazyLock<egui::Id> = LazyLock::new(|| egui::Id::new((file!(), 5)));
+static PASSPHRASE_MEMORY_ID: LazyLock<egui::Id> = LazyLock::new(|| egui::Id::new((file!(), 7)));
+static ERROR_VALUE_ENTRY_MEMORY_ID: LazyLock<egui::Id> = LazyLock::new(|| egui::Id::new((file!(), 8)));
+
+// How often buffer stats are reported back to the sender
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+// Ramps that keep gain, mix and mute changes free of clicks and zipper noise
+const GAIN_SMOOTHING_MS: f32 = 50.0;
+const MUTE_RAMP_MS: f32 = 10.0;
+const MIX_MODE_RAMP_MS: f32 = 20.0;
+
+// Room for half a second of the monitor feed at 192 kHz, allocated up front so the audio thread never has to
+const MONITOR_FEED_CAPACITY: usize = 96_000;
+// How often the network side picks up the monitor feed and sends it to the sender
+const MONITOR_SEND_INTERVAL: Duration = Duration::from_millis(5);
+
+// Where the remote audio ends up
+#[derive(Enum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MixMode {
+    // Crossfade from the track's input to the remote audio with the mix parameter
+    #[name = "Replace"]
+    Replace,
+    // Remote audio on top of the track's input
+    #[name = "Sum With Input"]
+    Sum,
+    // Track's input passes through, remote audio goes to the aux output for routing elsewhere
+    #[name = "Aux Output"]
+    Aux,
+}
 
 pub struct Receiver {
     params: Arc<ReceiverParams>,
+    sample_rate: f32,
+    max_buffer_size: u32,
+    // Latency last reported to the host, so it is only updated when it changes
+    reported_latency: u32,
+    // In fixed latency mode, playback holds off until the buffer reaches the target depth
+    prebuffering: bool,
+    // Nothing has played since the buffer last ran dry (or ever), so the next miss is not a new underrun
+    starved: bool,
+    // Ramps the output in and out when muting, a bool param has no smoother of its own
+    mute: Smoother<f32>,
+    // How much of each mix mode's output is heard, indexed by MixMode. Switching modes crossfades between them.
+    mix_modes: [Smoother<f32>; 3],
+    // Song position tags (stream sample index, sender position) bracketing the sample about to be played
+    current_position_tag: Option<(u64, i64)>,
+    next_position_tag: Option<(u64, i64)>,
+    // First MIDI event that belongs to a sample not played yet
+    next_midi_event: Option<(u64, MidiMessage)>,
 }
 
 #[derive(Params)]
@@ -25,28 +70,147 @@ pub struct ReceiverParams {
     #[persist = "editor-state"]
     editor_state: Arc<EguiState>,
 
-    pub page: IntParam,
+    #[id = "fixed-latency"]
+    pub fixed_latency: BoolParam,
+    #[id = "target-latency"]
+    pub target_latency_ms: IntParam,
+    #[id = "tag-song-position"]
+    pub tag_song_position: BoolParam,
+    #[id = "output-gain"]
+    pub output_gain: FloatParam,
+    #[id = "mute"]
+    pub mute: BoolParam,
+    // 0 is only the track's input, 1 only the remote audio
+    #[id = "mix"]
+    pub mix: FloatParam,
+    #[id = "mix-mode"]
+    pub mix_mode: EnumParam<MixMode>,
+    // Sends the main output back, for a sender monitoring the receiver's return
+    #[id = "send-monitor"]
+    pub send_monitor: BoolParam,
     
-    pub runtime: Runtime,
-    pub messages: Arc<SegQueue<f32>>,
+    pub runtime: PluginRuntime,
+    pub connection: ConnectionSlot,
+    pub identity: Option<Arc<Identity>>,
+    pub messages: Arc<SegQueue<StreamFrame>>,
+    // Main output on its way back to the sender, filled by the audio thread and sent from the runtime
+    pub monitor_feed: Arc<ArrayQueue<f32>>,
+    pub latency: Arc<LatencyTracker>,
+    pub remote_transport: Arc<Mutex<Option<TransportSnapshot>>>,
+    // Samples ever pushed to and taken from `messages`, used to line song position tags up with playback
+    pub pushed_samples: AtomicU64,
+    pub popped_samples: AtomicU64,
+    pub position_tags: SegQueue<(u64, i64)>,
+    // Incoming MIDI keyed by the stream sample index it lines up with
+    pub midi_events: SegQueue<(u64, MidiMessage)>,
+    // Sender song position of the audio currently playing, i64::MIN when unknown
+    pub playing_song_position: AtomicI64,
+    pub sample_rate: AtomicF32,
+    // Arrival of audio packets on the network side, playback troubles on the audio thread
+    pub stream_monitor: Mutex<StreamMonitor>,
+    // The audio channel is unordered, packets are put back in sequence before anything is keyed by stream position
+    pub reorder: Mutex<ReorderBuffer<AudioPacket>>,
+    pub playback: PlaybackCounters,
+    pub stats_history: Arc<Mutex<StatsHistory>>,
+    // Level of the decoded stream as it is played
+    pub receive_meter: LevelMeter,
+    // Format the sender last announced, and why it left if it said goodbye
+    pub remote_format: Arc<Mutex<Option<AudioFormat>>>,
+    pub peer_left: Arc<Mutex<Option<String>>>,
+    // Whether the sender muted what it sends, silence is then expected rather than a problem
+    pub sender_muted: AtomicBool,
+    // Recording of the received stream while one runs, and how the last one ended
+    pub recording: Mutex<Option<Recording>>,
+    pub recording_status: Mutex<Option<String>>,
+
+    #[persist = "session"]
+    pub session: Arc<RwLock<SessionConfig>>,
+    #[persist = "chat-history"]
+    pub chat_history: Arc<RwLock<ChatHistory>>,
 }
 
 impl Default for Receiver {
     fn default() -> Self {
         Self {
             params: Arc::new(ReceiverParams::default()),
+            sample_rate: 44100.0,
+            max_buffer_size: 0,
+            reported_latency: 0,
+            prebuffering: true,
+            starved: true,
+            mute: Smoother::new(SmoothingStyle::Linear(MUTE_RAMP_MS)),
+            mix_modes: std::array::from_fn(|_| Smoother::new(SmoothingStyle::Linear(MIX_MODE_RAMP_MS))),
+            current_position_tag: None,
+            next_position_tag: None,
+            next_midi_event: None,
         }
     }
 }
 
+// Closing the connection also drops the channel handlers holding on to the params, so the runtime goes with them
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.params.connection.shutdown(&self.params.runtime, "plugin removed");
+    }
+}
+
 impl Default for ReceiverParams {
     fn default() -> Self {
         Self {
             editor_state: EguiState::from_size(300, 180),
 
-            page: IntParam::new("page", 0, IntRange::Linear { min: 0, max: 1 }),
+            fixed_latency: BoolParam::new("Fixed Latency", false),
+            target_latency_ms: IntParam::new("Target Latency", 100, IntRange::Linear { min: 5, max: 1000 }).with_unit(" ms"),
             messages: Default::default(),
-            runtime: Runtime::new().unwrap(),
+            monitor_feed: Arc::new(ArrayQueue::new(MONITOR_FEED_CAPACITY)),
+            runtime: PluginRuntime::new().unwrap(),
+            connection: Default::default(),
+            identity: Identity::load_default()
+                .map_err(|err| nih_log!("No persistent identity, peers will not recognize this plugin: {err}"))
+                .ok()
+                .map(Arc::new),
+            latency: Default::default(),
+            tag_song_position: BoolParam::new("Tag Audio With Sender Position", false),
+            output_gain: FloatParam::new(
+                "Output Gain",
+                util::db_to_gain(0.0),
+                FloatRange::Skewed {
+                    min: util::db_to_gain(-60.0),
+                    max: util::db_to_gain(12.0),
+                    factor: FloatRange::gain_skew_factor(-60.0, 12.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Logarithmic(GAIN_SMOOTHING_MS))
+            .with_unit(" dB")
+            .with_value_to_string(formatters::v2s_f32_gain_to_db(2))
+            .with_string_to_value(formatters::s2v_f32_gain_to_db()),
+            mute: BoolParam::new("Mute", false),
+            mix: FloatParam::new("Mix", 1.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_smoother(SmoothingStyle::Linear(GAIN_SMOOTHING_MS))
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+            mix_mode: EnumParam::new("Mix Mode", MixMode::Replace),
+            send_monitor: BoolParam::new("Send Monitor Feed To Sender", false),
+            remote_transport: Default::default(),
+            pushed_samples: Default::default(),
+            popped_samples: Default::default(),
+            position_tags: Default::default(),
+            midi_events: Default::default(),
+            playing_song_position: AtomicI64::new(i64::MIN),
+            sample_rate: AtomicF32::new(44100.0),
+            stream_monitor: Default::default(),
+            reorder: Default::default(),
+            playback: Default::default(),
+            stats_history: Default::default(),
+            receive_meter: Default::default(),
+            remote_format: Default::default(),
+            peer_left: Default::default(),
+            sender_muted: Default::default(),
+            recording: Default::default(),
+            recording_status: Default::default(),
+            session: Arc::new(RwLock::new(SessionConfig::new(SessionRole::Receiver))),
+            chat_history: Default::default(),
         }
     }
 }
@@ -63,15 +227,20 @@ impl Plugin for Receiver {
         AudioIOLayout {
             main_input_channels: NonZeroU32::new(2),
             main_output_channels: NonZeroU32::new(2),
+            aux_output_ports: &[new_nonzero_u32(2); MAX_STREAMS],
+            names: PortNames { aux_outputs: &["Remote", "Stem 2", "Stem 3", "Stem 4"], ..PortNames::const_default() },
             ..AudioIOLayout::const_default()
         },
         AudioIOLayout {
             main_input_channels: NonZeroU32::new(1),
             main_output_channels: NonZeroU32::new(1),
+            aux_output_ports: &[new_nonzero_u32(1); MAX_STREAMS],
+            names: PortNames { aux_outputs: &["Remote", "Stem 2", "Stem 3", "Stem 4"], ..PortNames::const_default() },
             ..AudioIOLayout::const_default()
         },
     ];
 
+    const MIDI_OUTPUT: MidiConfig = MidiConfig::MidiCCs;
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
     type SysExMessage = ();
@@ -89,7 +258,7 @@ impl Plugin for Receiver {
             self.params.editor_state.clone(),
             (),
             |_, _| {},
-            move |egui_ctx, _, _state| {
+            move |egui_ctx, setter, _state| {
                 ResizableWindow::new("Live Collab Receiver")
                     .min_size(Vec2::new(128.0, 128.0))
                     .show(egui_ctx, egui_state.as_ref(), |ui| {
@@ -101,20 +270,107 @@ impl Plugin for Receiver {
                             style.visuals.extreme_bg_color = Color32::from_rgb(29, 31, 36);
                         });
 
-                        let connection: Option<WebRTCConnection> = ui.memory(|mem| { mem.data.get_temp(*WEBRTC_MEMORY_ID) });
+                        let connection = params.connection.get();
+
+                        identity_panel(ui, params.identity.as_deref(), connection.as_deref(), &params.session);
 
                         if let Some(connection) = &connection {
-                            ui.label(format!("Connection State: {}", connection.peer.connection_state().to_string()));
+                            ui.label(format!("Connection State: {}", connection.lifecycle()));
+
+                            let latency = params.latency.estimate();
+                            ui.label(format!("Round-Trip Latency ({:.2} ms, jitter {:.2} ms)", latency.smoothed_rtt_ms, latency.jitter_ms));
+                            ui.label(format!("Mouth-to-Ear Latency ({:.1} ms)", latency.mouth_to_ear_ms));
+
+                            level_meter(ui, "Incoming", &params.receive_meter);
+                            signal_light(ui, "Remote signal present", params.receive_meter.signal_present());
+
+                            handshake_label(ui, &connection.handshake, "Receiving");
+                            encryption_label(ui, &connection.encryption);
+
+                            if let Some(transport) = *params.remote_transport.lock().unwrap() {
+                                ui.label(format!("Sender Transport: {transport}"));
+
+                                let position = params.playing_song_position.load(Ordering::Relaxed);
+                                if params.tag_song_position.value() && position != i64::MIN && transport.sample_rate > 0.0 {
+                                    ui.label(format!("Playing Sender Position: {position} samples ({:.2} s)", position as f64 / transport.sample_rate as f64));
+                                }
+                            }
+
+                            if let Some(format) = *params.remote_format.lock().unwrap() {
+                                ui.label(format!("Sender Format: {} Hz, {} ch, up to {} samples", format.sample_rate, format.channels, format.frame_size));
+
+                                let sample_rate = params.sample_rate.load(Ordering::Relaxed);
+                                if format.sample_rate != sample_rate {
+                                    ui.colored_label(Color32::YELLOW, format!("Sample rate mismatch: sender runs at {} Hz, this session at {sample_rate} Hz", format.sample_rate));
+                                }
+                            }
+
+                            if params.sender_muted.load(Ordering::Relaxed) {
+                                ui.colored_label(Color32::YELLOW, "Sender has muted what it sends");
+                            }
 
-                            if ui.button("Disconnect").clicked() {
-                                let conn_clone = connection.clone();
-                                params.runtime.spawn(async move { conn_clone.peer.close().await });
-                                ui.memory_mut(|mem| mem.data.insert_temp(*PAGE_MEMORY_ID, 0));
+                            if let Some(reason) = &*params.peer_left.lock().unwrap() {
+                                ui.label(format!("Peer left: {reason}"));
                             }
+
+                            stats_panel(ui, &params.stats_history, |ui| {
+                                let monitor = params.stream_monitor.lock().unwrap();
+                                ui.label(format!(
+                                    "Audio Packets: {} received, {} lost ({:.1}%), jitter {:.1} ms",
+                                    monitor.received(),
+                                    monitor.lost(),
+                                    monitor.loss_percent(),
+                                    monitor.jitter_ms()
+                                ));
+
+                                let buffered_ms = params.messages.len() as f32 * 1000.0 / params.sample_rate.load(Ordering::Relaxed);
+                                ui.label(format!("Buffer Depth: {buffered_ms:.1} ms"));
+                                ui.label(format!(
+                                    "Underruns: {}, Overruns: {}, Concealed: {} samples",
+                                    params.playback.underruns.load(Ordering::Relaxed),
+                                    params.playback.overruns.load(Ordering::Relaxed),
+                                    params.playback.concealed_samples.load(Ordering::Relaxed)
+                                ));
+                            });
+
+                            let format = params.remote_format.lock().unwrap().or(connection.handshake.agreed_format());
+                            recording_panel(ui, &params, format);
+
+                            if connection.lifecycle() != Lifecycle::Closed && ui.button("Disconnect").clicked() {
+                                params.connection.close(&params.runtime, "receiver disconnected");
+                            }
+                        }
+
+                        ui.label("Fixed Latency");
+                        ui.add(ParamSlider::for_param(&params.fixed_latency, setter));
+
+                        if params.fixed_latency.value() {
+                            ui.label("Target Latency (reported to host)");
+                            ui.add(ParamSlider::for_param(&params.target_latency_ms, setter));
                         }
 
-                        match ui.memory(|mem| { mem.data.get_temp(*PAGE_MEMORY_ID).unwrap_or(0) }) {
-                            0 => {
+                        ui.label("Tag Audio With Sender Position");
+                        ui.add(ParamSlider::for_param(&params.tag_song_position, setter));
+
+                        ui.label("Output Gain");
+                        ui.add(ParamSlider::for_param(&params.output_gain, setter));
+
+                        ui.label("Mute");
+                        ui.add(ParamSlider::for_param(&params.mute, setter));
+
+                        ui.label("Mix Mode");
+                        ui.add(ParamSlider::for_param(&params.mix_mode, setter));
+
+                        ui.label("Send Monitor Feed To Sender");
+                        ui.add(ParamSlider::for_param(&params.send_monitor, setter));
+
+                        if params.mix_mode.value() == MixMode::Replace {
+                            ui.label("Mix (input / remote)");
+                            ui.add(ParamSlider::for_param(&params.mix, setter));
+                        }
+
+                        match params.connection.lifecycle() {
+                            Lifecycle::Closed => {
                                 let value_entry_mutex = ui.memory_mut(|mem| {
                                     mem.data
                                         .get_temp_mut_or_default::<Arc<Mutex<String>>>(*TEXT_VALUE_ENTRY_MEMORY_ID)
@@ -125,40 +381,195 @@ impl Plugin for Receiver {
                                 let text_input_label = ui.label("Enter peer offer:");
                                 ui.text_edit_singleline(&mut *value_entry).labelled_by(text_input_label.id);
 
+                                let passphrase_mutex = ui.memory_mut(|mem| {
+                                    mem.data
+                                        .get_temp_mut_or_default::<Arc<Mutex<String>>>(*PASSPHRASE_MEMORY_ID)
+                                        .clone()
+                                });
+                                let passphrase = passphrase_mutex.lock().unwrap().clone();
+
+                                passphrase_entry(ui, &passphrase_mutex);
+                                session_settings(ui, &params.session, |ui, session| {
+                                    egui::ComboBox::from_label("Recording format")
+                                        .selected_text(session.recording_format.to_string())
+                                        .show_ui(ui, |ui| {
+                                            for format in [RecordingFormat::Wav, RecordingFormat::Flac] {
+                                                ui.selectable_value(&mut session.recording_format, format, format.to_string());
+                                            }
+                                        });
+
+                                    let recording_dir_label = ui.label("Recording folder (blank for the default):");
+                                    ui.text_edit_singleline(&mut session.recording_dir).labelled_by(recording_dir_label.id);
+                                });
+
+                                let error_value_entry_mutex = ui.memory_mut(|mem| {
+                                    mem.data
+                                        .get_temp_mut_or_default::<Arc<Mutex<String>>>(*ERROR_VALUE_ENTRY_MEMORY_ID)
+                                        .clone()
+                                });
+
                                 if ui.button("Connect").clicked() {
-                                    if let Ok(connection) = create_answerer(&params.runtime, value_entry.to_owned()) {
+                                    // Expired, altered or otherwise invalid offers are refused before anything is set up
+                                    let ice_config = IceConfig {
+                                        passphrase: Some(passphrase),
+                                        ..params.session.read().unwrap().ice_config(params.identity.as_deref())
+                                    };
+
+                                    let result = create_answerer_with_config(&params.runtime, &ice_config, value_entry.to_owned());
+                                    *error_value_entry_mutex.lock().unwrap() = result.as_ref().err().map(|err| err.to_string()).unwrap_or_default();
+
+                                    if let Ok(connection) = result {
                                         let params_clone = params.clone();
 
                                         *value_entry = Default::default();
 
+                                        let handshake = connection.handshake.clone();
+                                        let encryption = connection.encryption.clone();
+                                        let peer_confirmed = connection.peer_confirmed.clone();
                                         connection.channel.on_message(Box::new(move |msg: DataChannelMessage| {
                                             let p2 = params_clone.clone();
+                                            let received_ns = monotonic_ns();
+                                            let agreed_format = handshake.agreed_format().filter(|_| peer_confirmed.load(Ordering::Relaxed));
+                                            // Without a passphrase this hands the frame back as is
+                                            let data = encryption.open(msg.data);
                                             Box::pin(async move {
-                                                let mut rdr = Cursor::new(msg.data);
-                                                while let Ok(val) = rdr.read_f32_le().await {
-                                                    p2.messages.push(val);
+                                                // Audio from a peer we could not agree with, or the user has not allowed yet, is not played
+                                                let (Some(agreed_format), Some(data)) = (agreed_format, data) else { return };
+
+                                                // Each frame carries one sample of every stem
+                                                let streams = agreed_format.streams as usize;
+                                                let Some(packet) = AudioPacket::decode(&data, agreed_format.codec, streams) else { return };
+
+                                                let frames = packet.samples.len() / streams.clamp(1, MAX_STREAMS);
+
+                                                let stream_rate = p2.remote_format.lock().unwrap().map_or(0.0, |format| format.sample_rate);
+                                                p2.stream_monitor.lock().unwrap().on_packet(packet.seq, frames, data.len(), stream_rate, received_ns);
+
+                                                let mut recording = p2.recording.lock().unwrap();
+
+                                                // A new sample rate starts a new file, the old one is finished off the network thread
+                                                if recording.as_ref().is_some_and(|recording| stream_rate > 0.0 && recording.sample_rate() != stream_rate) {
+                                                    let finished = recording.take();
+                                                    std::thread::spawn(move || drop(finished));
+                                                    *recording = start_recording(&p2, &AudioFormat { sample_rate: stream_rate, ..agreed_format }).ok();
                                                 }
-                                            })
-                                        }));
 
-                                        let conn_clone = connection.clone();
-                                        connection.tcp_channel.on_message(Box::new(move |msg| {
-                                            let cc2 = conn_clone.clone();
-                                            Box::pin(async move {
-                                                let _ = cc2.tcp_channel.send(&bytes::Bytes::copy_from_slice(&msg.data)).await;
+                                                // The recording puts packets back in order itself
+                                                if let Some(recording) = recording.as_mut() {
+                                                    recording.write_packet(packet.seq, &packet.samples);
+                                                }
+
+                                                drop(recording);
+
+                                                p2.reorder.lock().unwrap().push(packet.seq, packet, |reordered| {
+                                                    let packet = match reordered {
+                                                        Reordered::Packet(_, packet) => packet,
+                                                        // Too late to be played, but its note-offs still end their notes instead of leaving them hanging
+                                                        Reordered::Late(_, packet) => {
+                                                            let stream_index = p2.pushed_samples.load(Ordering::Relaxed);
+                                                            for event in packet.midi.iter().filter(|event| matches!(event.message, MidiMessage::NoteOff { .. })) {
+                                                                p2.midi_events.push((stream_index, event.message));
+                                                            }
+                                                            return;
+                                                        }
+                                                        // Concealed by playback
+                                                        Reordered::Lost(_) => return,
+                                                    };
+
+                                                    let frames = packet.samples.len() / streams.clamp(1, MAX_STREAMS);
+                                                    let stream_index = p2.pushed_samples.fetch_add(frames as u64, Ordering::Relaxed);
+
+                                                    if let (true, Some(song_position)) = (p2.tag_song_position.value(), packet.song_position) {
+                                                        p2.position_tags.push((stream_index, song_position));
+                                                    }
+
+                                                    for event in &packet.midi {
+                                                        p2.midi_events.push((stream_index + event.timing as u64, event.message));
+                                                    }
+
+                                                    for frame in packet.frames(streams) {
+                                                        p2.messages.push(frame);
+                                                    }
+                                                });
                                             })
                                         }));
 
-                                        ui.memory_mut(|mem| mem.data.insert_temp(*WEBRTC_MEMORY_ID, connection));
-                                        ui.memory_mut(|mem| mem.data.insert_temp(*PAGE_MEMORY_ID, 1));
+                                        *params.remote_format.lock().unwrap() = None;
+                                        *params.peer_left.lock().unwrap() = None;
+                                        params.sender_muted.store(false, Ordering::Relaxed);
+                                        *params.stream_monitor.lock().unwrap() = Default::default();
+                                        *params.reorder.lock().unwrap() = Default::default();
+                                        while params.monitor_feed.pop().is_some() {}
+                                        params.stats_history.lock().unwrap().clear();
+                                        params.playback.underruns.store(0, Ordering::Relaxed);
+                                        params.playback.overruns.store(0, Ordering::Relaxed);
+                                        params.playback.concealed_samples.store(0, Ordering::Relaxed);
+
+                                        let params_clone = params.clone();
+                                        // Mono or stereo output both play the mono stream
+                                        let hello = params.session.read().unwrap().hello(Receiver::VERSION, params.sample_rate.load(Ordering::Relaxed), 2);
+                                        connection.start_control(&params.runtime, params.latency.clone(), hello, move |message| {
+                                            match message {
+                                                ControlMessage::Transport(snapshot) => *params_clone.remote_transport.lock().unwrap() = Some(snapshot),
+                                                ControlMessage::FormatChange(format) => *params_clone.remote_format.lock().unwrap() = Some(format),
+                                                ControlMessage::Bye { reason } => *params_clone.peer_left.lock().unwrap() = Some(reason),
+                                                ControlMessage::Mute { muted } => params_clone.sender_muted.store(muted, Ordering::Relaxed),
+                                                message => return params_clone.chat_history.write().unwrap().on_control(message),
+                                            }
+
+                                            None
+                                        });
+
+                                        let params_clone = params.clone();
+                                        connection.start_periodic(&params.runtime, STATS_INTERVAL, move || {
+                                            let sample_rate = params_clone.sample_rate.load(Ordering::Relaxed);
+                                            let monitor = params_clone.stream_monitor.lock().unwrap();
+                                            let playback = &params_clone.playback;
+
+                                            Some(ControlMessage::Stats(StatsReport {
+                                                buffered_ms: params_clone.messages.len() as f32 * 1000.0 / sample_rate,
+                                                packets_received: monitor.received(),
+                                                packets_lost: monitor.lost(),
+                                                jitter_ms: monitor.jitter_ms(),
+                                                underruns: playback.underruns.load(Ordering::Relaxed),
+                                                overruns: playback.overruns.load(Ordering::Relaxed),
+                                                concealed_samples: playback.concealed_samples.load(Ordering::Relaxed),
+                                                signal_present: params_clone.receive_meter.signal_present(),
+                                            }))
+                                        });
+
+                                        connection.start_stats(&params.runtime, STATS_POLL_INTERVAL, params.latency.clone(), params.stats_history.clone());
+
+                                        params.connection.replace(&params.runtime, connection);
+
+                                        // Sends what the audio thread queued of the monitor feed, until this connection is gone
+                                        if let Some(connection) = params.connection.get() {
+                                            let weak_connection = Arc::downgrade(&connection);
+                                            let feed = params.monitor_feed.clone();
+                                            params.runtime.spawn(async move {
+                                                let mut seq = 0u32;
+
+                                                loop {
+                                                    tokio::time::sleep(MONITOR_SEND_INTERVAL).await;
+                                                    let Some(connection) = weak_connection.upgrade() else { break };
+                                                    let frame_size = connection.handshake.agreed_format().map_or(MAX_FRAME_SIZE, |format| format.frame_size.max(1));
+
+                                                    while !feed.is_empty() {
+                                                        let samples = std::iter::from_fn(|| feed.pop()).take(frame_size as usize).collect();
+                                                        let packet = AudioPacket { seq, samples, ..Default::default() };
+                                                        seq = seq.wrapping_add(1);
+
+                                                        let _ = connection.send_audio(packet.to_bytes()).await;
+                                                    }
+                                                }
+                                            });
+                                        }
                                     }
                                 }
-                            },
-                            1 => {
-                                if ui.button("Go back").clicked() {
-                                    ui.memory_mut(|mem| mem.data.insert_temp(*PAGE_MEMORY_ID, 0));
-                                }
 
+                                ui.label(error_value_entry_mutex.lock().unwrap().to_owned());
+                            },
+                            _ => {
                                 if let Some(connection) = &connection {
                                     let send_label = ui.label("Send this to peer:");
                                     if ui.button("Copy Session Token").labelled_by(send_label.id).clicked() {
@@ -172,15 +583,26 @@ impl Plugin for Receiver {
                                             if params.messages.pop().is_none() {
                                                 break;
                                             }
-                                        }
-                                    }
 
-                                    if connection.peer.connection_state() == RTCPeerConnectionState::Failed {
-                                        ui.memory_mut(|mem| mem.data.insert_temp(*PAGE_MEMORY_ID, 0));
+                                            params.popped_samples.fetch_add(1, Ordering::Relaxed);
+                                        }
                                     }
                                 }
                             },
-                            _ => {}
+                        }
+
+                        if let Some(connection) = &connection {
+                            if connection.lifecycle() == Lifecycle::Connected {
+                                // Prefer the position of the audio we are hearing, then the sender's last reported transport
+                                let remote_transport = *params.remote_transport.lock().unwrap();
+                                let marker_position = remote_transport.and_then(|transport| {
+                                    let playing = params.playing_song_position.load(Ordering::Relaxed);
+                                    let position = if playing != i64::MIN { Some(playing) } else { transport.pos_samples };
+                                    position.map(|position| (position, transport.sample_rate))
+                                });
+
+                                chat_panel(ui, &params.runtime, connection, &params.chat_history, marker_position);
+                            }
                         }
                     });
             },
@@ -193,33 +615,275 @@ impl Plugin for Receiver {
         buffer_config: &BufferConfig,
         _context: &mut impl InitContext<Self>,
     ) -> bool {
+        self.sample_rate = buffer_config.sample_rate;
+        self.max_buffer_size = buffer_config.max_buffer_size;
+        self.params.sample_rate.store(self.sample_rate, Ordering::Relaxed);
+
         true
     }
 
+    // The session ends with the plugin, whether or not the editor is open
+    fn deactivate(&mut self) {
+        self.params.connection.shutdown(&self.params.runtime, "plugin deactivated");
+    }
+
+    fn reset(&mut self) {
+        self.prebuffering = true;
+        self.starved = true;
+        self.mute.reset(if self.params.mute.value() { 0.0 } else { 1.0 });
+
+        let mix_mode = self.params.mix_mode.value().to_index();
+        for (mode, weight) in self.mix_modes.iter().enumerate() {
+            weight.reset(if mode == mix_mode { 1.0 } else { 0.0 });
+        }
+    }
+
     fn process(
         &mut self,
         buffer: &mut Buffer,
-        _aux: &mut AuxiliaryBuffers,
-        _context: &mut impl ProcessContext<Self>,
+        aux: &mut AuxiliaryBuffers,
+        context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
         let num_samples = buffer.samples();
 
-        unsafe { buffer.set_slices(num_samples, |output| {
-            for i in 0..num_samples {
-                if let Some(recv_sample) = self.params.messages.pop() {
-                    output[0][i] = recv_sample;
-                    output[1][i] = recv_sample;
-                } else {
-                    output[0][i] = 0.0;
-                    output[1][i] = 0.0;
+        let fixed_latency = self.params.fixed_latency.value();
+        let target_samples = (self.params.target_latency_ms.value() as f32 * self.sample_rate / 1000.0) as usize;
+
+        let latency = if fixed_latency { target_samples as u32 } else { 0 };
+        if latency != self.reported_latency {
+            context.set_latency_samples(latency);
+            self.reported_latency = latency;
+        }
+
+        if fixed_latency {
+            let buffered = self.params.messages.len();
+
+            if self.prebuffering && buffered >= target_samples {
+                self.prebuffering = false;
+            }
+
+            // Drift or a burst of late packets grew the buffer, drop the oldest samples to get back to the reported latency
+            if !self.prebuffering && buffered > target_samples + (target_samples / 2).max(num_samples) {
+                let dropped = (target_samples..buffered).filter(|_| self.params.messages.pop().is_some()).count();
+                self.params.popped_samples.fetch_add(dropped as u64, Ordering::Relaxed);
+                self.params.playback.overruns.fetch_add(1, Ordering::Relaxed);
+            }
+        } else {
+            self.prebuffering = false;
+        }
+
+        self.update_playing_song_position();
+
+        let block_start = self.params.popped_samples.load(Ordering::Relaxed);
+
+        let mut prebuffering = self.prebuffering;
+        let mut starved = self.starved;
+        let mut popped = 0;
+        let mut concealed = 0;
+        let mut underruns = 0;
+        let mut peak = 0.0f32;
+        let mut sum_squares = 0.0;
+
+        // Silence only stands in for audio once the stream has started playing
+        let streaming = block_start > 0;
+
+        // Whatever is queued plays after the samples already handed to the host
+        self.params.latency.set_local_delay((self.params.messages.len() + self.max_buffer_size as usize) as u64, self.sample_rate);
+
+        self.mute.set_target(self.sample_rate, if self.params.mute.value() { 0.0 } else { 1.0 });
+
+        let mix_mode = self.params.mix_mode.value().to_index();
+        for (mode, weight) in self.mix_modes.iter().enumerate() {
+            weight.set_target(self.sample_rate, if mode == mix_mode { 1.0 } else { 0.0 });
+        }
+
+        // Only goes back once the sender is agreed on and trusted
+        let send_monitor = self
+            .params
+            .connection
+            .get()
+            .filter(|connection| self.params.send_monitor.value() && connection.lifecycle() == Lifecycle::Connected)
+            .filter(|connection| connection.peer_confirmed.load(Ordering::Relaxed))
+            .is_some_and(|connection| connection.handshake.agreed_format().is_some());
+
+        for (index, mut channel_samples) in buffer.iter_samples().enumerate() {
+            let remote_frame = if let Some(frame) = (!prebuffering).then(|| self.params.messages.pop()).flatten() {
+                popped += 1;
+                starved = false;
+
+                peak = peak.max(frame[0].abs());
+                sum_squares += frame[0] * frame[0];
+
+                frame
+            } else {
+                // An underrun in fixed latency mode restarts prebuffering so the delay stays where it was reported
+                prebuffering = fixed_latency;
+
+                if streaming {
+                    concealed += 1;
+                    underruns += u64::from(!starved);
+                    starved = true;
                 }
+
+                StreamFrame::default()
+            };
+
+            let mix = self.params.mix.smoothed.next();
+            // Output Gain and Mute only ever touch the remote audio, the track's input passes at unity in every mode
+            let gain = self.params.output_gain.smoothed.next() * self.mute.next();
+            let remote_sample = remote_frame[0] * gain;
+
+            let replace = self.mix_modes[MixMode::Replace.to_index()].next();
+            let sum = self.mix_modes[MixMode::Sum.to_index()].next();
+            let aux_weight = self.mix_modes[MixMode::Aux.to_index()].next();
+
+            // The track's own input and the mono stream, on every channel. Outside a mode switch only one weight is non-zero.
+            let input_weight = replace * (1.0 - mix) + sum + aux_weight;
+            let remote_weight = replace * mix + sum;
+            for sample in channel_samples.iter_mut() {
+                *sample = *sample * input_weight + remote_sample * remote_weight;
+            }
+
+            // A full queue means the network side fell behind, the newest audio is dropped
+            if send_monitor {
+                let channels = channel_samples.len().max(1) as f32;
+                let _ = self.params.monitor_feed.push(channel_samples.iter_mut().map(|sample| *sample).sum::<f32>() / channels);
             }
-        }) };
+
+            // "Remote" carries the main stream in aux mode and is silent otherwise, hosts do not clear it for us.
+            // Every other bus plays one stem, the host may leave any of them disconnected.
+            for (bus, output) in aux.outputs.iter_mut().enumerate() {
+                let aux_sample = match bus {
+                    0 => remote_sample * aux_weight,
+                    stream => remote_frame.get(stream).copied().unwrap_or_default() * gain,
+                };
+
+                for channel in output.as_slice() {
+                    if let Some(sample) = channel.get_mut(index) {
+                        *sample = aux_sample;
+                    }
+                }
+            }
+        }
+
+        self.prebuffering = prebuffering;
+        self.starved = starved;
+        self.params.popped_samples.fetch_add(popped, Ordering::Relaxed);
+        self.params.playback.concealed_samples.fetch_add(concealed, Ordering::Relaxed);
+        self.params.playback.underruns.fetch_add(underruns, Ordering::Relaxed);
+        self.params.receive_meter.update_from(peak, sum_squares, num_samples, self.sample_rate);
+
+        self.send_midi(context, block_start, popped);
 
         ProcessStatus::Normal
     }
 }
 
+impl Receiver {
+    // Sends the MIDI events that line up with the `played` samples taken from the buffer at `block_start`.
+    // Events for samples that were dropped instead of played still go out at the start of the block, so no note hangs.
+    fn send_midi(&mut self, context: &mut impl ProcessContext<Self>, block_start: u64, played: u64) {
+        while let Some((stream_index, message)) = self.next_midi_event.take().or_else(|| self.params.midi_events.pop()) {
+            if stream_index >= block_start + played {
+                self.next_midi_event = Some((stream_index, message));
+                break;
+            }
+
+            let timing = stream_index.saturating_sub(block_start) as u32;
+
+            context.send_event(match message {
+                MidiMessage::NoteOn { channel, note, velocity } => NoteEvent::NoteOn { timing, voice_id: None, channel, note, velocity },
+                MidiMessage::NoteOff { channel, note, velocity } => NoteEvent::NoteOff { timing, voice_id: None, channel, note, velocity },
+                MidiMessage::PolyPressure { channel, note, pressure } => NoteEvent::PolyPressure { timing, voice_id: None, channel, note, pressure },
+                MidiMessage::ChannelPressure { channel, pressure } => NoteEvent::MidiChannelPressure { timing, channel, pressure },
+                MidiMessage::PitchBend { channel, value } => NoteEvent::MidiPitchBend { timing, channel, value },
+                MidiMessage::ControlChange { channel, cc, value } => NoteEvent::MidiCC { timing, channel, cc, value },
+                MidiMessage::ProgramChange { channel, program } => NoteEvent::MidiProgramChange { timing, channel, program },
+            });
+        }
+    }
+
+    // Moves to the latest song position tag at or before the next sample to be played
+    fn update_playing_song_position(&mut self) {
+        if !self.params.tag_song_position.value() {
+            self.current_position_tag = None;
+            self.next_position_tag = None;
+            while self.params.position_tags.pop().is_some() {}
+            self.params.playing_song_position.store(i64::MIN, Ordering::Relaxed);
+            return;
+        }
+
+        let next_sample = self.params.popped_samples.load(Ordering::Relaxed);
+
+        while let Some(tag) = self.next_position_tag.take().or_else(|| self.params.position_tags.pop()) {
+            if tag.0 > next_sample {
+                self.next_position_tag = Some(tag);
+                break;
+            }
+
+            self.current_position_tag = Some(tag);
+        }
+
+        if let Some((stream_index, song_position)) = self.current_position_tag {
+            self.params.playing_song_position.store(song_position + (next_sample - stream_index) as i64, Ordering::Relaxed);
+        }
+    }
+}
+
+// Records to the session's folder, named after the peer and the time it started
+fn start_recording(params: &ReceiverParams, format: &AudioFormat) -> io::Result<Recording> {
+    let session = params.session.read().unwrap();
+    let path = session
+        .recording_path(SystemTime::now())
+        .ok_or_else(|| io::Error::other("no folder for recordings, choose one in the session settings"))?;
+
+    Recording::start(&path, session.recording_format, format)
+}
+
+// Start and stop for recording the received stream in the format the sender last announced
+fn recording_panel(ui: &mut egui::Ui, params: &ReceiverParams, format: Option<AudioFormat>) {
+    // The network thread writes into the recording under this lock, so it is never held while a file is opened or finished
+    let active = params.recording.lock().unwrap().as_ref().map(|active| (active.path().to_owned(), active.seconds(), active.sample_rate()));
+
+    match active {
+        Some((path, seconds, sample_rate)) => {
+            ui.label(format!("Recording to {} ({:.1} s)", path.display(), seconds));
+
+            if ui.button("Stop Recording").clicked() {
+                let recording = params.recording.lock().unwrap().take();
+
+                let status = match recording.map(Recording::stop) {
+                    Some(Ok(summary)) => format!(
+                        "Saved {} ({:.1} s, {} gaps from packet loss)",
+                        summary.path.display(),
+                        summary.frames as f32 / sample_rate,
+                        summary.gaps.len()
+                    ),
+                    Some(Err(err)) => format!("Recording failed: {err}"),
+                    None => return,
+                };
+
+                *params.recording_status.lock().unwrap() = Some(status);
+            }
+        }
+        None => {
+            // Nothing to record until the sender's format is known
+            let start = ui.add_enabled(format.is_some(), egui::Button::new("Start Recording")).clicked();
+
+            if let (true, Some(format)) = (start, format) {
+                match start_recording(params, &format) {
+                    Ok(started) => *params.recording.lock().unwrap() = Some(started),
+                    Err(err) => *params.recording_status.lock().unwrap() = Some(format!("Could not start recording: {err}")),
+                }
+            }
+        }
+    }
+
+    if let Some(status) = &*params.recording_status.lock().unwrap() {
+        ui.label(status);
+    }
+}
+
 impl ClapPlugin for Receiver {
     const CLAP_ID: &'static str = "com.moist-plugins-gmbh-egui.live-collab-receiver-gui";
     const CLAP_DESCRIPTION: Option<&'static str> = Some("WebRTC Audio Receiver");
@@ -1,24 +1,66 @@
+use live_collab_ui::*;
 use shared::*;
 
-use bytes::{Buf, Bytes};
 use nih_plug::prelude::*;
 use nih_plug_egui::{
     create_egui_editor,
     egui::{self, Color32, CornerRadius, Vec2, Window},
     resizable_window::ResizableWindow,
+    widgets::ParamSlider,
     EguiState,
 };
-use tokio::runtime::Runtime;
-use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
-use std::{sync::{Arc, LazyLock, Mutex}, time::{SystemTime, UNIX_EPOCH}};
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use std::sync::{atomic::{AtomicI64, Ordering}, Arc, LazyLock, Mutex, RwLock};
 
-static PAGE_MEMORY_ID: LazyLock<egui::Id> = LazyLock::new(|| egui::Id::new((file!(), 4)));
-static WEBRTC_MEMORY_ID: LazyLock<egui::Id> = LazyLock::new(|| egui::Id::new((file!(), 5)));
 static ANSWER_VALUE_ENTRY_MEMORY_ID: LazyLock<egui::Id> = LazyLock::new(|| egui::Id::new((file!(), 6)));
 static ERROR_VALUE_ENTRY_MEMORY_ID: LazyLock<egui::Id> = LazyLock::new(|| egui::Id::new((file!(), 7)));
+static PASSPHRASE_MEMORY_ID: LazyLock<egui::Id> = LazyLock::new(|| egui::Id::new((file!(), 9)));
+static ALLOWED_FINGERPRINTS_MEMORY_ID: LazyLock<egui::Id> = LazyLock::new(|| egui::Id::new((file!(), 10)));
+
+// How often the host transport is sent to the receiver while nothing changes
+const TRANSPORT_INTERVAL_SECS: f32 = 0.25;
+
+// Ramps that keep gain, mute and passthrough changes free of clicks and zipper noise
+const GAIN_SMOOTHING_MS: f32 = 50.0;
+const MUTE_RAMP_MS: f32 = 10.0;
+
+// Most of the receiver's return feed kept waiting to be played, anything beyond is dropped
+const RETURN_FEED_MAX_SECS: f32 = 0.5;
+
+// What the sender hears of its own track while passthrough is on
+#[derive(Enum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MonitorSource {
+    #[name = "Direct"]
+    Direct,
+    // Held back by the measured round trip, so it lines up with what comes back from the receiver
+    #[name = "Delayed By Round Trip"]
+    Delayed,
+    // What the receiver sends back of its output
+    #[name = "Receiver Return"]
+    Return,
+}
 
 pub struct Sender {
     params: Arc<SenderParams>,
+    sample_rate: f32,
+    max_buffer_size: u32,
+    audio_seq: u32,
+    last_playing: Option<bool>,
+    samples_since_transport: usize,
+    // Format last announced to the receiver, None until announced on the current connection
+    announced_format: Option<AudioFormat>,
+    // Send mute as the receiver last heard of it, the same way
+    announced_mute: Option<bool>,
+    // Rebuilt for the agreed codec whenever the format is announced
+    encoder: AudioEncoder,
+    // Bool params have no smoother of their own, these ramp the send and the local output in and out
+    send_mute: Smoother<f32>,
+    passthrough: Smoother<f32>,
+    // One per output channel, sized in `initialize`
+    delay_lines: Vec<DelayLine>,
+    monitor_delay: MonitorDelay,
+    // Source of the last block, the delay lines start over when it changes
+    monitor_source: MonitorSource,
 }
 
 #[derive(Params)]
@@ -27,34 +69,108 @@ pub struct SenderParams {
     editor_state: Arc<EguiState>,
     
     pub buffer_size: IntParam,
-
-    pub page: IntParam,
-
-    pub round_trip_latency: AtomicF32,
+    #[id = "send-gain"]
+    pub send_gain: FloatParam,
+    #[id = "send-mute"]
+    pub send_mute: BoolParam,
+    // Whether the track's input is also heard locally
+    #[id = "passthrough"]
+    pub passthrough: BoolParam,
+    #[id = "monitor-source"]
+    pub monitor_source: EnumParam<MonitorSource>,
+
+    pub latency: Arc<LatencyTracker>,
     pub sample_buffer: Arc<crossbeam::queue::SegQueue<f32>>,
-    pub runtime: Runtime,
-    pub connection: Arc<Mutex<Option<WebRTCConnection>>>,
+    // Receiver's monitor feed, mono at the stream's sample rate
+    pub return_feed: Arc<crossbeam::queue::SegQueue<f32>>,
+    pub runtime: PluginRuntime,
+    pub connection: ConnectionSlot,
+    pub identity: Option<Arc<Identity>>,
+
+    #[persist = "session"]
+    pub session: Arc<RwLock<SessionConfig>>,
+    #[persist = "chat-history"]
+    pub chat_history: Arc<RwLock<ChatHistory>>,
+    // Host song position as of the last processed block, i64::MIN when the host has none
+    pub song_position: AtomicI64,
+    pub song_sample_rate: AtomicF32,
+    pub sample_rate: AtomicF32,
+    // Latest stats report from the receiver, and why it left if it said goodbye
+    pub remote_stats: Arc<Mutex<Option<StatsReport>>>,
+    pub stats_history: Arc<Mutex<StatsHistory>>,
+    // Level of what goes out to the receiver
+    pub send_meter: LevelMeter,
+    pub peer_left: Arc<Mutex<Option<String>>>,
 }
 
 impl Default for Sender {
     fn default() -> Self {
         Self {
             params: Arc::new(SenderParams::default()),
+            sample_rate: 44100.0,
+            max_buffer_size: 0,
+            audio_seq: 0,
+            last_playing: None,
+            samples_since_transport: 0,
+            announced_format: None,
+            announced_mute: None,
+            encoder: AudioEncoder::new(Codec::PcmF32, Dither::None),
+            send_mute: Smoother::new(SmoothingStyle::Linear(MUTE_RAMP_MS)),
+            passthrough: Smoother::new(SmoothingStyle::Linear(MUTE_RAMP_MS)),
+            delay_lines: Vec::new(),
+            monitor_delay: MonitorDelay::new(1),
+            monitor_source: MonitorSource::Direct,
         }
     }
 }
 
+// Closing the connection also drops the channel handlers holding on to the params, so the runtime goes with them
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.params.connection.shutdown(&self.params.runtime, "plugin removed");
+    }
+}
+
 impl Default for SenderParams {
     fn default() -> Self {
         Self {
             editor_state: EguiState::from_size(300, 180),
 
             buffer_size: IntParam::new("buffer-size", 64, IntRange::Linear { min: 0, max: 2048 }),
-            page: IntParam::new("page", 0, IntRange::Linear { min: 0, max: 1 }),
+            send_gain: FloatParam::new(
+                "Send Gain",
+                util::db_to_gain(0.0),
+                FloatRange::Skewed {
+                    min: util::db_to_gain(-60.0),
+                    max: util::db_to_gain(12.0),
+                    factor: FloatRange::gain_skew_factor(-60.0, 12.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Logarithmic(GAIN_SMOOTHING_MS))
+            .with_unit(" dB")
+            .with_value_to_string(formatters::v2s_f32_gain_to_db(2))
+            .with_string_to_value(formatters::s2v_f32_gain_to_db()),
+            send_mute: BoolParam::new("Send Mute", false),
+            passthrough: BoolParam::new("Local Passthrough", true),
+            monitor_source: EnumParam::new("Monitor Source", MonitorSource::Direct),
             connection: Default::default(),
-            runtime: Runtime::new().unwrap(),
+            runtime: PluginRuntime::new().unwrap(),
+            identity: Identity::load_default()
+                .map_err(|err| nih_log!("No persistent identity, peers will not recognize this plugin: {err}"))
+                .ok()
+                .map(Arc::new),
             sample_buffer: Default::default(),
-            round_trip_latency: Default::default(),
+            return_feed: Default::default(),
+            latency: Default::default(),
+            session: Arc::new(RwLock::new(SessionConfig::new(SessionRole::Sender))),
+            chat_history: Default::default(),
+            song_position: AtomicI64::new(i64::MIN),
+            song_sample_rate: Default::default(),
+            sample_rate: AtomicF32::new(44100.0),
+            remote_stats: Default::default(),
+            stats_history: Default::default(),
+            send_meter: Default::default(),
+            peer_left: Default::default(),
         }
     }
 }
@@ -71,15 +187,20 @@ impl Plugin for Sender {
         AudioIOLayout {
             main_input_channels: NonZeroU32::new(2),
             main_output_channels: NonZeroU32::new(2),
+            aux_input_ports: &[new_nonzero_u32(2); MAX_STREAMS - 1],
+            names: PortNames { aux_inputs: &["Stem 2", "Stem 3", "Stem 4"], ..PortNames::const_default() },
             ..AudioIOLayout::const_default()
         },
         AudioIOLayout {
             main_input_channels: NonZeroU32::new(1),
             main_output_channels: NonZeroU32::new(1),
+            aux_input_ports: &[new_nonzero_u32(1); MAX_STREAMS - 1],
+            names: PortNames { aux_inputs: &["Stem 2", "Stem 3", "Stem 4"], ..PortNames::const_default() },
             ..AudioIOLayout::const_default()
         },
     ];
 
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
     type SysExMessage = ();
@@ -97,7 +218,7 @@ impl Plugin for Sender {
             self.params.editor_state.clone(),
             (),
             |_, _| {},
-            move |egui_ctx, _, _state| {
+            move |egui_ctx, setter, _state| {
                 ResizableWindow::new("Live Collab Sender")
                     .min_size(Vec2::new(300.0, 300.0))
                     .show(egui_ctx, egui_state.as_ref(), |ui| {
@@ -109,60 +230,163 @@ impl Plugin for Sender {
                             style.visuals.extreme_bg_color = Color32::from_rgb(29, 31, 36);
                         });
 
-                        let connection: Option<WebRTCConnection> = ui.memory(|mem| { mem.data.get_temp(*WEBRTC_MEMORY_ID) });
+                        let connection = params.connection.get();
+
+                        identity_panel(ui, params.identity.as_deref(), connection.as_deref(), &params.session);
+
+                        ui.label("Send Gain");
+                        ui.add(ParamSlider::for_param(&params.send_gain, setter));
+
+                        ui.label("Send Mute");
+                        ui.add(ParamSlider::for_param(&params.send_mute, setter));
+
+                        ui.label("Local Passthrough");
+                        ui.add(ParamSlider::for_param(&params.passthrough, setter));
+
+                        ui.label("Monitor Source");
+                        ui.add(ParamSlider::for_param(&params.monitor_source, setter));
 
                         if let Some(connection) = &connection {
-                            ui.label(format!("Connection State: {}", connection.peer.connection_state().to_string()));
+                            let sample_rate = params.sample_rate.load(Ordering::Relaxed);
+                            let return_rate = connection.handshake.remote_sample_rate();
+                            if params.monitor_source.value() == MonitorSource::Return && return_rate.is_some_and(|rate| rate != sample_rate) {
+                                ui.colored_label(
+                                    Color32::YELLOW,
+                                    format!("Return feed runs at {} Hz, this session at {sample_rate} Hz, so it stays silent", return_rate.unwrap_or_default()),
+                                );
+                            }
+
+                            ui.label(format!("Connection State: {}", connection.lifecycle()));
                             
-                            ui.label(format!("Round-Trip Latency ({} ms)", params.round_trip_latency.load(std::sync::atomic::Ordering::Relaxed)));
+                            let latency = params.latency.estimate();
+                            ui.label(format!("Round-Trip Latency ({:.2} ms, jitter {:.2} ms)", latency.smoothed_rtt_ms, latency.jitter_ms));
+                            ui.label(format!("Mouth-to-Ear Latency ({:.1} ms)", latency.mouth_to_ear_ms));
 
-                            if connection.peer.connection_state() == RTCPeerConnectionState::Connected {
-                                if ui.button("Disconnect").clicked() {
-                                    let conn_clone = connection.clone();
-                                    params.runtime.spawn(async move { conn_clone.peer.close().await });
-                                    ui.memory_mut(|mem| mem.data.insert_temp(*PAGE_MEMORY_ID, 0));
-                                }
+                            level_meter(ui, "Outgoing", &params.send_meter);
+
+                            let remote_signal = params.remote_stats.lock().unwrap().is_some_and(|stats| stats.signal_present);
+                            signal_light(ui, "Receiver hears signal", remote_signal);
+
+                            handshake_label(ui, &connection.handshake, "Streaming");
+                            encryption_label(ui, &connection.encryption);
+
+                            if let Some(reason) = &*params.peer_left.lock().unwrap() {
+                                ui.label(format!("Peer left: {reason}"));
+                            }
+
+                            // The stream's health as the receiver sees it
+                            stats_panel(ui, &params.stats_history, |ui| {
+                                let Some(stats) = *params.remote_stats.lock().unwrap() else {
+                                    ui.label("Waiting for the receiver's report...");
+                                    return;
+                                };
+
+                                let expected = stats.packets_received + stats.packets_lost;
+                                let loss_percent = if expected == 0 { 0.0 } else { stats.packets_lost as f32 * 100.0 / expected as f32 };
+
+                                ui.label(format!(
+                                    "Audio Packets: {} received, {} lost ({loss_percent:.1}%), jitter {:.1} ms",
+                                    stats.packets_received, stats.packets_lost, stats.jitter_ms
+                                ));
+                                ui.label(format!("Receiver Buffer Depth: {:.1} ms", stats.buffered_ms));
+                                ui.label(format!("Underruns: {}, Overruns: {}, Concealed: {} samples", stats.underruns, stats.overruns, stats.concealed_samples));
+                            });
+
+                            if connection.lifecycle() == Lifecycle::Connected && ui.button("Disconnect").clicked() {
+                                params.connection.close(&params.runtime, "sender disconnected");
                             }
                         }
 
-                        match ui.memory(|mem| { mem.data.get_temp(*PAGE_MEMORY_ID).unwrap_or(0) }) {
-                            0 => {
+                        match params.connection.lifecycle() {
+                            Lifecycle::Closed => {
+                                let passphrase_mutex = ui.memory_mut(|mem| {
+                                    mem.data
+                                        .get_temp_mut_or_default::<Arc<Mutex<String>>>(*PASSPHRASE_MEMORY_ID)
+                                        .clone()
+                                });
+                                let passphrase = passphrase_mutex.lock().unwrap().clone();
+
+                                passphrase_entry(ui, &passphrase_mutex);
+                                session_settings(ui, &params.session, |ui, session| {
+                                    egui::ComboBox::from_label("Codec")
+                                        .selected_text(session.codec.to_string())
+                                        .show_ui(ui, |ui| {
+                                            for codec in SUPPORTED_CODECS {
+                                                ui.selectable_value(&mut session.codec, *codec, codec.to_string());
+                                            }
+                                        });
+
+                                    // Only the integer codecs round the stream, applies from the next handshake
+                                    if matches!(session.codec, Codec::LosslessI16 | Codec::LosslessI24) {
+                                        egui::ComboBox::from_label("Dither")
+                                            .selected_text(session.dither.to_string())
+                                            .show_ui(ui, |ui| {
+                                                for dither in [Dither::None, Dither::Rectangular, Dither::Triangular] {
+                                                    ui.selectable_value(&mut session.dither, dither, dither.to_string());
+                                                }
+                                            });
+                                    }
+
+                                    // Main input plus stems from the aux inputs, applies from the next handshake
+                                    ui.add(egui::Slider::new(&mut session.streams, 1..=MAX_STREAMS as u16).text("Streams (main + aux stems)"));
+                                });
+
                                 if ui.button("Create Session").clicked() {
-                                    if let Ok(connection) = create_offerer(&params.runtime) {
-                                        *params.connection.lock().unwrap() = Some(connection.clone());
+                                    let ice_config = IceConfig {
+                                        passphrase: Some(passphrase),
+                                        ..params.session.read().unwrap().ice_config(params.identity.as_deref())
+                                    };
 
-                                        let params_clone = params.clone();
-                                        let conn_clone = connection.clone();
+                                    if let Ok(connection) = create_offerer_with_config(&params.runtime, &ice_config) {
 
-                                        connection.tcp_channel.on_message(Box::new(move |mut msg| {
-                                            let cur_ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+                                        #[cfg(debug_assertions)]
+                                        let connection = match ImpairmentConfig::from_env() {
+                                            Some(config) => connection.with_impairment(config),
+                                            None => connection,
+                                        };
 
-                                            if msg.data.len() == 16 {
-                                                let recv_ts = msg.data.get_u128_le();
-                                                
-                                                params_clone.round_trip_latency.store((cur_ts - recv_ts) as f32, std::sync::atomic::Ordering::Relaxed);
+                                        params.connection.replace(&params.runtime, connection.clone());
+
+                                        // The only audio coming back is the receiver's monitor feed
+                                        let params_clone = params.clone();
+                                        let encryption = connection.encryption.clone();
+                                        connection.channel.on_message(Box::new(move |msg: DataChannelMessage| {
+                                            let packet = encryption.open(msg.data).and_then(|data| AudioPacket::parse(&data));
+                                            let max_len = (params_clone.sample_rate.load(Ordering::Relaxed) * RETURN_FEED_MAX_SECS) as usize;
+
+                                            for sample in packet.map(|packet| packet.samples).unwrap_or_default() {
+                                                if params_clone.return_feed.len() < max_len {
+                                                    params_clone.return_feed.push(sample);
+                                                }
                                             }
 
-                                            let cc2 = conn_clone.clone();
-                                            Box::pin(async move {
-                                                let _ = cc2.tcp_channel.send(&Bytes::copy_from_slice(&cur_ts.to_le_bytes())).await;
-                                            })
+                                            Box::pin(async {})
                                         }));
 
-                                        let conn_clone = connection.clone();
-                                        params.runtime.spawn(async move {
-                                            let cur_ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-                                            conn_clone.channel.send(&Bytes::copy_from_slice(&cur_ts.to_le_bytes())).await
+                                        while params.return_feed.pop().is_some() {}
+                                        *params.remote_stats.lock().unwrap() = None;
+                                        params.stats_history.lock().unwrap().clear();
+                                        *params.peer_left.lock().unwrap() = None;
+
+                                        let params_clone = params.clone();
+                                        let hello = params.session.read().unwrap().hello(Sender::VERSION, params.sample_rate.load(Ordering::Relaxed), 1);
+                                        connection.start_control(&params.runtime, params.latency.clone(), hello, move |message| {
+                                            match message {
+                                                ControlMessage::Stats(stats) => *params_clone.remote_stats.lock().unwrap() = Some(stats),
+                                                ControlMessage::Bye { reason } => *params_clone.peer_left.lock().unwrap() = Some(reason),
+                                                message => return params_clone.chat_history.write().unwrap().on_control(message),
+                                            }
+
+                                            None
                                         });
-                                        
-                                        ui.memory_mut(|mem| mem.data.insert_temp(*WEBRTC_MEMORY_ID, connection));
-                                        ui.memory_mut(|mem| mem.data.insert_temp(*PAGE_MEMORY_ID, 1));
+
+                                        connection.start_stats(&params.runtime, STATS_POLL_INTERVAL, params.latency.clone(), params.stats_history.clone());
                                     }
                                 }
                             },
-                            1 => {
-                                if ui.button("Go back").clicked() {
-                                    ui.memory_mut(|mem| mem.data.insert_temp(*PAGE_MEMORY_ID, 0));
+                            lifecycle => {
+                                if lifecycle != Lifecycle::Connected && ui.button("Cancel Session").clicked() {
+                                    params.connection.close(&params.runtime, "session cancelled");
                                 }
 
                                 if let Some(connection) = &connection {
@@ -182,7 +406,22 @@ impl Plugin for Sender {
                                     let text_input_label = ui.label("Enter peer answer:");
                                     ui.text_edit_singleline(&mut *value_entry).labelled_by(text_input_label.id);
 
+                                    let allowed_fingerprints_mutex = ui.memory_mut(|mem| {
+                                        mem.data
+                                            .get_temp_mut_or_default::<Arc<Mutex<String>>>(*ALLOWED_FINGERPRINTS_MEMORY_ID)
+                                            .clone()
+                                    });
+
+                                    let mut allowed_fingerprints = allowed_fingerprints_mutex.lock().unwrap();
+
+                                    let fingerprints_label = ui.label("Only accept these peer certificates (optional, one fingerprint per line):");
+                                    ui.text_edit_multiline(&mut *allowed_fingerprints).labelled_by(fingerprints_label.id);
+
                                     if ui.button("Set Answer").clicked() {
+                                        connection.session.set_allowed_fingerprints(
+                                            allowed_fingerprints.lines().map(str::trim).filter(|line| !line.is_empty()).map(str::to_owned).collect(),
+                                        );
+
                                         let error_value_entry_mutex = ui.memory_mut(|mem| {
                                             mem.data
                                                 .get_temp_mut_or_default::<Arc<Mutex<String>>>(*ERROR_VALUE_ENTRY_MEMORY_ID)
@@ -207,13 +446,18 @@ impl Plugin for Sender {
 
                                         ui.label(error_value_entry.to_owned());
                                     }
-
-                                    if connection.peer.connection_state() == RTCPeerConnectionState::Failed {
-                                        ui.memory_mut(|mem| mem.data.insert_temp(*PAGE_MEMORY_ID, 0));
-                                    }
                                 }
                             },
-                            _ => {}
+                        }
+
+                        if let Some(connection) = &connection {
+                            if connection.lifecycle() == Lifecycle::Connected {
+                                let marker_position = Some(params.song_position.load(Ordering::Relaxed))
+                                    .filter(|position| *position != i64::MIN)
+                                    .map(|position| (position, params.song_sample_rate.load(Ordering::Relaxed)));
+
+                                chat_panel(ui, &params.runtime, connection, &params.chat_history, marker_position);
+                            }
                         }
                     });
             },
@@ -222,47 +466,247 @@ impl Plugin for Sender {
 
     fn initialize(
         &mut self,
-        _audio_io_layout: &AudioIOLayout,
+        audio_io_layout: &AudioIOLayout,
         buffer_config: &BufferConfig,
         _context: &mut impl InitContext<Self>,
     ) -> bool {
+        self.sample_rate = buffer_config.sample_rate;
+        self.max_buffer_size = buffer_config.max_buffer_size;
+
+        let channels = audio_io_layout.main_output_channels.map_or(0, NonZeroU32::get) as usize;
+        let max_delay = (MAX_MONITOR_DELAY_SECS * self.sample_rate) as usize;
+        self.delay_lines = (0..channels).map(|_| DelayLine::new(max_delay)).collect();
+        self.monitor_delay = MonitorDelay::new((MONITOR_CROSSFADE_MS * self.sample_rate / 1000.0) as usize);
+        self.params.sample_rate.store(self.sample_rate, Ordering::Relaxed);
+
         true
     }
 
+    // The session ends with the plugin, whether or not the editor is open
+    fn deactivate(&mut self) {
+        self.params.connection.shutdown(&self.params.runtime, "plugin deactivated");
+    }
+
+    fn reset(&mut self) {
+        self.send_mute.reset(if self.params.send_mute.value() { 0.0 } else { 1.0 });
+        self.passthrough.reset(if self.params.passthrough.value() { 1.0 } else { 0.0 });
+
+        for line in &mut self.delay_lines {
+            line.clear();
+        }
+        self.monitor_delay.reset(self.monitor_delay.delay());
+    }
+
     fn process(
         &mut self,
         buffer: &mut Buffer,
-        _aux: &mut AuxiliaryBuffers,
-        _context: &mut impl ProcessContext<Self>,
+        aux: &mut AuxiliaryBuffers,
+        context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        if let Some(connection) = &*self.params.connection.lock().unwrap() {
-            if connection.peer.connection_state() == RTCPeerConnectionState::Connected {
-                let num_samples = buffer.samples();
-                let mut samples = Vec::with_capacity(num_samples);
-
-                unsafe { 
-                    buffer.set_slices(num_samples, |output| {
-                        for i in 0..num_samples {
-                            samples.push((output[0][i] + output[1][i]) / 2.0);
-                        }
-                    })
+        let mut midi = Vec::new();
+        while let Some(event) = context.next_event() {
+            if let Some(event) = to_midi_event(event) {
+                midi.push(event);
+            }
+        }
+
+        // Input arrives one host buffer late and a whole block is collected before it is sent
+        self.params.latency.set_local_delay((self.max_buffer_size as usize + buffer.samples()) as u64, self.sample_rate);
+
+        let transport = context.transport();
+        let snapshot = TransportSnapshot {
+            playing: transport.playing,
+            tempo: transport.tempo,
+            time_signature: transport.time_sig_numerator.zip(transport.time_sig_denominator),
+            pos_samples: transport.pos_samples(),
+            pos_beats: transport.pos_beats(),
+            sample_rate: self.sample_rate,
+        };
+
+        self.params.song_position.store(snapshot.pos_samples.unwrap_or(i64::MIN), Ordering::Relaxed);
+        self.params.song_sample_rate.store(self.sample_rate, Ordering::Relaxed);
+
+        let connection = self.params.connection.get();
+
+        // Nothing goes out until the receiver agreed on a format, the user trusts it and, with a passphrase, it proved to have the same one
+        let agreed_format = connection
+            .as_ref()
+            .filter(|connection| connection.lifecycle() == Lifecycle::Connected)
+            .filter(|connection| connection.encryption.ready() && connection.peer_confirmed.load(Ordering::Relaxed))
+            .and_then(|connection| connection.handshake.agreed_format());
+
+        // Main input first, then one stem per aux input, interleaved
+        let streams = agreed_format.map_or(0, |format| (format.streams as usize).clamp(1, MAX_STREAMS));
+
+        let num_samples = buffer.samples();
+        let mut samples = Vec::with_capacity(num_samples * streams);
+        let mut peak = 0.0f32;
+        let mut sum_squares = 0.0;
+
+        self.send_mute.set_target(self.sample_rate, if self.params.send_mute.value() { 0.0 } else { 1.0 });
+        self.passthrough.set_target(self.sample_rate, if self.params.passthrough.value() { 1.0 } else { 0.0 });
+
+        let monitor_source = self.params.monitor_source.value();
+        if monitor_source != self.monitor_source {
+            self.monitor_source = monitor_source;
+            for line in &mut self.delay_lines {
+                line.clear();
+            }
+            self.monitor_delay.reset(self.monitor_delay.delay());
+        }
+
+        if let Some(delay) = monitor_delay(self.params.latency.smoothed_rtt_ns(), self.sample_rate, self.monitor_delay.delay()) {
+            self.monitor_delay.set(delay);
+        }
+
+        // The return feed is played as it comes, at another sample rate than ours it would play at the wrong speed
+        let play_return = monitor_source == MonitorSource::Return
+            && connection.as_ref().and_then(|connection| connection.handshake.remote_sample_rate()) == Some(self.sample_rate);
+
+        // Old return audio is not worth hearing once it is switched back on
+        if !play_return {
+            while self.params.return_feed.pop().is_some() {}
+        }
+
+        // The smoothers advance every sample, whether or not anything is being sent
+        for (index, channel_samples) in buffer.iter_samples().enumerate() {
+            let send_gain = self.params.send_gain.smoothed.next() * self.send_mute.next();
+            let passthrough = self.passthrough.next();
+            let channels = channel_samples.len() as f32;
+            let (delay_from, delay_to, delay_fade) = self.monitor_delay.advance();
+
+            let return_sample = if play_return { self.params.return_feed.pop().unwrap_or_default() } else { 0.0 };
+
+            let mut mono = 0.0;
+            for (channel, sample) in channel_samples.into_iter().enumerate() {
+                mono += *sample;
+
+                let monitored = match (monitor_source, self.delay_lines.get_mut(channel)) {
+                    (MonitorSource::Delayed, Some(line)) => line.process_crossfade(*sample, delay_from, delay_to, delay_fade),
+                    (MonitorSource::Return, _) => return_sample,
+                    _ => *sample,
                 };
 
-                if !samples.is_empty() {
+                *sample = monitored * passthrough;
+            }
+
+            if streams > 0 {
+                let main = mono / channels * send_gain;
+                peak = peak.max(main.abs());
+                sum_squares += main * main;
+                samples.push(main);
+
+                // A stem whose bus the host left out is sent as silence
+                for stem in 1..streams {
+                    let stem_channels = aux.inputs.get(stem - 1).map(|input| input.as_slice_immutable()).unwrap_or_default();
+                    samples.push(downmix(stem_channels, index) * send_gain);
+                }
+            }
+        }
+
+        match (connection, agreed_format) {
+            (Some(connection), Some(agreed_format)) => {
+                // The host may have changed sample rate since the handshake
+                let format = AudioFormat { sample_rate: self.sample_rate, ..agreed_format };
+
+                if self.announced_format != Some(format) {
+                    self.announced_format = Some(format);
+
+                    // The editor may hold the session, the dither it had last time is good enough then
+                    let dither = self.params.session.try_read().map_or(self.encoder.dither(), |session| session.dither);
+                    self.encoder = AudioEncoder::new(format.codec, dither);
+
                     let conn_clone = connection.clone();
+                    self.params.runtime.spawn(async move {
+                        conn_clone.send_control(ControlMessage::FormatChange(format)).await;
+                    });
+                }
+
+                self.params.send_meter.update_from(peak, sum_squares, num_samples, self.sample_rate);
+
+                let muted = self.params.send_mute.value();
+                if self.announced_mute != Some(muted) {
+                    self.announced_mute = Some(muted);
 
+                    let conn_clone = connection.clone();
                     self.params.runtime.spawn(async move {
-                        let samples_as_bytes = samples.iter().flat_map(|f| f.to_le_bytes()).collect::<Vec<_>>();
-                        conn_clone.channel.send(&Bytes::copy_from_slice(samples_as_bytes.as_slice())).await
+                        conn_clone.send_control(ControlMessage::Mute { muted }).await;
                     });
                 }
-            }   
+
+                self.samples_since_transport += num_samples;
+
+                if self.last_playing != Some(snapshot.playing) || self.samples_since_transport as f32 >= self.sample_rate * TRANSPORT_INTERVAL_SECS {
+                    self.last_playing = Some(snapshot.playing);
+                    self.samples_since_transport = 0;
+
+                    let conn_clone = connection.clone();
+                    self.params.runtime.spawn(async move {
+                        conn_clone.send_control(ControlMessage::Transport(snapshot)).await;
+                    });
+                }
+
+                // Blocks larger than the agreed frame size go out as several packets
+                for (index, chunk) in samples.chunks(format.frame_size.max(1) as usize * streams).enumerate() {
+                    let offset = index * format.frame_size as usize;
+                    let chunk_midi = midi
+                        .iter()
+                        .filter(|event| (offset..offset + chunk.len() / streams).contains(&(event.timing as usize)))
+                        .map(|event| MidiEvent { timing: event.timing - offset as u32, message: event.message })
+                        .collect();
+
+                    let conn_clone = connection.clone();
+                    let packet = AudioPacket {
+                        seq: self.audio_seq,
+                        song_position: snapshot.pos_samples.map(|position| position + offset as i64),
+                        midi: chunk_midi,
+                        samples: Vec::new(),
+                    };
+
+                    // Dither is all the encoding done here, the prediction search happens with the send
+                    let samples = self.encoder.quantize(chunk, streams);
+
+                    self.audio_seq = self.audio_seq.wrapping_add(1);
+
+                    self.params.runtime.spawn(async move {
+                        let _ = conn_clone.send_audio(packet.encode_quantized(&samples)).await;
+                    });
+                }
+            }
+            _ => {
+                self.announced_format = None;
+                self.announced_mute = None;
+                self.params.send_meter.update_from(0.0, 0.0, num_samples, self.sample_rate);
+            }
         }
 
         ProcessStatus::Normal
     }
 }
 
+// Mono sum of one bus at `index`, silence for a bus without channels
+fn downmix(channels: &[&mut [f32]], index: usize) -> f32 {
+    let sum: f32 = channels.iter().filter_map(|channel| channel.get(index)).sum();
+    sum / channels.len().max(1) as f32
+}
+
+fn to_midi_event(event: NoteEvent<()>) -> Option<MidiEvent> {
+    let timing = event.timing();
+
+    let message = match event {
+        NoteEvent::NoteOn { channel, note, velocity, .. } => MidiMessage::NoteOn { channel, note, velocity },
+        NoteEvent::NoteOff { channel, note, velocity, .. } => MidiMessage::NoteOff { channel, note, velocity },
+        NoteEvent::PolyPressure { channel, note, pressure, .. } => MidiMessage::PolyPressure { channel, note, pressure },
+        NoteEvent::MidiChannelPressure { channel, pressure, .. } => MidiMessage::ChannelPressure { channel, pressure },
+        NoteEvent::MidiPitchBend { channel, value, .. } => MidiMessage::PitchBend { channel, value },
+        NoteEvent::MidiCC { channel, cc, value, .. } => MidiMessage::ControlChange { channel, cc, value },
+        NoteEvent::MidiProgramChange { channel, program, .. } => MidiMessage::ProgramChange { channel, program },
+        _ => return None,
+    };
+
+    Some(MidiEvent { timing, message })
+}
+
 impl ClapPlugin for Sender {
     const CLAP_ID: &'static str = "com.moist-plugins-gmbh-egui.live-collab-sender-gui";
     const CLAP_DESCRIPTION: Option<&'static str> = Some("WebRTC Audio Sender");
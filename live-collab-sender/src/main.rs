@@ -0,0 +1,7 @@
+use nih_plug::prelude::*;
+
+use live_collab_sender::Sender;
+
+fn main() {
+    nih_export_standalone::<Sender>();
+}
@@ -0,0 +1,210 @@
+use std::sync::{atomic::Ordering, Arc, LazyLock, Mutex, RwLock};
+
+use nih_plug_egui::egui::{self, Color32, CornerRadius, Vec2};
+use shared::*;
+use tokio::runtime::Runtime;
+
+static CHAT_ENTRY_MEMORY_ID: LazyLock<egui::Id> = LazyLock::new(|| egui::Id::new((file!(), 1)));
+
+// `verb` says which way the audio goes, "Streaming" or "Receiving"
+pub fn handshake_label(ui: &mut egui::Ui, handshake: &Handshake, verb: &str) {
+    match handshake.state() {
+        HandshakeState::Pending => ui.label("Negotiating with peer..."),
+        HandshakeState::Agreed(format) => ui.label(format!("{verb} {}, {} ch, {} streams at {} Hz, up to {} samples per packet", format.codec, format.channels, format.streams, format.sample_rate, format.frame_size)),
+        HandshakeState::Refused(reason) => ui.colored_label(Color32::RED, format!("Incompatible peer: {reason}")),
+    };
+}
+
+// Our own fingerprint to hand out, and whether the connected peer is someone we trust.
+// Unknown peers are held until the user allows them, once or for good.
+pub fn identity_panel(ui: &mut egui::Ui, identity: Option<&Identity>, connection: Option<&WebRTCConnection>, session: &RwLock<SessionConfig>) {
+    if let Some(identity) = identity {
+        ui.horizontal(|ui| {
+            ui.label(format!("Your fingerprint: {}", identity.fingerprint));
+            if ui.button("Copy").clicked() {
+                ui.ctx().copy_text(identity.fingerprint.clone());
+            }
+        });
+    }
+
+    let Some(connection) = connection else { return };
+    let Some(fingerprint) = connection.remote_fingerprints.lock().unwrap().first().cloned() else { return };
+
+    // Trusted peers were already confirmed when their fingerprint arrived
+    if connection.peer_confirmed.load(Ordering::Relaxed) {
+        match identity.and_then(|identity| identity.trusted_peer(&fingerprint)) {
+            Some(peer) => ui.colored_label(Color32::GREEN, format!("Trusted peer: {}", peer.name)),
+            None => ui.label(format!("Peer allowed for this session: {fingerprint}")),
+        };
+        return;
+    }
+
+    ui.colored_label(Color32::YELLOW, format!("Unknown peer: {fingerprint}\nCheck the fingerprint with your collaborator before allowing audio."));
+
+    // Trusted under the peer name from the session settings
+    let peer_name = session.read().unwrap().peer_name.trim().to_owned();
+
+    ui.horizontal(|ui| {
+        if let Some(identity) = identity {
+            let trust = ui.add_enabled(!peer_name.is_empty(), egui::Button::new("Trust")).on_disabled_hover_text("Enter the peer name in the session settings first");
+
+            if trust.clicked() && identity.trust(peer_name, &fingerprint).is_ok() {
+                connection.peer_confirmed.store(true, Ordering::Relaxed);
+            }
+        }
+
+        if ui.button("Allow Once").clicked() {
+            connection.peer_confirmed.store(true, Ordering::Relaxed);
+        }
+    });
+}
+
+// Saved with the project, so these are what the last session used. `side_settings` adds what only one plugin has.
+pub fn session_settings(ui: &mut egui::Ui, session: &RwLock<SessionConfig>, side_settings: impl FnOnce(&mut egui::Ui, &mut SessionConfig)) {
+    let mut session = session.write().unwrap();
+
+    egui::CollapsingHeader::new("Session Settings").show(ui, |ui| {
+        let peer_name_label = ui.label("Peer name:");
+        ui.text_edit_singleline(&mut session.peer_name).labelled_by(peer_name_label.id);
+
+        // Edited as one server per line, blank lines are dropped when connecting
+        let mut stun_urls = session.ice.stun_urls.join("\n");
+        let stun_label = ui.label("STUN servers (one per line):");
+        if ui.text_edit_multiline(&mut stun_urls).labelled_by(stun_label.id).changed() {
+            session.ice.stun_urls = stun_urls.split('\n').map(str::to_owned).collect();
+        }
+
+        ui.checkbox(&mut session.ice.include_loopback, "Include loopback candidates");
+
+        ui.add(egui::Slider::new(&mut session.frame_size, 64..=MAX_FRAME_SIZE).text("Max frame size"));
+
+        side_settings(ui, &mut session);
+    });
+}
+
+// Transport figures from get_stats, then whatever `stream_stats` adds for this side, then the latency graph
+pub fn stats_panel(ui: &mut egui::Ui, history: &Mutex<StatsHistory>, stream_stats: impl FnOnce(&mut egui::Ui)) {
+    egui::CollapsingHeader::new("Statistics").show(ui, |ui| {
+        let history = history.lock().unwrap();
+        let network = &history.latest;
+
+        match &network.candidate_pair {
+            Some((local, remote)) => ui.label(format!("Candidate Pair: {local} -> {remote}")),
+            None => ui.label("Candidate Pair: none selected yet"),
+        };
+
+        let (sent_kbps, received_kbps) = history.bitrate_kbps();
+        ui.label(format!("Sent: {} packets, {} bytes ({sent_kbps:.0} kbit/s)", network.packets_sent, network.bytes_sent));
+        ui.label(format!("Received: {} packets, {} bytes ({received_kbps:.0} kbit/s)", network.packets_received, network.bytes_received));
+
+        stream_stats(ui);
+
+        latency_graph(ui, &history.latency_ms());
+    });
+}
+
+// Round-trip latency over the last STATS_HISTORY_LEN polls, newest on the right
+fn latency_graph(ui: &mut egui::Ui, latency_ms: &[f32]) {
+    let (rect, _) = ui.allocate_exact_size(Vec2::new(ui.available_width(), 60.0), egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, CornerRadius::ZERO, Color32::from_rgb(29, 31, 36));
+
+    let peak = latency_ms.iter().copied().fold(1.0, f32::max);
+    let step = rect.width() / (STATS_HISTORY_LEN - 1) as f32;
+    let points = latency_ms
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(age, ms)| egui::pos2(rect.right() - age as f32 * step, rect.bottom() - rect.height() * ms / peak))
+        .collect();
+
+    painter.add(egui::Shape::line(points, egui::Stroke::new(1.5, Color32::LIGHT_GREEN)));
+
+    let window = STATS_POLL_INTERVAL * STATS_HISTORY_LEN as u32;
+    ui.label(format!("Round-Trip Latency, last {} s (peak {peak:.1} ms)", window.as_secs()));
+}
+
+// RMS as a bar with the peak as a line over it, both on a dBFS scale from METER_FLOOR_DB to 0
+pub fn level_meter(ui: &mut egui::Ui, label: &str, meter: &LevelMeter) {
+    let (peak_db, rms_db) = (meter.peak_db(), meter.rms_db());
+    ui.label(format!("{label}: peak {peak_db:.1} dB, RMS {rms_db:.1} dB"));
+
+    let (rect, _) = ui.allocate_exact_size(Vec2::new(ui.available_width(), 10.0), egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, CornerRadius::ZERO, Color32::from_rgb(29, 31, 36));
+
+    let x = |db: f32| rect.left() + rect.width() * (1.0 - db / METER_FLOOR_DB).clamp(0.0, 1.0);
+    let color = if peak_db > -1.0 { Color32::RED } else if peak_db > -12.0 { Color32::YELLOW } else { Color32::GREEN };
+
+    painter.rect_filled(egui::Rect::from_min_max(rect.min, egui::pos2(x(rms_db), rect.bottom())), CornerRadius::ZERO, color);
+    painter.vline(x(peak_db), rect.y_range(), egui::Stroke::new(2.0, Color32::WHITE));
+}
+
+pub fn signal_light(ui: &mut egui::Ui, label: &str, present: bool) {
+    ui.horizontal(|ui| {
+        let (rect, _) = ui.allocate_exact_size(Vec2::splat(12.0), egui::Sense::hover());
+        ui.painter().circle_filled(rect.center(), 6.0, if present { Color32::GREEN } else { Color32::DARK_GRAY });
+        ui.label(label);
+    });
+}
+
+pub fn encryption_label(ui: &mut egui::Ui, encryption: &Encryption) {
+    match encryption.state() {
+        EncryptionState::Off => ui.label("Not end-to-end encrypted (no passphrase)"),
+        EncryptionState::Pending => ui.label("Checking passphrase with peer..."),
+        EncryptionState::Verified => ui.colored_label(Color32::GREEN, "End-to-end encrypted"),
+        EncryptionState::Failed(reason) => ui.colored_label(Color32::RED, format!("Connection rejected: {reason}")),
+    };
+}
+
+// Optional, both sides have to enter the same one
+pub fn passphrase_entry(ui: &mut egui::Ui, passphrase: &Mutex<String>) {
+    let mut passphrase = passphrase.lock().unwrap();
+
+    let passphrase_label = ui.label("Passphrase (optional):");
+    ui.add(egui::TextEdit::singleline(&mut *passphrase).password(true)).labelled_by(passphrase_label.id);
+}
+
+// Chat history with a text box underneath, markers are only offered when there is a song position to pin them to
+pub fn chat_panel(ui: &mut egui::Ui, runtime: &Runtime, connection: &WebRTCConnection, history: &RwLock<ChatHistory>, marker_position: Option<(i64, f32)>) {
+    egui::ScrollArea::vertical().max_height(120.0).stick_to_bottom(true).show(ui, |ui| {
+        for entry in &history.read().unwrap().entries {
+            let from = if entry.from_self { "You" } else { "Peer" };
+            let status = if entry.from_self && !entry.acknowledged { " (sending)" } else { "" };
+
+            ui.label(format!("[{from}] {}{status}", entry.body));
+        }
+    });
+
+    let chat_entry_mutex = ui.memory_mut(|mem| {
+        mem.data
+            .get_temp_mut_or_default::<Arc<Mutex<String>>>(*CHAT_ENTRY_MEMORY_ID)
+            .clone()
+    });
+
+    let mut chat_entry = chat_entry_mutex.lock().unwrap();
+
+    let chat_label = ui.label("Message:");
+    ui.text_edit_singleline(&mut *chat_entry).labelled_by(chat_label.id);
+
+    let mut body = None;
+
+    ui.horizontal(|ui| {
+        if ui.button("Send").clicked() && !chat_entry.is_empty() {
+            body = Some(ChatBody::Text(std::mem::take(&mut *chat_entry)));
+        }
+
+        if let Some((position_samples, sample_rate)) = marker_position {
+            if ui.button("Add Marker").clicked() {
+                body = Some(ChatBody::Marker { position_samples, sample_rate, text: std::mem::take(&mut *chat_entry) });
+            }
+        }
+    });
+
+    if let Some(body) = body {
+        let message = history.write().unwrap().push_outgoing(body);
+
+        let conn_clone = connection.clone();
+        runtime.spawn(async move { conn_clone.send_control(ControlMessage::Chat(message)).await });
+    }
+}
@@ -0,0 +1,158 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use crate::{decode_samples, AudioEncoder, Dither, MidiEvent, QuantizedSamples, MIDI_EVENT_LEN};
+
+const HAS_SONG_POSITION: u8 = 1;
+const HAS_MIDI: u8 = 2;
+
+// The main stream plus up to three stems from the sender's aux inputs
+pub const MAX_STREAMS: usize = 4;
+
+// One sample of every stream at the same instant, streams the session does not carry stay silent
+pub type StreamFrame = [f32; MAX_STREAMS];
+
+// Serde default for stream counts missing from older sessions and peers
+pub(crate) fn one_stream() -> u16 {
+    1
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    PcmF32,
+    // Linear prediction and Rice coding, see `AudioEncoder`. The integer ones round the stream to 16 or 24 bits first.
+    LosslessI16,
+    LosslessI24,
+    LosslessF32,
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Codec::PcmF32 => write!(f, "32-bit float PCM"),
+            Codec::LosslessI16 => write!(f, "Lossless 16-bit"),
+            Codec::LosslessI24 => write!(f, "Lossless 24-bit"),
+            Codec::LosslessF32 => write!(f, "Lossless 32-bit float"),
+        }
+    }
+}
+
+// What the sending side is putting on the "audio" channel
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AudioFormat {
+    pub codec: Codec,
+    pub sample_rate: f32,
+    pub channels: u16,
+    // Largest number of samples per channel in one packet
+    pub frame_size: u32,
+    // Mono streams sent side by side, the first is the main one
+    #[serde(default = "one_stream")]
+    pub streams: u16,
+}
+
+// One block of mono samples as sent on the "audio" channel, interleaved by stream when a session carries several
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AudioPacket {
+    pub seq: u32,
+    // Sender's song position at the first sample, when its host provides one
+    pub song_position: Option<i64>,
+    // MIDI played during this block, timed relative to its first sample
+    pub midi: Vec<MidiEvent>,
+    pub samples: Vec<f32>,
+}
+
+impl AudioPacket {
+    // Uncompressed, the way the sender's monitor return feed travels
+    pub fn to_bytes(&self) -> Bytes {
+        self.encode(&mut AudioEncoder::new(Codec::PcmF32, Dither::None), 1)
+    }
+
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        Self::decode(data, Codec::PcmF32, 1)
+    }
+
+    // With the samples in the session's codec, `streams` tells it how they are interleaved
+    pub fn encode(&self, encoder: &mut AudioEncoder, streams: usize) -> Bytes {
+        self.encode_quantized(&encoder.quantize(&self.samples, streams))
+    }
+
+    // With `samples` already quantized in place of the packet's own, which are left out
+    pub fn encode_quantized(&self, samples: &QuantizedSamples) -> Bytes {
+        let mut buf = BytesMut::with_capacity(15 + self.midi.len() * MIDI_EVENT_LEN + samples.len() * 4);
+
+        let mut flags = 0;
+        if self.song_position.is_some() {
+            flags |= HAS_SONG_POSITION;
+        }
+        if !self.midi.is_empty() {
+            flags |= HAS_MIDI;
+        }
+
+        buf.put_u8(flags);
+        buf.put_u32_le(self.seq);
+
+        if let Some(song_position) = self.song_position {
+            buf.put_i64_le(song_position);
+        }
+
+        if !self.midi.is_empty() {
+            buf.put_u16_le(self.midi.len() as u16);
+
+            for event in &self.midi {
+                event.write(&mut buf);
+            }
+        }
+
+        samples.encode(&mut buf);
+
+        buf.freeze()
+    }
+
+    pub fn decode(mut data: &[u8], codec: Codec, streams: usize) -> Option<Self> {
+        if data.remaining() < 5 {
+            return None;
+        }
+
+        let flags = data.get_u8();
+        let seq = data.get_u32_le();
+
+        let song_position = if flags & HAS_SONG_POSITION != 0 {
+            if data.remaining() < 8 {
+                return None;
+            }
+
+            Some(data.get_i64_le())
+        } else {
+            None
+        };
+
+        let mut midi = Vec::new();
+
+        if flags & HAS_MIDI != 0 {
+            if data.remaining() < 2 {
+                return None;
+            }
+
+            let count = data.get_u16_le();
+            for _ in 0..count {
+                midi.push(MidiEvent::read(&mut data)?);
+            }
+        }
+
+        let samples = decode_samples(codec, data, streams)?;
+
+        Some(Self { seq, song_position, midi, samples })
+    }
+
+    // Samples of all `streams` grouped per instant. A trailing partial frame is dropped.
+    pub fn frames(&self, streams: usize) -> impl Iterator<Item = StreamFrame> + '_ {
+        let streams = streams.clamp(1, MAX_STREAMS);
+
+        self.samples.chunks_exact(streams).map(move |samples| {
+            let mut frame = StreamFrame::default();
+            frame[..streams].copy_from_slice(samples);
+            frame
+        })
+    }
+}
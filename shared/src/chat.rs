@@ -0,0 +1,94 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{unix_time, ControlMessage};
+
+// Oldest entries are dropped past this so the plugin state stays small
+const MAX_HISTORY: usize = 500;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ChatBody {
+    Text(String),
+    // A note pinned to a song position on the sender's timeline
+    Marker { position_samples: i64, sample_rate: f32, text: String },
+}
+
+impl std::fmt::Display for ChatBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChatBody::Text(text) => write!(f, "{text}"),
+            ChatBody::Marker { position_samples, sample_rate, text } if *sample_rate > 0.0 => {
+                write!(f, "Marker at {:.2} s: {text}", *position_samples as f64 / *sample_rate as f64)
+            }
+            ChatBody::Marker { position_samples, text, .. } => write!(f, "Marker at sample {position_samples}: {text}"),
+        }
+    }
+}
+
+// What goes over the wire, ids are only unique per sending side
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub id: u64,
+    pub body: ChatBody,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChatEntry {
+    pub id: u64,
+    pub from_self: bool,
+    pub body: ChatBody,
+    // Unix time in seconds
+    pub time: u64,
+    pub acknowledged: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChatHistory {
+    pub entries: Vec<ChatEntry>,
+    next_id: u64,
+}
+
+impl ChatHistory {
+    // Records a message we are about to send and returns it ready for the wire
+    pub fn push_outgoing(&mut self, body: ChatBody) -> ChatMessage {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.push(ChatEntry { id, from_self: true, body: body.clone(), time: unix_time(), acknowledged: false });
+
+        ChatMessage { id, body }
+    }
+
+    pub fn push_incoming(&mut self, message: ChatMessage) {
+        self.push(ChatEntry { id: message.id, from_self: false, body: message.body, time: unix_time(), acknowledged: true });
+    }
+
+    pub fn acknowledge(&mut self, id: u64) {
+        if let Some(entry) = self.entries.iter_mut().rev().find(|entry| entry.from_self && entry.id == id) {
+            entry.acknowledged = true;
+        }
+    }
+
+    // Handles chat and acknowledgements from the control channel, returning the reply to send back
+    pub fn on_control(&mut self, message: ControlMessage) -> Option<ControlMessage> {
+        match message {
+            ControlMessage::Chat(message) => {
+                let id = message.id;
+                self.push_incoming(message);
+                Some(ControlMessage::Ack { id })
+            }
+            ControlMessage::Ack { id } => {
+                self.acknowledge(id);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn push(&mut self, entry: ChatEntry) {
+        self.entries.push(entry);
+
+        if self.entries.len() > MAX_HISTORY {
+            self.entries.drain(..self.entries.len() - MAX_HISTORY);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use crate::{AudioFormat, ChatMessage, Hello, HelloAck, Ping, Pong, TransportSnapshot, NONCE_LEN, SALT_LEN};
+
+// First byte of every frame on the "tcp" channel, bumped whenever a change would confuse an older peer
+pub const PROTOCOL_VERSION: u8 = 1;
+
+// Periodic report from the receiving side about how the stream is arriving
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StatsReport {
+    pub buffered_ms: f32,
+    pub packets_received: u64,
+    // Left out by older receivers
+    #[serde(default)]
+    pub packets_lost: u64,
+    #[serde(default)]
+    pub jitter_ms: f32,
+    #[serde(default)]
+    pub underruns: u64,
+    #[serde(default)]
+    pub overruns: u64,
+    #[serde(default)]
+    pub concealed_samples: u64,
+    #[serde(default)]
+    pub signal_present: bool,
+}
+
+// Everything sent on the reliable "tcp" channel. Variants from a newer peer decode as Unknown and are ignored.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlMessage {
+    Ping(Ping),
+    Pong(Pong),
+    Hello(Hello),
+    HelloAck(HelloAck),
+    // Passphrase handshake, see `Encryption`
+    AuthHello { salt: [u8; SALT_LEN], nonce: [u8; NONCE_LEN] },
+    AuthProof { mac: Vec<u8> },
+    FormatChange(AudioFormat),
+    Mute { muted: bool },
+    Stats(StatsReport),
+    Transport(TransportSnapshot),
+    Chat(ChatMessage),
+    // Confirms a chat message with this id arrived
+    Ack { id: u64 },
+    Bye { reason: String },
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug)]
+pub enum ControlError {
+    Empty,
+    UnsupportedVersion(u8),
+    Malformed(serde_json::Error),
+}
+
+impl std::fmt::Display for ControlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControlError::Empty => write!(f, "empty control frame"),
+            ControlError::UnsupportedVersion(version) => write!(f, "peer speaks control protocol v{version}, we speak v{PROTOCOL_VERSION}"),
+            ControlError::Malformed(err) => write!(f, "malformed control frame: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ControlError {}
+
+impl ControlMessage {
+    // Frame layout: [PROTOCOL_VERSION][JSON message]
+    pub fn to_bytes(&self) -> Bytes {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+
+        let mut buf = BytesMut::with_capacity(1 + json.len());
+        buf.put_u8(PROTOCOL_VERSION);
+        buf.put_slice(&json);
+        buf.freeze()
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, ControlError> {
+        let (&version, json) = data.split_first().ok_or(ControlError::Empty)?;
+
+        if version != PROTOCOL_VERSION {
+            return Err(ControlError::UnsupportedVersion(version));
+        }
+
+        serde_json::from_slice(json).map_err(ControlError::Malformed)
+    }
+}
@@ -0,0 +1,221 @@
+use std::sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Mutex, OnceLock};
+
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use argon2::Argon2;
+use bytes::{BufMut, Bytes, BytesMut};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::ControlMessage;
+
+pub const SALT_LEN: usize = 16;
+pub const NONCE_LEN: usize = 16;
+
+const KEY_LEN: usize = 32;
+const OFFERER_PROOF_LABEL: &[u8] = b"live-collab offerer proof";
+const ANSWERER_PROOF_LABEL: &[u8] = b"live-collab answerer proof";
+const AUDIO_KEY_LABEL: &[u8] = b"live-collab audio";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EncryptionState {
+    // No passphrase set on this side
+    Off,
+    // Waiting for the peer to prove it knows the passphrase
+    Pending,
+    Verified,
+    Failed(String),
+}
+
+// Argon2id over the passphrase, salted with both sides' salts so neither side picks the salt alone
+pub fn derive_key(passphrase: &str, salt: &[u8; SALT_LEN], peer_salt: &[u8; SALT_LEN]) -> [u8; KEY_LEN] {
+    let (first, second) = if salt <= peer_salt { (salt, peer_salt) } else { (peer_salt, salt) };
+
+    let mut combined = [0u8; SALT_LEN * 2];
+    combined[..SALT_LEN].copy_from_slice(first);
+    combined[SALT_LEN..].copy_from_slice(second);
+
+    let mut key = [0u8; KEY_LEN];
+    Argon2::default().hash_password_into(passphrase.as_bytes(), &combined, &mut key).expect("valid argon2 output length");
+    key
+}
+
+fn mac(key: &[u8], label: &[u8], first: &[u8], second: &[u8]) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("hmac takes any key length");
+    mac.update(label);
+    mac.update(first);
+    mac.update(second);
+    mac
+}
+
+struct Keys {
+    master: [u8; KEY_LEN],
+    peer_nonce: [u8; NONCE_LEN],
+    // One key per direction, so both sides can count their frames from zero
+    seal: Aes256Gcm,
+    open: Aes256Gcm,
+}
+
+// Optional end-to-end layer on top of DTLS. Both sides exchange a salt and nonce on the control channel,
+// derive the same key from the passphrase and prove it to each other before any audio is sealed or opened.
+pub struct Encryption {
+    passphrase: Option<String>,
+    // Each side proves under its own role's label, so a proof sent back to its maker never verifies
+    offerer: bool,
+    salt: [u8; SALT_LEN],
+    nonce: [u8; NONCE_LEN],
+    keys: OnceLock<Keys>,
+    state: Mutex<EncryptionState>,
+    // Set once the state is Verified, which it never leaves, for the audio thread that must not wait on the lock
+    verified: AtomicBool,
+    // The peer's proof may arrive before its salt has been turned into a key
+    pending_proof: Mutex<Option<Vec<u8>>>,
+    send_counter: AtomicU64,
+}
+
+impl Default for Encryption {
+    fn default() -> Self {
+        Self::new(None, false)
+    }
+}
+
+impl Encryption {
+    pub fn new(passphrase: Option<String>, offerer: bool) -> Self {
+        let passphrase = passphrase.filter(|passphrase| !passphrase.is_empty());
+        let state = if passphrase.is_some() { EncryptionState::Pending } else { EncryptionState::Off };
+
+        Self {
+            passphrase,
+            offerer,
+            salt: rand::random(),
+            nonce: rand::random(),
+            keys: OnceLock::new(),
+            state: Mutex::new(state),
+            verified: AtomicBool::new(false),
+            pending_proof: Mutex::new(None),
+            send_counter: AtomicU64::new(0),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.passphrase.is_some()
+    }
+
+    pub fn state(&self) -> EncryptionState {
+        self.state.lock().unwrap().clone()
+    }
+
+    // Audio may flow: either there is no passphrase or the peer proved it has the same one
+    pub fn ready(&self) -> bool {
+        !self.enabled() || self.verified.load(Ordering::Acquire)
+    }
+
+    pub fn auth_hello(&self) -> Option<ControlMessage> {
+        self.enabled().then_some(ControlMessage::AuthHello { salt: self.salt, nonce: self.nonce })
+    }
+
+    // Slow on purpose (Argon2), call off the audio thread
+    pub fn on_auth_hello(&self, peer_salt: [u8; SALT_LEN], peer_nonce: [u8; NONCE_LEN]) -> Option<ControlMessage> {
+        let passphrase = self.passphrase.as_ref()?;
+
+        // Only our own hello sent back has our nonce
+        if peer_nonce == self.nonce {
+            let mut state = self.state.lock().unwrap();
+            if *state == EncryptionState::Pending {
+                *state = EncryptionState::Failed("peer sent back our own handshake".to_owned());
+            }
+            return None;
+        }
+
+        let keys = self.keys.get_or_init(|| {
+            let master = derive_key(passphrase, &self.salt, &peer_salt);
+            let seal = mac(&master, AUDIO_KEY_LABEL, &self.nonce, &peer_nonce).finalize().into_bytes();
+            let open = mac(&master, AUDIO_KEY_LABEL, &peer_nonce, &self.nonce).finalize().into_bytes();
+
+            Keys {
+                master,
+                peer_nonce,
+                seal: Aes256Gcm::new(&seal),
+                open: Aes256Gcm::new(&open),
+            }
+        });
+
+        if let Some(proof) = self.pending_proof.lock().unwrap().take() {
+            self.verify(keys, &proof);
+        }
+
+        let proof = mac(&keys.master, proof_label(self.offerer), &self.nonce, &keys.peer_nonce).finalize().into_bytes();
+        Some(ControlMessage::AuthProof { mac: proof.to_vec() })
+    }
+
+    pub fn on_auth_proof(&self, proof: Vec<u8>) {
+        if !self.enabled() {
+            return;
+        }
+
+        match self.keys.get() {
+            Some(keys) => self.verify(keys, &proof),
+            None => *self.pending_proof.lock().unwrap() = Some(proof),
+        }
+    }
+
+    fn verify(&self, keys: &Keys, proof: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+
+        if *state != EncryptionState::Pending {
+            return;
+        }
+
+        *state = match mac(&keys.master, proof_label(!self.offerer), &keys.peer_nonce, &self.nonce).verify_slice(proof) {
+            Ok(()) => EncryptionState::Verified,
+            Err(_) => EncryptionState::Failed("passphrase does not match the peer's".to_owned()),
+        };
+        self.verified.store(*state == EncryptionState::Verified, Ordering::Release);
+    }
+
+    // Frame layout: [counter u64 LE][AES-256-GCM ciphertext + tag]. Passes data through untouched without a passphrase.
+    pub fn seal(&self, data: Bytes) -> Option<Bytes> {
+        if !self.enabled() {
+            return Some(data);
+        }
+
+        if !self.verified.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let keys = self.keys.get()?;
+        let counter = self.send_counter.fetch_add(1, Ordering::Relaxed);
+        let ciphertext = keys.seal.encrypt(&frame_nonce(counter), data.as_ref()).ok()?;
+
+        let mut buf = BytesMut::with_capacity(8 + ciphertext.len());
+        buf.put_u64_le(counter);
+        buf.put_slice(&ciphertext);
+        Some(buf.freeze())
+    }
+
+    // Frames that fail authentication, or arrive before the peer is verified, are dropped
+    pub fn open(&self, frame: Bytes) -> Option<Bytes> {
+        if !self.enabled() {
+            return Some(frame);
+        }
+
+        if !self.verified.load(Ordering::Acquire) || frame.len() < 8 {
+            return None;
+        }
+
+        let keys = self.keys.get()?;
+        let counter = u64::from_le_bytes(frame[..8].try_into().ok()?);
+        keys.open.decrypt(&frame_nonce(counter), &frame[8..]).ok().map(Bytes::from)
+    }
+}
+
+fn proof_label(offerer: bool) -> &'static [u8] {
+    if offerer { OFFERER_PROOF_LABEL } else { ANSWERER_PROOF_LABEL }
+}
+
+fn frame_nonce(counter: u64) -> Nonce<aes_gcm::aead::consts::U12> {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce.into()
+}
@@ -0,0 +1,341 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+// Samples per channel in every frame but the last
+pub const FLAC_BLOCK_SIZE: usize = 4096;
+
+// Recordings keep the stream's floats as 24-bit integers
+pub const FLAC_BITS_PER_SAMPLE: u32 = 24;
+
+// Shared with the stream codec in lossless.rs, which writes subframes much like FLAC does
+pub(crate) const MAX_FIXED_ORDER: usize = 4;
+// A 4-bit parameter, 15 is the escape code
+pub(crate) const MAX_RICE_PARAMETER: u32 = 14;
+// A 5-bit parameter as the stream codec writes them, float samples can leave residuals far wider than 16 bits
+pub(crate) const MAX_RICE5_PARAMETER: u32 = 31;
+
+// MSB first, the way FLAC packs everything
+#[derive(Default)]
+pub(crate) struct BitWriter {
+    bytes: Vec<u8>,
+    // Bits not yet making up a whole byte, in the low end
+    pending: u64,
+    pending_bits: u32,
+}
+
+impl BitWriter {
+    pub(crate) fn write(&mut self, value: u64, bits: u32) {
+        debug_assert!(bits <= 32);
+        if bits == 0 {
+            return;
+        }
+
+        self.pending = (self.pending << bits) | (value & ((1 << bits) - 1));
+        self.pending_bits += bits;
+
+        while self.pending_bits >= 8 {
+            self.pending_bits -= 8;
+            self.bytes.push((self.pending >> self.pending_bits) as u8);
+        }
+    }
+
+    pub(crate) fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    pub(crate) fn write_unary(&mut self, zeros: u64) {
+        for _ in 0..zeros / 32 {
+            self.write(0, 32);
+        }
+        self.write(1, (zeros % 32) as u32 + 1);
+    }
+
+    pub(crate) fn write_rice(&mut self, value: i64, parameter: u32) {
+        let folded = zigzag(value);
+        self.write_unary(folded >> parameter);
+        self.write(folded, parameter);
+    }
+
+    // Pads with zeros up to the next byte
+    pub(crate) fn align(&mut self) {
+        if self.pending_bits > 0 {
+            self.write(0, 8 - self.pending_bits);
+        }
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub(crate) fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+// Signed residuals as unsigned, 0, -1, 1, -2, ... become 0, 1, 2, 3, ...
+pub(crate) fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+pub(crate) fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |mut crc, byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+pub(crate) fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |mut crc, byte| {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+// Residual of the fixed polynomial predictor of `order` (0 to 4), for every sample after the warm-up
+pub(crate) fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    (order..samples.len())
+        .map(|i| {
+            let s = |back: usize| samples[i - back];
+            match order {
+                0 => s(0),
+                1 => s(0) - s(1),
+                2 => s(0) - 2 * s(1) + s(2),
+                3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+                _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+            }
+        })
+        .collect()
+}
+
+// Cheapest Rice parameter up to `max_parameter` for `residual`, and the bits it takes. The mean narrows it down
+// to three candidates, only those are counted exactly.
+pub(crate) fn rice_parameter(residual: &[i64], max_parameter: u32) -> (u32, u64) {
+    let sum: u64 = residual.iter().map(|value| zigzag(*value)).sum();
+    let mean = sum / (residual.len() as u64).max(1);
+    let guess = (u64::BITS - mean.leading_zeros()).min(max_parameter);
+
+    (guess.saturating_sub(1)..=(guess + 1).min(max_parameter))
+        .map(|parameter| (parameter, rice_bits(residual, parameter)))
+        .min_by_key(|(_, bits)| *bits)
+        .unwrap()
+}
+
+pub(crate) fn rice_bits(residual: &[i64], parameter: u32) -> u64 {
+    residual.iter().map(|value| (zigzag(*value) >> parameter) + 1 + parameter as u64).sum()
+}
+
+// Writes a FLAC file with fixed predictor subframes, falling back to verbatim where prediction does not pay.
+// STREAMINFO is rewritten with the final length by `finish`, so the output has to be seekable.
+pub struct FlacWriter<W: Write + Seek> {
+    out: W,
+    sample_rate: u32,
+    channels: usize,
+    // Interleaved samples waiting for a full block
+    pending: Vec<i64>,
+    frame_number: u64,
+    total_samples: u64,
+    min_frame_size: u32,
+    max_frame_size: u32,
+}
+
+impl<W: Write + Seek> FlacWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32, channels: usize) -> io::Result<Self> {
+        if !(1..=8).contains(&channels) || sample_rate == 0 || sample_rate >= 1 << 20 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("FLAC cannot hold {channels} channels at {sample_rate} Hz")));
+        }
+
+        out.write_all(b"fLaC")?;
+
+        let mut writer = Self {
+            out,
+            sample_rate,
+            channels,
+            pending: Vec::with_capacity(FLAC_BLOCK_SIZE * channels),
+            frame_number: 0,
+            total_samples: 0,
+            min_frame_size: 0,
+            max_frame_size: 0,
+        };
+        writer.write_streaminfo()?;
+
+        Ok(writer)
+    }
+
+    // Interleaved floats, anything outside -1..1 is clipped
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        let full_scale = ((1 << (FLAC_BITS_PER_SAMPLE - 1)) - 1) as f32;
+
+        for sample in samples {
+            self.pending.push((sample.clamp(-1.0, 1.0) * full_scale).round() as i64);
+
+            if self.pending.len() == FLAC_BLOCK_SIZE * self.channels {
+                self.write_frame()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        // A trailing partial frame is dropped, the file always ends on whole samples
+        self.pending.truncate(self.pending.len() - self.pending.len() % self.channels);
+        if !self.pending.is_empty() {
+            self.write_frame()?;
+        }
+
+        self.out.seek(SeekFrom::Start(4))?;
+        self.write_streaminfo()?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+
+        Ok(self.out)
+    }
+
+    fn write_streaminfo(&mut self) -> io::Result<()> {
+        let mut bits = BitWriter::default();
+
+        // Last metadata block, type 0, 34 bytes
+        bits.write(1, 1);
+        bits.write(0, 7);
+        bits.write(34, 24);
+
+        bits.write(FLAC_BLOCK_SIZE as u64, 16);
+        bits.write(FLAC_BLOCK_SIZE as u64, 16);
+        bits.write(self.min_frame_size as u64, 24);
+        bits.write(self.max_frame_size as u64, 24);
+        bits.write(self.sample_rate as u64, 20);
+        bits.write(self.channels as u64 - 1, 3);
+        bits.write(FLAC_BITS_PER_SAMPLE as u64 - 1, 5);
+        bits.write(self.total_samples >> 32, 4);
+        bits.write(self.total_samples & 0xFFFF_FFFF, 32);
+
+        // No MD5 of the audio, all zeros means unknown
+        for _ in 0..4 {
+            bits.write(0, 32);
+        }
+
+        self.out.write_all(&bits.into_bytes())
+    }
+
+    fn write_frame(&mut self) -> io::Result<()> {
+        let block_size = self.pending.len() / self.channels;
+        let mut bits = BitWriter::default();
+
+        // Sync code, fixed block size strategy
+        bits.write(0b11_1111_1111_1110, 14);
+        bits.write(0, 1);
+        bits.write(0, 1);
+
+        // 4096 has its own code, a short last block spells its size out after the frame number
+        let full_block = block_size == FLAC_BLOCK_SIZE;
+        bits.write(if full_block { 0b1100 } else { 0b0111 }, 4);
+        // Sample rate from STREAMINFO, independent channels, 24 bits per sample
+        bits.write(0, 4);
+        bits.write(self.channels as u64 - 1, 4);
+        bits.write(0b110, 3);
+        bits.write(0, 1);
+
+        write_utf8_number(&mut bits, self.frame_number);
+        if !full_block {
+            bits.write(block_size as u64 - 1, 16);
+        }
+
+        let header_crc = crc8(bits.bytes());
+        bits.write(header_crc as u64, 8);
+
+        for channel in 0..self.channels {
+            let samples: Vec<i64> = self.pending.iter().skip(channel).step_by(self.channels).copied().collect();
+            write_subframe(&mut bits, &samples, FLAC_BITS_PER_SAMPLE);
+        }
+
+        bits.align();
+        let frame_crc = crc16(bits.bytes());
+        bits.write(frame_crc as u64, 16);
+
+        let frame = bits.into_bytes();
+        self.out.write_all(&frame)?;
+
+        let frame_size = frame.len() as u32;
+        self.min_frame_size = if self.frame_number == 0 { frame_size } else { self.min_frame_size.min(frame_size) };
+        self.max_frame_size = self.max_frame_size.max(frame_size);
+        self.frame_number += 1;
+        self.total_samples += block_size as u64;
+        self.pending.clear();
+
+        Ok(())
+    }
+}
+
+// Frame numbers use the same variable length coding as UTF-8
+fn write_utf8_number(bits: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        bits.write(value, 8);
+        return;
+    }
+
+    let continuation_bytes = match value {
+        0x80..0x800 => 1,
+        0x800..0x1_0000 => 2,
+        0x1_0000..0x20_0000 => 3,
+        0x20_0000..0x400_0000 => 4,
+        0x400_0000..0x8000_0000 => 5,
+        _ => 6,
+    };
+
+    // As many leading ones as there are bytes in total, the rest of the first byte carries the top bits
+    let marker = (0xFF << (7 - continuation_bytes)) & 0xFF;
+    bits.write(marker | (value >> (6 * continuation_bytes)), 8);
+
+    for byte in (0..continuation_bytes).rev() {
+        bits.write(0x80 | ((value >> (6 * byte)) & 0x3F), 8);
+    }
+}
+
+// Cheapest of the fixed predictors, or verbatim
+fn write_subframe(bits: &mut BitWriter, samples: &[i64], bits_per_sample: u32) {
+    let verbatim_bits = samples.len() as u64 * bits_per_sample as u64;
+
+    let best = (0..=MAX_FIXED_ORDER.min(samples.len().saturating_sub(1)))
+        .map(|order| {
+            let residual = fixed_residual(samples, order);
+            let (parameter, residual_bits) = rice_parameter(&residual, MAX_RICE_PARAMETER);
+            (order, parameter, residual, order as u64 * bits_per_sample as u64 + 10 + residual_bits)
+        })
+        .min_by_key(|(.., total_bits)| *total_bits);
+
+    // Zero padding bit, then the type, then no wasted bits
+    match best {
+        Some((order, parameter, residual, total_bits)) if total_bits < verbatim_bits => {
+            bits.write(0, 1);
+            bits.write(0b001000 | order as u64, 6);
+            bits.write(0, 1);
+
+            for sample in &samples[..order] {
+                bits.write_signed(*sample, bits_per_sample);
+            }
+
+            // Rice coding with 4-bit parameters, one partition
+            bits.write(0, 2);
+            bits.write(0, 4);
+            bits.write(parameter as u64, 4);
+            for value in residual {
+                bits.write_rice(value, parameter);
+            }
+        }
+        _ => {
+            bits.write(0, 1);
+            bits.write(0b000001, 6);
+            bits.write(0, 1);
+
+            for sample in samples {
+                bits.write_signed(*sample, bits_per_sample);
+            }
+        }
+    }
+}
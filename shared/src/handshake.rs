@@ -0,0 +1,191 @@
+use std::sync::{atomic::{AtomicU32, Ordering}, Arc, Mutex};
+
+use arc_swap::ArcSwapOption;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{AudioFormat, Codec, MAX_STREAMS, PROTOCOL_VERSION};
+
+// Codecs this build can encode and decode, most preferred first
+pub const SUPPORTED_CODECS: &[Codec] = &[Codec::PcmF32, Codec::LosslessI24, Codec::LosslessI16, Codec::LosslessF32];
+
+pub const HELLO_INTERVAL_MS: u64 = 250;
+
+// Largest packet either plugin is built to handle, larger host blocks are split
+pub const MAX_FRAME_SIZE: u32 = 2048;
+
+// Sent by both sides as soon as the control channel opens
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Hello {
+    pub plugin_version: String,
+    pub protocol_version: u8,
+    // Optional features this side understands, e.g. "midi", "chat", "transport"
+    pub features: Vec<String>,
+    pub codecs: Vec<Codec>,
+    // Exactly one side of a session sends audio, its sample rate is the stream's
+    pub sends_audio: bool,
+    pub sample_rate: f32,
+    // Channels the sending side produces, or the receiving side can play
+    pub channels: u16,
+    pub max_frame_size: u32,
+    // Whether this side has a passphrase set, both must agree
+    #[serde(default)]
+    pub encrypted: bool,
+    // Mono streams the sending side has, or the receiving side can route
+    #[serde(default = "crate::audio::one_stream")]
+    pub streams: u16,
+}
+
+impl Hello {
+    pub fn new(plugin_version: &str, sends_audio: bool, sample_rate: f32, channels: u16) -> Self {
+        Self {
+            plugin_version: plugin_version.to_owned(),
+            protocol_version: PROTOCOL_VERSION,
+            features: ["midi", "chat", "transport"].map(str::to_owned).to_vec(),
+            codecs: SUPPORTED_CODECS.to_vec(),
+            sends_audio,
+            sample_rate,
+            channels,
+            max_frame_size: MAX_FRAME_SIZE,
+            encrypted: false,
+            streams: 1,
+        }
+    }
+}
+
+// Answer to the peer's Hello with what this side agreed to, or why it refused
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HelloAck {
+    pub plugin_version: String,
+    pub result: Result<AudioFormat, String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum HandshakeState {
+    #[default]
+    Pending,
+    Agreed(AudioFormat),
+    Refused(String),
+}
+
+// Both sides run this on the pair of hellos and reach the same answer, regardless of which one is local
+pub fn negotiate(local: &Hello, remote: &Hello) -> Result<AudioFormat, String> {
+    if local.protocol_version != remote.protocol_version {
+        return Err(format!(
+            "peer (v{}) speaks control protocol v{}, this plugin (v{}) speaks v{}",
+            remote.plugin_version, remote.protocol_version, local.plugin_version, local.protocol_version
+        ));
+    }
+
+    match (local.encrypted, remote.encrypted) {
+        (true, false) => return Err("peer has no passphrase set, but this side requires one".to_owned()),
+        (false, true) => return Err("peer requires a passphrase, set the same one on this side".to_owned()),
+        _ => {}
+    }
+
+    let sender = match (local.sends_audio, remote.sends_audio) {
+        (true, false) => local,
+        (false, true) => remote,
+        (true, true) => return Err("both peers are senders, connect a sender to a receiver".to_owned()),
+        (false, false) => return Err("both peers are receivers, connect a sender to a receiver".to_owned()),
+    };
+
+    let codec = SUPPORTED_CODECS
+        .iter()
+        .find(|codec| local.codecs.contains(codec) && remote.codecs.contains(codec))
+        .copied()
+        .ok_or_else(|| format!("no common codec (peer offers {:?}, this plugin {:?})", remote.codecs, local.codecs))?;
+
+    let channels = local.channels.min(remote.channels);
+    if channels == 0 {
+        return Err("peer has no audio channels".to_owned());
+    }
+
+    let frame_size = local.max_frame_size.min(remote.max_frame_size);
+    if frame_size == 0 {
+        return Err("peer accepts no audio frames".to_owned());
+    }
+
+    // Stems the receiver cannot route are not sent at all
+    let streams = local.streams.min(remote.streams).clamp(1, MAX_STREAMS as u16);
+
+    Ok(AudioFormat { codec, sample_rate: sender.sample_rate, channels, frame_size, streams })
+}
+
+#[derive(Default)]
+pub struct Handshake {
+    local: Mutex<Option<Hello>>,
+    state: Mutex<HandshakeState>,
+    // Copies of the outcome for the audio thread, which must not wait on the locks above
+    agreed_format: ArcSwapOption<AudioFormat>,
+    // Bits of the rate from the peer's last hello, which anything it sends back besides the stream runs at. 0 until then.
+    remote_sample_rate: AtomicU32,
+}
+
+impl Handshake {
+    pub fn set_local(&self, hello: Hello) {
+        *self.local.lock().unwrap() = Some(hello);
+    }
+
+    pub fn local(&self) -> Option<Hello> {
+        self.local.lock().unwrap().clone()
+    }
+
+    pub fn remote_sample_rate(&self) -> Option<f32> {
+        Some(self.remote_sample_rate.load(Ordering::Relaxed)).filter(|bits| *bits != 0).map(f32::from_bits)
+    }
+
+    pub fn state(&self) -> HandshakeState {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn agreed_format(&self) -> Option<AudioFormat> {
+        self.agreed_format.load().as_deref().copied()
+    }
+
+    // A refusal sticks, nothing the peer sends afterwards can turn it into an agreement
+    pub fn refuse(&self, reason: String) {
+        self.set_state(&mut self.state.lock().unwrap(), HandshakeState::Refused(reason));
+    }
+
+    // Called with the state locked, so the published format follows the state in the same order
+    fn set_state(&self, state: &mut HandshakeState, new_state: HandshakeState) {
+        let agreed = match &new_state {
+            HandshakeState::Agreed(format) => Some(Arc::new(*format)),
+            _ => None,
+        };
+
+        *state = new_state;
+        self.agreed_format.store(agreed);
+    }
+
+    pub fn on_hello(&self, remote: Hello) -> Option<HelloAck> {
+        let local = self.local()?;
+        let result = negotiate(&local, &remote);
+        self.remote_sample_rate.store(remote.sample_rate.to_bits(), Ordering::Relaxed);
+
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, HandshakeState::Refused(_)) {
+            let new_state = match &result {
+                Ok(format) => HandshakeState::Agreed(*format),
+                Err(reason) => HandshakeState::Refused(reason.clone()),
+            };
+            self.set_state(&mut state, new_state);
+        }
+
+        Some(HelloAck { plugin_version: local.plugin_version, result })
+    }
+
+    pub fn on_hello_ack(&self, ack: HelloAck) {
+        let mut state = self.state.lock().unwrap();
+
+        // Our own verdict wins, the peer usually refuses for the same reason
+        if *state == HandshakeState::Pending || (matches!(*state, HandshakeState::Agreed(_)) && ack.result.is_err()) {
+            let new_state = match ack.result {
+                Ok(format) => HandshakeState::Agreed(format),
+                Err(reason) => HandshakeState::Refused(format!("peer (v{}) refused: {reason}", ack.plugin_version)),
+            };
+            self.set_state(&mut state, new_state);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::{fs, io::{self, Write}, path::{Path, PathBuf}, sync::Mutex};
+
+use serde::{Deserialize, Serialize};
+use webrtc::peer_connection::certificate::RTCCertificate;
+
+use crate::normalize_fingerprint;
+
+// Overrides where the identity and trusted peers are kept, mostly for tests and portable installs
+pub const CONFIG_DIR_ENV_VAR: &str = "LIVE_COLLAB_CONFIG_DIR";
+
+const CERTIFICATE_FILE: &str = "identity.pem";
+const TRUSTED_PEERS_FILE: &str = "trusted_peers.json";
+
+// Per-user settings directory shared by every plugin instance on this machine
+pub fn config_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os(CONFIG_DIR_ENV_VAR) {
+        return Some(PathBuf::from(dir));
+    }
+
+    let base = if cfg!(target_os = "windows") {
+        PathBuf::from(std::env::var_os("APPDATA")?)
+    } else if cfg!(target_os = "macos") {
+        PathBuf::from(std::env::var_os("HOME")?).join("Library").join("Application Support")
+    } else {
+        match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        }
+    };
+
+    Some(base.join("live-collab"))
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TrustedPeer {
+    pub name: String,
+    pub fingerprint: String,
+}
+
+// Long-lived DTLS certificate of this installation, plus the collaborators the user chose to trust
+pub struct Identity {
+    pub certificate: RTCCertificate,
+    pub fingerprint: String,
+    dir: PathBuf,
+    trusted_peers: Mutex<Vec<TrustedPeer>>,
+}
+
+impl Identity {
+    pub fn load_default() -> Result<Self, Box<dyn std::error::Error>> {
+        Self::load_or_create(&config_dir().ok_or("no config directory for this user")?)
+    }
+
+    pub fn load_or_create(dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        fs::create_dir_all(dir)?;
+
+        let certificate_path = dir.join(CERTIFICATE_FILE);
+        let certificate = match fs::read_to_string(&certificate_path) {
+            Ok(pem) => RTCCertificate::from_pem(&pem)?,
+            // Anything but a missing file is left alone, a new certificate would make every peer distrust us
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let certificate = RTCCertificate::from_key_pair(rcgen::KeyPair::generate()?)?;
+                write_private(&certificate_path, certificate.serialize_pem().as_bytes())?;
+                certificate
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let fingerprint = certificate
+            .get_fingerprints()
+            .into_iter()
+            .find(|fingerprint| fingerprint.algorithm == "sha-256")
+            .map(|fingerprint| normalize_fingerprint(&format!("{} {}", fingerprint.algorithm, fingerprint.value)))
+            .ok_or("certificate has no sha-256 fingerprint")?;
+
+        // An unreadable list trusts nobody, and is left for the user to fix rather than overwritten
+        let trusted_peers = read_trusted_peers(dir).unwrap_or_default();
+
+        Ok(Self {
+            certificate,
+            fingerprint,
+            dir: dir.to_owned(),
+            trusted_peers: Mutex::new(trusted_peers),
+        })
+    }
+
+    pub fn trusted_peers(&self) -> Vec<TrustedPeer> {
+        self.trusted_peers.lock().unwrap().clone()
+    }
+
+    pub fn trusted_peer(&self, fingerprint: &str) -> Option<TrustedPeer> {
+        let fingerprint = normalize_fingerprint(fingerprint);
+        self.trusted_peers.lock().unwrap().iter().find(|peer| peer.fingerprint == fingerprint).cloned()
+    }
+
+    // Trusting a fingerprint again renames it
+    pub fn trust(&self, name: String, fingerprint: &str) -> io::Result<()> {
+        let fingerprint = normalize_fingerprint(fingerprint);
+
+        self.update(|trusted_peers| {
+            trusted_peers.retain(|peer| peer.fingerprint != fingerprint);
+            trusted_peers.push(TrustedPeer { name, fingerprint });
+        })
+    }
+
+    pub fn forget(&self, fingerprint: &str) -> io::Result<()> {
+        let fingerprint = normalize_fingerprint(fingerprint);
+
+        self.update(|trusted_peers| trusted_peers.retain(|peer| peer.fingerprint != fingerprint))
+    }
+
+    // Every plugin instance on this machine shares the file, so the change goes onto what is saved now rather than
+    // what this one loaded. A list that can no longer be read is not overwritten.
+    fn update(&self, change: impl FnOnce(&mut Vec<TrustedPeer>)) -> io::Result<()> {
+        let mut trusted_peers = self.trusted_peers.lock().unwrap();
+
+        let mut latest = read_trusted_peers(&self.dir)?;
+        change(&mut latest);
+
+        // Written next to it and renamed over it, so nobody reads a half written list
+        let path = self.dir.join(TRUSTED_PEERS_FILE);
+        let temp_path = path.with_extension(format!("json.{}", std::process::id()));
+        fs::write(&temp_path, serde_json::to_string_pretty(&latest)?)?;
+        fs::rename(&temp_path, &path)?;
+
+        *trusted_peers = latest;
+        Ok(())
+    }
+}
+
+// A missing list just means nobody is trusted yet
+fn read_trusted_peers(dir: &Path) -> io::Result<Vec<TrustedPeer>> {
+    match fs::read_to_string(dir.join(TRUSTED_PEERS_FILE)) {
+        Ok(json) => Ok(serde_json::from_str(&json)?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err),
+    }
+}
+
+// The PEM holds the private key, keep it readable by this user only
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(path)?.write_all(contents)
+}
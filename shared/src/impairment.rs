@@ -0,0 +1,152 @@
+use std::{str::FromStr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+// Environment variable read by debug builds of the plugins, e.g. "loss=5,delay=40,jitter=10,seed=7"
+pub const IMPAIRMENT_ENV_VAR: &str = "LIVE_COLLAB_IMPAIRMENT";
+
+// Packets are tail dropped once the simulated link has this much data queued
+const MAX_QUEUE_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ImpairmentConfig {
+    pub loss_percent: f32,
+    // Chance that a lost packet starts a burst, and how many packets the burst drops
+    pub burst_percent: f32,
+    pub burst_length: u32,
+    pub delay_ms: u32,
+    // Uniformly distributed extra delay on top of delay_ms
+    pub jitter_ms: u32,
+    pub reorder_percent: f32,
+    pub reorder_delay_ms: u32,
+    pub duplicate_percent: f32,
+    // 0 means unlimited
+    pub bandwidth_kbps: u32,
+    pub seed: u64,
+}
+
+impl ImpairmentConfig {
+    pub fn from_env() -> Option<Self> {
+        std::env::var(IMPAIRMENT_ENV_VAR).ok()?.parse().ok()
+    }
+}
+
+impl FromStr for ImpairmentConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = ImpairmentConfig::default();
+
+        for entry in s.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (key, value) = entry.split_once('=').ok_or_else(|| format!("expected key=value, got \"{entry}\""))?;
+            let invalid = || format!("invalid value for {key}: \"{value}\"");
+
+            match key {
+                "loss" => config.loss_percent = value.parse().map_err(|_| invalid())?,
+                "burst" => config.burst_percent = value.parse().map_err(|_| invalid())?,
+                "burst_length" => config.burst_length = value.parse().map_err(|_| invalid())?,
+                "delay" => config.delay_ms = value.parse().map_err(|_| invalid())?,
+                "jitter" => config.jitter_ms = value.parse().map_err(|_| invalid())?,
+                "reorder" => config.reorder_percent = value.parse().map_err(|_| invalid())?,
+                "reorder_delay" => config.reorder_delay_ms = value.parse().map_err(|_| invalid())?,
+                "dup" => config.duplicate_percent = value.parse().map_err(|_| invalid())?,
+                "kbps" => config.bandwidth_kbps = value.parse().map_err(|_| invalid())?,
+                "seed" => config.seed = value.parse().map_err(|_| invalid())?,
+                _ => return Err(format!("unknown impairment \"{key}\"")),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+// SplitMix64, so a seed gives the same packet fate on every platform
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 100)
+    fn percent(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32 * 100.0
+    }
+
+    fn below(&mut self, max: u32) -> u32 {
+        if max == 0 { 0 } else { (self.next_u64() % (max as u64 + 1)) as u32 }
+    }
+}
+
+pub struct Impairment {
+    config: ImpairmentConfig,
+    rng: Rng,
+    burst_remaining: u32,
+    link_free_at: Duration,
+}
+
+impl Impairment {
+    pub fn new(config: ImpairmentConfig) -> Self {
+        Self {
+            rng: Rng(config.seed),
+            config,
+            burst_remaining: 0,
+            link_free_at: Duration::ZERO,
+        }
+    }
+
+    pub fn config(&self) -> &ImpairmentConfig {
+        &self.config
+    }
+
+    // Decides the fate of a packet of `len` bytes handed to the link at `now` (time since the link was created).
+    // Returns one delivery delay per copy that arrives, so an empty result means the packet was lost.
+    pub fn schedule(&mut self, len: usize, now: Duration) -> Vec<Duration> {
+        if self.burst_remaining > 0 {
+            self.burst_remaining -= 1;
+            return Vec::new();
+        }
+
+        if self.rng.percent() < self.config.loss_percent {
+            if self.rng.percent() < self.config.burst_percent {
+                self.burst_remaining = self.config.burst_length.saturating_sub(1);
+            }
+
+            return Vec::new();
+        }
+
+        let mut queue_delay = Duration::ZERO;
+
+        if self.config.bandwidth_kbps > 0 {
+            let link_free_at = self.link_free_at.max(now);
+
+            if link_free_at - now > MAX_QUEUE_DELAY {
+                return Vec::new();
+            }
+
+            let transmit_time = Duration::from_secs_f64((len * 8) as f64 / (self.config.bandwidth_kbps as f64 * 1000.0));
+            self.link_free_at = link_free_at + transmit_time;
+            queue_delay = self.link_free_at - now;
+        }
+
+        let copies = if self.rng.percent() < self.config.duplicate_percent { 2 } else { 1 };
+
+        (0..copies)
+            .map(|_| {
+                let mut delay = queue_delay
+                    + Duration::from_millis(self.config.delay_ms as u64)
+                    + Duration::from_millis(self.rng.below(self.config.jitter_ms) as u64);
+
+                if self.rng.percent() < self.config.reorder_percent {
+                    delay += Duration::from_millis(self.config.reorder_delay_ms as u64);
+                }
+
+                delay
+            })
+            .collect()
+    }
+}
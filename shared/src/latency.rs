@@ -0,0 +1,170 @@
+use std::{
+    collections::VecDeque,
+    sync::{atomic::{AtomicU32, AtomicU64, Ordering}, LazyLock, Mutex},
+    time::Instant,
+};
+
+use serde::{Deserialize, Serialize};
+
+pub const PING_INTERVAL_MS: u64 = 500;
+
+// Offsets are taken from the lowest RTT sample in this window, like NTP's clock filter
+const OFFSET_WINDOW: usize = 8;
+
+static EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+// Nanoseconds on a clock that never jumps, only meaningful within this process
+pub fn monotonic_ns() -> u64 {
+    EPOCH.elapsed().as_nanos() as u64
+}
+
+pub fn samples_to_ns(samples: u64, sample_rate: f32) -> u64 {
+    if sample_rate <= 0.0 {
+        return 0;
+    }
+
+    (samples as f64 * 1_000_000_000.0 / sample_rate as f64) as u64
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Ping {
+    pub seq: u32,
+    pub sent_ns: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Pong {
+    pub seq: u32,
+    // t1 on the pinging side's clock
+    pub ping_sent_ns: u64,
+    // t2 and t3 on the replying side's clock
+    pub ping_received_ns: u64,
+    pub sent_ns: u64,
+    // Buffering the replying side adds to its audio path (jitter buffer, host buffers)
+    pub delay_ns: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LatencyEstimate {
+    pub rtt_ms: f32,
+    pub smoothed_rtt_ms: f32,
+    pub jitter_ms: f32,
+    // Remote clock minus local clock
+    pub clock_offset_ms: f32,
+    pub one_way_ms: f32,
+    pub local_delay_ms: f32,
+    pub remote_delay_ms: f32,
+    // Local buffering + network + remote buffering
+    pub mouth_to_ear_ms: f32,
+    pub samples: u64,
+}
+
+#[derive(Default)]
+struct LatencyState {
+    estimate: LatencyEstimate,
+    last_seq: Option<u32>,
+    // (rtt, offset) in nanoseconds
+    recent: VecDeque<(i64, i64)>,
+}
+
+#[derive(Default)]
+pub struct LatencyTracker {
+    local_delay_ns: AtomicU64,
+    // Copy of the smoothed RTT for the audio thread, which must not wait on the lock
+    smoothed_rtt_ns: AtomicU64,
+    next_seq: AtomicU32,
+    state: Mutex<LatencyState>,
+}
+
+impl LatencyTracker {
+    // Called from the audio thread, so this only touches an atomic
+    pub fn set_local_delay(&self, samples: u64, sample_rate: f32) {
+        self.local_delay_ns.store(samples_to_ns(samples, sample_rate), Ordering::Relaxed);
+    }
+
+    pub fn local_delay_ns(&self) -> u64 {
+        self.local_delay_ns.load(Ordering::Relaxed)
+    }
+
+    pub fn smoothed_rtt_ns(&self) -> u64 {
+        self.smoothed_rtt_ns.load(Ordering::Relaxed)
+    }
+
+    pub fn next_ping(&self) -> Ping {
+        Ping {
+            seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+            sent_ns: monotonic_ns(),
+        }
+    }
+
+    pub fn answer_ping(&self, ping: Ping, received_ns: u64) -> Pong {
+        Pong {
+            seq: ping.seq,
+            ping_sent_ns: ping.sent_ns,
+            ping_received_ns: received_ns,
+            sent_ns: monotonic_ns(),
+            delay_ns: self.local_delay_ns(),
+        }
+    }
+
+    pub fn on_pong(&self, pong: Pong, received_ns: u64) {
+        let mut state = self.state.lock().unwrap();
+
+        // Stale or duplicated replies would drag the estimate backwards
+        if state.last_seq.is_some_and(|last_seq| pong.seq <= last_seq) {
+            return;
+        }
+        state.last_seq = Some(pong.seq);
+
+        let t1 = pong.ping_sent_ns as i64;
+        let t2 = pong.ping_received_ns as i64;
+        let t3 = pong.sent_ns as i64;
+        let t4 = received_ns as i64;
+
+        let rtt = ((t4 - t1) - (t3 - t2)).max(0);
+        let offset = ((t2 - t1) + (t3 - t4)) / 2;
+
+        state.recent.push_back((rtt, offset));
+        if state.recent.len() > OFFSET_WINDOW {
+            state.recent.pop_front();
+        }
+
+        let best_offset = state.recent.iter().min_by_key(|(rtt, _)| *rtt).map(|(_, offset)| *offset).unwrap_or(offset);
+
+        let rtt_ms = rtt as f32 / 1_000_000.0;
+        let estimate = &mut state.estimate;
+
+        if estimate.samples == 0 {
+            estimate.smoothed_rtt_ms = rtt_ms;
+            estimate.jitter_ms = 0.0;
+        } else {
+            // RFC 3550 style jitter on RTT changes, RFC 6298 style smoothing on the RTT itself
+            estimate.jitter_ms += ((rtt_ms - estimate.rtt_ms).abs() - estimate.jitter_ms) / 16.0;
+            estimate.smoothed_rtt_ms += (rtt_ms - estimate.smoothed_rtt_ms) / 8.0;
+        }
+
+        estimate.rtt_ms = rtt_ms;
+        estimate.clock_offset_ms = best_offset as f32 / 1_000_000.0;
+        estimate.remote_delay_ms = pong.delay_ns as f32 / 1_000_000.0;
+        estimate.samples += 1;
+
+        self.smoothed_rtt_ns.store((estimate.smoothed_rtt_ms as f64 * 1_000_000.0) as u64, Ordering::Relaxed);
+    }
+
+    // Forgets everything measured on the previous connection, the local delay is still ours and stays
+    pub fn reset(&self) {
+        *self.state.lock().unwrap() = LatencyState::default();
+        self.smoothed_rtt_ns.store(0, Ordering::Relaxed);
+        self.next_seq.store(0, Ordering::Relaxed);
+    }
+
+    pub fn estimate(&self) -> LatencyEstimate {
+        let mut estimate = self.state.lock().unwrap().estimate;
+
+        estimate.one_way_ms = estimate.smoothed_rtt_ms / 2.0;
+        estimate.local_delay_ms = self.local_delay_ns() as f32 / 1_000_000.0;
+        estimate.mouth_to_ear_ms = estimate.local_delay_ms + estimate.one_way_ms + estimate.remote_delay_ms;
+
+        estimate
+    }
+}
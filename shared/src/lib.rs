@@ -1,11 +1,97 @@
-use std::{sync::Arc, time::Duration};
+mod audio;
+mod chat;
+mod control;
+mod crypto;
+mod flac;
+mod handshake;
+mod identity;
+mod impairment;
+mod latency;
+mod lifecycle;
+mod lossless;
+mod meter;
+mod midi;
+mod monitor;
+mod recording;
+mod reorder;
+mod runtime;
+mod session;
+mod stats;
+mod token;
+mod transport;
+
+pub use audio::*;
+pub use chat::*;
+pub use control::*;
+pub use crypto::*;
+pub use flac::*;
+pub use handshake::*;
+pub use identity::*;
+pub use impairment::*;
+pub use latency::*;
+pub use lifecycle::*;
+pub use lossless::*;
+pub use meter::*;
+pub use midi::*;
+pub use monitor::*;
+pub use recording::*;
+pub use reorder::*;
+pub use runtime::*;
+pub use session::*;
+pub use stats::*;
+pub use token::*;
+pub use transport::*;
+
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc}, time::{Duration, Instant}};
 
 use bytes::Bytes;
 use tokio::{sync::Mutex, task};
-use webrtc::{api::{setting_engine::SettingEngine, APIBuilder, API}, data_channel::{data_channel_init::RTCDataChannelInit, RTCDataChannel}, ice_transport::{ice_candidate::RTCIceCandidate, ice_server::RTCIceServer}, peer_connection::{configuration::RTCConfiguration, sdp::session_description::RTCSessionDescription, RTCPeerConnection}};
+use webrtc::{api::{setting_engine::SettingEngine, APIBuilder, API}, data_channel::{data_channel_init::RTCDataChannelInit, data_channel_state::RTCDataChannelState, RTCDataChannel}, ice_transport::{ice_candidate::RTCIceCandidate, ice_server::RTCIceServer}, peer_connection::{certificate::RTCCertificate, configuration::RTCConfiguration, sdp::session_description::RTCSessionDescription, RTCPeerConnection}};
 
 use serde::{Serialize, Deserialize};
 
+// How long `close` waits for the peer to acknowledge the goodbye
+const BYE_FLUSH_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IceConfig {
+    pub stun_urls: Vec<String>,
+    // Loopback candidates are only useful when both peers run on the same machine (tests, local debugging)
+    pub include_loopback: bool,
+    // Persistent DTLS certificate (see `Identity`), a fresh one is generated per connection without it
+    #[serde(skip)]
+    pub certificate: Option<RTCCertificate>,
+    // Fingerprints of trusted peers (see `Identity`), a peer presenting only these is confirmed without asking
+    #[serde(skip)]
+    pub trusted_fingerprints: Vec<String>,
+    // Signs the session tokens and seals audio end to end, the peer has to use the same one
+    #[serde(skip)]
+    pub passphrase: Option<String>,
+}
+
+impl Default for IceConfig {
+    fn default() -> Self {
+        Self {
+            stun_urls: vec![
+                "stun:stun.l.google.com:19302".to_owned(),
+                "stun:stun.l.google.com:5349".to_owned(),
+                "stun:stun1.l.google.com:3478".to_owned(),
+                "stun:stun1.l.google.com:5349".to_owned(),
+                "stun:stun2.l.google.com:19302".to_owned(),
+                "stun:stun2.l.google.com:5349".to_owned(),
+                "stun:stun3.l.google.com:3478".to_owned(),
+                "stun:stun3.l.google.com:5349".to_owned(),
+                "stun:stun4.l.google.com:19302".to_owned(),
+                "stun:stun4.l.google.com:5349".to_owned(),
+            ],
+            include_loopback: false,
+            certificate: None,
+            trusted_fingerprints: Vec::new(),
+            passphrase: None,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct ConnectInfo {
     pub sdp: RTCSessionDescription,
@@ -18,11 +104,29 @@ pub struct WebRTCConnection {
     pub peer: Arc<RTCPeerConnection>,
     pub channel: Arc<RTCDataChannel>,
     pub tcp_channel: Arc<RTCDataChannel>,
+    // Session token to hand to the peer, see `SessionToken`
     pub connect_info: String,
+    pub session: Arc<SessionToken>,
+    // Made the offer rather than answered it, the two sides prove the passphrase under different labels
+    pub offerer: bool,
+    // DTLS fingerprints from the peer's SDP, known once its offer or answer was accepted
+    pub remote_fingerprints: Arc<std::sync::Mutex<Vec<String>>>,
+    // Audio only flows once the user trusts the peer, or it is already on the trusted list
+    pub peer_confirmed: Arc<AtomicBool>,
+    pub trusted_fingerprints: Arc<Vec<String>>,
+    pub handshake: Arc<Handshake>,
+    pub encryption: Arc<Encryption>,
+    // Only set in tests and debug builds, see `with_impairment`
+    pub impairment: Option<Arc<std::sync::Mutex<(Impairment, Instant)>>>,
 }
 
 async fn set_peer_answer(connection: &WebRTCConnection, peer_connect_info: String) -> Result<(), Box<dyn std::error::Error>> {
-    let peer_connect_info: ConnectInfo = serde_json::from_str(&String::from_utf8(base64::decode(peer_connect_info)?)?)?;
+    // Nothing from the answer is applied until it proved to be the one and only answer to our offer
+    let peer_connect_info = connection.session.open_answer(&peer_connect_info, unix_time())?;
+    let peer_connect_info: ConnectInfo = serde_json::from_str(&peer_connect_info)?;
+
+    connection.session.accept_answer(&peer_connect_info.sdp.sdp)?;
+    connection.set_remote_fingerprints(sdp_fingerprints(&peer_connect_info.sdp.sdp));
 
     connection.peer.set_remote_description(peer_connect_info.sdp).await?;
 
@@ -34,11 +138,57 @@ async fn set_peer_answer(connection: &WebRTCConnection, peer_connect_info: Strin
 }
 
 impl WebRTCConnection {
-    async fn send_blocking_internal(&self, buffer: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-        self.channel.send(&Bytes::copy_from_slice(buffer)).await?;
+    // Known once the peer's offer or answer is accepted. A peer whose certificates are all trusted needs no confirmation.
+    fn set_remote_fingerprints(&self, fingerprints: Vec<String>) {
+        if !fingerprints.is_empty() && fingerprints.iter().all(|fingerprint| self.trusted_fingerprints.contains(fingerprint)) {
+            self.peer_confirmed.store(true, Ordering::Relaxed);
+        }
+
+        *self.remote_fingerprints.lock().unwrap() = fingerprints;
+    }
+
+    // Routes outgoing audio through a simulated bad network
+    pub fn with_impairment(mut self, config: ImpairmentConfig) -> Self {
+        self.impairment = Some(Arc::new(std::sync::Mutex::new((Impairment::new(config), Instant::now()))));
+        self
+    }
+
+    pub async fn send_audio(&self, data: Bytes) -> Result<(), Box<dyn std::error::Error>> {
+        // Dropped until the peer proved it has the same passphrase
+        let Some(data) = self.encryption.seal(data) else { return Ok(()) };
+
+        let Some(impairment) = &self.impairment else {
+            self.channel.send(&data).await?;
+            return Ok(());
+        };
+
+        let delays = {
+            let mut impairment = impairment.lock().unwrap();
+            let now = impairment.1.elapsed();
+            impairment.0.schedule(data.len(), now)
+        };
+
+        for delay in delays {
+            if delay.is_zero() {
+                self.channel.send(&data).await?;
+                continue;
+            }
+
+            let channel = self.channel.clone();
+            let data = data.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                let _ = channel.send(&data).await;
+            });
+        }
+
         Ok(())
     }
 
+    async fn send_blocking_internal(&self, buffer: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.send_audio(Bytes::copy_from_slice(buffer)).await
+    }
+
     pub fn send_blocking(&self, runtime: &tokio::runtime::Runtime, buffer: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
         task::block_in_place(|| {
             runtime.block_on(async {
@@ -58,54 +208,216 @@ impl WebRTCConnection {
 
         Ok(())
     }
-}
 
-pub fn create_offerer(runtime: &tokio::runtime::Runtime) -> Result<WebRTCConnection, Box<dyn std::error::Error>> {
-    task::block_in_place(|| {
-        runtime.block_on(async {
-            // Create API for the WebRTC connection
-            let mut settings = SettingEngine::default();
-            settings.set_ice_timeouts(Some(Duration::from_secs(300)), Default::default(), Default::default());
-
-            let api = Arc::new(APIBuilder::new().with_setting_engine(settings).build());
-
-            let config = RTCConfiguration {
-                ice_servers: vec![RTCIceServer {
-                    urls: vec![
-                        "stun:stun.l.google.com:19302".to_owned(),
-                        "stun:stun.l.google.com:5349".to_owned(),
-                        "stun:stun1.l.google.com:3478".to_owned(),
-                        "stun:stun1.l.google.com:5349".to_owned(),
-                        "stun:stun2.l.google.com:19302".to_owned(),
-                        "stun:stun2.l.google.com:5349".to_owned(),
-                        "stun:stun3.l.google.com:3478".to_owned(),
-                        "stun:stun3.l.google.com:5349".to_owned(),
-                        "stun:stun4.l.google.com:19302".to_owned(),
-                        "stun:stun4.l.google.com:5349".to_owned(),
-                    ],
-                    ..Default::default()
-                }],
-                ..Default::default()
+    pub async fn send_control(&self, message: ControlMessage) {
+        let _ = self.tcp_channel.send(&message.to_bytes()).await;
+    }
+
+    pub fn lifecycle(&self) -> Lifecycle {
+        self.peer.connection_state().into()
+    }
+
+    // Tells the peer why, then closes both data channels and the peer, so the other side does not wait for a timeout
+    pub async fn close(&self, reason: String) {
+        self.send_control(ControlMessage::Bye { reason }).await;
+
+        // Closing drops whatever SCTP still holds, so give the goodbye a moment to be acknowledged
+        let flush_started = Instant::now();
+        while self.tcp_channel.buffered_amount().await > 0 && flush_started.elapsed() < BYE_FLUSH_TIMEOUT {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let _ = self.channel.close().await;
+        let _ = self.tcp_channel.close().await;
+        let _ = self.peer.close().await;
+    }
+
+    // Blocking `close` for plugin teardown, gives up after `timeout` so an unreachable peer cannot hang the host
+    pub fn shutdown(&self, runtime: &tokio::runtime::Runtime, reason: String, timeout: Duration) {
+        task::block_in_place(|| {
+            runtime.block_on(async {
+                let _ = tokio::time::timeout(timeout, self.close(reason)).await;
+            })
+        });
+    }
+
+    // Sends whatever `next_message` returns every `interval` while the "tcp" channel is open, until it closes
+    pub fn start_periodic<F>(&self, runtime: &tokio::runtime::Runtime, interval: Duration, next_message: F)
+    where
+        F: Fn() -> Option<ControlMessage> + Send + Sync + 'static,
+    {
+        let tcp_channel = Arc::downgrade(&self.tcp_channel);
+        runtime.spawn(async move {
+            loop {
+                let Some(channel) = tcp_channel.upgrade() else { break };
+
+                match channel.ready_state() {
+                    RTCDataChannelState::Open => {
+                        if let Some(message) = next_message() {
+                            let _ = channel.send(&message.to_bytes()).await;
+                        }
+                    }
+                    RTCDataChannelState::Closing | RTCDataChannelState::Closed => break,
+                    _ => {}
+                }
+
+                drop(channel);
+                tokio::time::sleep(interval).await;
+            }
+        });
+    }
+
+    // Takes over the "tcp" channel: latency pings are answered here and our own are sent every PING_INTERVAL_MS,
+    // so both sides get a latency estimate. `hello` is sent until the peer's capabilities are known, and the outcome
+    // lands in `handshake`. With a passphrase set, the same goes for the passphrase proof and `encryption`, and a
+    // mismatch closes the connection. Every other control message is dispatched to `on_message`, which can reply.
+    // Frames we cannot decode are dropped, a frame from another protocol version refuses the session.
+    pub fn start_control<F>(&self, runtime: &tokio::runtime::Runtime, tracker: Arc<LatencyTracker>, mut hello: Hello, on_message: F)
+    where
+        F: Fn(ControlMessage) -> Option<ControlMessage> + Send + Sync + 'static,
+    {
+        hello.encrypted = self.encryption.enabled();
+        self.handshake.set_local(hello.clone());
+        // A new connection measures from scratch, nothing of the last one's RTT carries over
+        tracker.reset();
+
+        let tcp_channel = Arc::downgrade(&self.tcp_channel);
+        let peer = Arc::downgrade(&self.peer);
+        let tracker_clone = tracker.clone();
+        let handshake = self.handshake.clone();
+        let encryption = self.encryption.clone();
+        self.tcp_channel.on_message(Box::new(move |msg| {
+            let received_ns = monotonic_ns();
+            let failed_before = matches!(encryption.state(), EncryptionState::Failed(_));
+            let mut peer_salt = None;
+
+            let reply = match ControlMessage::parse(&msg.data) {
+                Ok(ControlMessage::Ping(ping)) => Some(ControlMessage::Pong(tracker_clone.answer_ping(ping, received_ns))),
+                Ok(ControlMessage::Pong(pong)) => {
+                    tracker_clone.on_pong(pong, received_ns);
+                    None
+                }
+                Ok(ControlMessage::Hello(hello)) => handshake.on_hello(hello).map(ControlMessage::HelloAck),
+                Ok(ControlMessage::HelloAck(ack)) => {
+                    handshake.on_hello_ack(ack);
+                    None
+                }
+                Ok(ControlMessage::AuthHello { salt, nonce }) => {
+                    peer_salt = Some((salt, nonce));
+                    None
+                }
+                Ok(ControlMessage::AuthProof { mac }) => {
+                    encryption.on_auth_proof(mac);
+                    None
+                }
+                Err(err @ ControlError::UnsupportedVersion(_)) => {
+                    handshake.refuse(err.to_string());
+                    None
+                }
+                Ok(ControlMessage::Unknown) | Err(_) => None,
+                Ok(message) => on_message(message),
             };
 
-            // Create a new RTCPeerConnection
-            let peer_connection = Arc::new(api.new_peer_connection(config).await?);
+            let tcp_channel = tcp_channel.clone();
+            let peer = peer.clone();
+            let encryption = encryption.clone();
+            Box::pin(async move {
+                // Key derivation is slow on purpose, keep it off the runtime's worker threads
+                let reply = match peer_salt {
+                    Some((salt, nonce)) => {
+                        let encryption = encryption.clone();
+                        task::spawn_blocking(move || encryption.on_auth_hello(salt, nonce)).await.ok().flatten()
+                    }
+                    None => reply,
+                };
+
+                let Some(tcp_channel) = tcp_channel.upgrade() else { return };
 
-            let data_channel = peer_connection.create_data_channel("audio", Some(RTCDataChannelInit { ordered: Some(false), negotiated: Some(0), max_retransmits: None, protocol: None, ..Default::default() })).await?;
-            let tcp_data_channel = peer_connection.create_data_channel("tcp", Some(RTCDataChannelInit { negotiated: Some(0), ..Default::default() })).await?;
+                if let Some(reply) = reply {
+                    let _ = tcp_channel.send(&reply.to_bytes()).await;
+                }
 
-            let gathered_candidates: Arc<Mutex<Vec<RTCIceCandidate>>> = Arc::new(Mutex::new(Vec::new()));
+                // Tell the peer why before hanging up, it shows the same failure on its side
+                if let (false, EncryptionState::Failed(reason)) = (failed_before, encryption.state()) {
+                    let _ = tcp_channel.send(&ControlMessage::Bye { reason }.to_bytes()).await;
+                    tokio::time::sleep(Duration::from_secs(1)).await;
 
-            let gc2 = gathered_candidates.clone();
-            peer_connection.on_ice_candidate(Box::new(move |candidate| {
-                let gc3 = gc2.clone();
-                Box::pin(async move {
-                    if let Some(candidate) = candidate {
-                        let mut gc = gc3.lock().await;
-                        gc.push(candidate);
+                    if let Some(peer) = peer.upgrade() {
+                        let _ = peer.close().await;
                     }
-                })
-            }));
+                }
+            })
+        }));
+
+        // The peer may not be listening yet, so keep saying hello until it answers
+        let handshake = self.handshake.clone();
+        self.start_periodic(runtime, Duration::from_millis(HELLO_INTERVAL_MS), move || {
+            (handshake.state() == HandshakeState::Pending).then(|| ControlMessage::Hello(hello.clone()))
+        });
+
+        let encryption = self.encryption.clone();
+        self.start_periodic(runtime, Duration::from_millis(HELLO_INTERVAL_MS), move || {
+            (encryption.state() == EncryptionState::Pending).then(|| encryption.auth_hello()).flatten()
+        });
+
+        self.start_periodic(runtime, Duration::from_millis(PING_INTERVAL_MS), move || Some(ControlMessage::Ping(tracker.next_ping())));
+    }
+}
+
+// Creates the API, peer connection and both negotiated data channels, collecting local ICE candidates as they are gathered
+async fn new_peer_connection(ice_config: &IceConfig) -> Result<(Arc<API>, Arc<RTCPeerConnection>, Arc<RTCDataChannel>, Arc<RTCDataChannel>, Arc<Mutex<Vec<RTCIceCandidate>>>), Box<dyn std::error::Error>> {
+    // Create API for the WebRTC connection
+    let mut settings = SettingEngine::default();
+    settings.set_ice_timeouts(Some(Duration::from_secs(300)), Default::default(), Default::default());
+    settings.set_include_loopback_candidate(ice_config.include_loopback);
+
+    let api = Arc::new(APIBuilder::new().with_setting_engine(settings).build());
+
+    let ice_servers = if ice_config.stun_urls.is_empty() {
+        Vec::new()
+    } else {
+        vec![RTCIceServer {
+            urls: ice_config.stun_urls.clone(),
+            ..Default::default()
+        }]
+    };
+
+    let config = RTCConfiguration {
+        ice_servers,
+        certificates: ice_config.certificate.iter().cloned().collect(),
+        ..Default::default()
+    };
+
+    // Create a new RTCPeerConnection
+    let peer_connection = Arc::new(api.new_peer_connection(config).await?);
+
+    let data_channel = peer_connection.create_data_channel("audio", Some(RTCDataChannelInit { ordered: Some(false), negotiated: Some(0), max_retransmits: None, protocol: None, ..Default::default() })).await?;
+    let tcp_data_channel = peer_connection.create_data_channel("tcp", Some(RTCDataChannelInit { negotiated: Some(1), ..Default::default() })).await?;
+
+    let gathered_candidates: Arc<Mutex<Vec<RTCIceCandidate>>> = Arc::new(Mutex::new(Vec::new()));
+
+    let gc2 = gathered_candidates.clone();
+    peer_connection.on_ice_candidate(Box::new(move |candidate| {
+        let gc3 = gc2.clone();
+        Box::pin(async move {
+            if let Some(candidate) = candidate {
+                let mut gc = gc3.lock().await;
+                gc.push(candidate);
+            }
+        })
+    }));
+
+    Ok((api, peer_connection, data_channel, tcp_data_channel, gathered_candidates))
+}
+
+pub fn create_offerer(runtime: &tokio::runtime::Runtime) -> Result<WebRTCConnection, Box<dyn std::error::Error>> {
+    create_offerer_with_config(runtime, &IceConfig::default())
+}
+
+pub fn create_offerer_with_config(runtime: &tokio::runtime::Runtime, ice_config: &IceConfig) -> Result<WebRTCConnection, Box<dyn std::error::Error>> {
+    task::block_in_place(|| {
+        runtime.block_on(async {
+            let (api, peer_connection, data_channel, tcp_data_channel, gathered_candidates) = new_peer_connection(ice_config).await?;
 
             let offer = peer_connection.create_offer(None).await?;
 
@@ -124,13 +436,23 @@ pub fn create_offerer(runtime: &tokio::runtime::Runtime) -> Result<WebRTCConnect
                     candidates: candidates.to_vec(),
                 };
 
+            let session = SessionToken::generate(unix_time(), ice_config.passphrase.as_deref());
+
             Ok (
                 WebRTCConnection {
                     api,
                     peer: peer_connection,
                     channel: data_channel,
                     tcp_channel: tcp_data_channel,
-                    connect_info: base64::encode(serde_json::to_string(&connect_info)?),
+                    connect_info: session.token(serde_json::to_string(&connect_info)?, true),
+                    session: Arc::new(session),
+                    offerer: true,
+                    remote_fingerprints: Default::default(),
+                    peer_confirmed: Default::default(),
+                    trusted_fingerprints: Arc::new(ice_config.trusted_fingerprints.clone()),
+                    handshake: Default::default(),
+                    encryption: Arc::new(Encryption::new(ice_config.passphrase.clone(), true)),
+                    impairment: None,
                 }
             )
         })
@@ -138,52 +460,19 @@ pub fn create_offerer(runtime: &tokio::runtime::Runtime) -> Result<WebRTCConnect
 }
 
 pub fn create_answerer(runtime: &tokio::runtime::Runtime, peer_connect_info: String) -> Result<WebRTCConnection, Box<dyn std::error::Error>> {
+    create_answerer_with_config(runtime, &IceConfig::default(), peer_connect_info)
+}
+
+pub fn create_answerer_with_config(runtime: &tokio::runtime::Runtime, ice_config: &IceConfig, peer_connect_info: String) -> Result<WebRTCConnection, Box<dyn std::error::Error>> {
     task::block_in_place(|| {
         runtime.block_on(async {
-            // Create API for the WebRTC connection
-            let mut settings = SettingEngine::default();
-            settings.set_ice_timeouts(Some(Duration::from_secs(300)), Default::default(), Default::default());
-
-            let api = Arc::new(APIBuilder::new().with_setting_engine(settings).build());
-
-            let config = RTCConfiguration {
-                ice_servers: vec![RTCIceServer {
-                    urls: vec![
-                        "stun:stun.l.google.com:19302".to_owned(),
-                        "stun:stun.l.google.com:5349".to_owned(),
-                        "stun:stun1.l.google.com:3478".to_owned(),
-                        "stun:stun1.l.google.com:5349".to_owned(),
-                        "stun:stun2.l.google.com:19302".to_owned(),
-                        "stun:stun2.l.google.com:5349".to_owned(),
-                        "stun:stun3.l.google.com:3478".to_owned(),
-                        "stun:stun3.l.google.com:5349".to_owned(),
-                        "stun:stun4.l.google.com:19302".to_owned(),
-                        "stun:stun4.l.google.com:5349".to_owned(),
-                    ],
-                    ..Default::default()
-                }],
-                ..Default::default()
-            };
-            // Create a new RTCPeerConnection
-            let peer_connection = Arc::new(api.new_peer_connection(config).await?);
+            let (api, peer_connection, data_channel, tcp_data_channel, gathered_candidates) = new_peer_connection(ice_config).await?;
 
-            let data_channel = peer_connection.create_data_channel("audio", Some(RTCDataChannelInit { ordered: Some(false), negotiated: Some(0), max_retransmits: None, protocol: None, ..Default::default() })).await?;
-            let tcp_data_channel = peer_connection.create_data_channel("tcp", Some(RTCDataChannelInit { negotiated: Some(0), ..Default::default() })).await?;
-
-            let gathered_candidates: Arc<Mutex<Vec<RTCIceCandidate>>> = Arc::new(Mutex::new(Vec::new()));
-
-            let gc2 = gathered_candidates.clone();
-            peer_connection.on_ice_candidate(Box::new(move |candidate| {
-                let gc3 = gc2.clone();
-                Box::pin(async move {
-                    if let Some(candidate) = candidate {
-                        let mut gc = gc3.lock().await;
-                        gc.push(candidate);
-                    }
-                })
-            }));
-
-            let peer_connect_info: ConnectInfo = serde_json::from_str(&String::from_utf8(base64::decode(peer_connect_info)?)?)?;
+            // The answer carries the offer's session nonce back and, with a passphrase, is signed like the offer was.
+            // Without one, whoever made the offer is only known by its certificate fingerprint, see `Identity`.
+            let (session, peer_connect_info) = SessionToken::open_offer(&peer_connect_info, unix_time(), ice_config.passphrase.as_deref())?;
+            let peer_connect_info: ConnectInfo = serde_json::from_str(&peer_connect_info)?;
+            let remote_fingerprints = sdp_fingerprints(&peer_connect_info.sdp.sdp);
 
             peer_connection.set_remote_description(peer_connect_info.sdp).await?;
 
@@ -208,15 +497,25 @@ pub fn create_answerer(runtime: &tokio::runtime::Runtime, peer_connect_info: Str
                     candidates: candidates.to_vec(),
                 };
 
-            Ok (
-                WebRTCConnection {
-                    api,
-                    peer: peer_connection,
-                    channel: data_channel,
-                    tcp_channel: tcp_data_channel,
-                    connect_info: base64::encode(serde_json::to_string(&connect_info)?),
-                }
-            )
+            let connection = WebRTCConnection {
+                api,
+                peer: peer_connection,
+                channel: data_channel,
+                tcp_channel: tcp_data_channel,
+                connect_info: session.token(serde_json::to_string(&connect_info)?, false),
+                session: Arc::new(session),
+                offerer: false,
+                remote_fingerprints: Default::default(),
+                peer_confirmed: Default::default(),
+                trusted_fingerprints: Arc::new(ice_config.trusted_fingerprints.clone()),
+                handshake: Default::default(),
+                encryption: Arc::new(Encryption::new(ice_config.passphrase.clone(), false)),
+                impairment: None,
+            };
+
+            connection.set_remote_fingerprints(remote_fingerprints);
+
+            Ok(connection)
         })
     })
 }
\ No newline at end of file
@@ -0,0 +1,80 @@
+use std::sync::Arc;
+
+use arc_swap::ArcSwapOption;
+use tokio::runtime::Runtime;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+
+use crate::{WebRTCConnection, SHUTDOWN_TIMEOUT};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Lifecycle {
+    // Session token made, nothing heard from the peer yet
+    Created,
+    Connecting,
+    Connected,
+    // Closed, failed or never created
+    Closed,
+}
+
+impl From<RTCPeerConnectionState> for Lifecycle {
+    fn from(state: RTCPeerConnectionState) -> Self {
+        match state {
+            RTCPeerConnectionState::Unspecified | RTCPeerConnectionState::New => Lifecycle::Created,
+            // Disconnected is ICE losing the peer for a moment, it either comes back or fails
+            RTCPeerConnectionState::Connecting | RTCPeerConnectionState::Disconnected => Lifecycle::Connecting,
+            RTCPeerConnectionState::Connected => Lifecycle::Connected,
+            RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed => Lifecycle::Closed,
+        }
+    }
+}
+
+impl std::fmt::Display for Lifecycle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Lifecycle::Created => write!(f, "waiting for peer"),
+            Lifecycle::Connecting => write!(f, "connecting"),
+            Lifecycle::Connected => write!(f, "connected"),
+            Lifecycle::Closed => write!(f, "closed"),
+        }
+    }
+}
+
+// The plugin's one connection. It belongs to the plugin rather than the editor, so closing and reopening
+// the editor finds it where it was. The audio thread reads it every block, so it is swapped rather than locked.
+#[derive(Default)]
+pub struct ConnectionSlot {
+    connection: ArcSwapOption<WebRTCConnection>,
+}
+
+impl ConnectionSlot {
+    pub fn get(&self) -> Option<Arc<WebRTCConnection>> {
+        self.connection.load_full()
+    }
+
+    pub fn lifecycle(&self) -> Lifecycle {
+        self.connection.load().as_ref().map_or(Lifecycle::Closed, |connection| connection.lifecycle())
+    }
+
+    // Takes over a new connection, the previous one is closed
+    pub fn replace(&self, runtime: &Runtime, connection: WebRTCConnection) {
+        if let Some(previous) = self.connection.swap(Some(Arc::new(connection))) {
+            runtime.spawn(async move { previous.close("session replaced".to_owned()).await });
+        }
+    }
+
+    // Says goodbye to the peer and closes in the background, a no-op when there is no connection
+    pub fn close(&self, runtime: &Runtime, reason: &str) {
+        if let Some(connection) = self.connection.swap(None) {
+            let reason = reason.to_owned();
+            runtime.spawn(async move { connection.close(reason).await });
+        }
+    }
+
+    // Like `close`, but waits (up to SHUTDOWN_TIMEOUT) until the peer was told and everything is closed
+    pub fn shutdown(&self, runtime: &Runtime, reason: &str) {
+        // Taken out first, so the audio thread stops using it while we wait on the network
+        if let Some(connection) = self.connection.swap(None) {
+            connection.shutdown(runtime, reason.to_owned(), SHUTDOWN_TIMEOUT);
+        }
+    }
+}
@@ -0,0 +1,502 @@
+use bytes::{BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    flac::{fixed_residual, rice_parameter, BitWriter, MAX_FIXED_ORDER, MAX_RICE5_PARAMETER},
+    Codec, MAX_STREAMS,
+};
+
+// Longest linear predictor tried, and how finely its coefficients are quantized
+pub const MAX_LPC_ORDER: usize = 8;
+const LPC_PRECISION: u32 = 12;
+const MAX_LPC_SHIFT: i32 = 15;
+
+const MAX_PARTITION_ORDER: u32 = 4;
+
+const VERBATIM: u64 = 0;
+const CONSTANT: u64 = 1;
+const FIXED: u64 = 2;
+const LPC: u64 = 3;
+
+// Noise added before floats are rounded to 16 or 24 bits, so the rounding error does not follow the signal
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dither {
+    #[default]
+    None,
+    // Uniform, one LSB wide
+    Rectangular,
+    // Two uniforms added up, two LSB wide, keeps the noise floor independent of the signal
+    Triangular,
+}
+
+impl std::fmt::Display for Dither {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Dither::None => write!(f, "None"),
+            Dither::Rectangular => write!(f, "Rectangular"),
+            Dither::Triangular => write!(f, "Triangular (TPDF)"),
+        }
+    }
+}
+
+impl Codec {
+    // Width of the integers the codec carries, floats travel as 32-bit keys
+    fn sample_bits(&self) -> u32 {
+        match self {
+            Codec::LosslessI16 => 16,
+            Codec::LosslessI24 => 24,
+            Codec::PcmF32 | Codec::LosslessF32 => 32,
+        }
+    }
+}
+
+// Turns the samples of one packet into the codec's payload. Only quantizing to integers needs state, for the dither,
+// so that much runs on the audio thread and the prediction search in `QuantizedSamples::encode` can run elsewhere.
+pub struct AudioEncoder {
+    codec: Codec,
+    dither: Dither,
+    // xorshift32, never 0
+    noise: u32,
+}
+
+impl AudioEncoder {
+    pub fn new(codec: Codec, dither: Dither) -> Self {
+        Self { codec, dither, noise: 0x9E37_79B9 }
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn dither(&self) -> Dither {
+        self.dither
+    }
+
+    // `samples` are interleaved by stream, a trailing partial frame is dropped
+    pub fn encode(&mut self, samples: &[f32], streams: usize, buf: &mut BytesMut) {
+        self.quantize(samples, streams).encode(buf);
+    }
+
+    // First half of `encode`, cheap enough for the audio thread
+    pub fn quantize(&mut self, samples: &[f32], streams: usize) -> QuantizedSamples {
+        if self.codec == Codec::PcmF32 {
+            return QuantizedSamples::Float(samples.to_vec());
+        }
+
+        let streams = streams.clamp(1, MAX_STREAMS);
+        let frames = (samples.len() / streams).min(u16::MAX as usize);
+
+        QuantizedSamples::Integer {
+            bits_per_sample: self.codec.sample_bits(),
+            streams,
+            samples: samples[..frames * streams].iter().map(|sample| self.quantize_sample(*sample)).collect(),
+        }
+    }
+
+    fn quantize_sample(&mut self, sample: f32) -> i64 {
+        let bits = self.codec.sample_bits();
+
+        if self.codec == Codec::LosslessF32 {
+            return float_key(sample);
+        }
+
+        let scale = (1i64 << (bits - 1)) as f64;
+        let dither = match self.dither {
+            Dither::None => 0.0,
+            Dither::Rectangular => self.next_noise(),
+            Dither::Triangular => self.next_noise() + self.next_noise(),
+        };
+
+        ((sample as f64 * scale + dither).round() as i64).clamp(-(scale as i64), scale as i64 - 1)
+    }
+
+    // Uniform in -0.5..0.5
+    fn next_noise(&mut self) -> f64 {
+        self.noise ^= self.noise << 13;
+        self.noise ^= self.noise >> 17;
+        self.noise ^= self.noise << 5;
+        self.noise as f64 / (u32::MAX as f64 + 1.0) - 0.5
+    }
+}
+
+// One packet's samples as the codec carries them, interleaved by stream, see `AudioEncoder::quantize`
+pub enum QuantizedSamples {
+    Float(Vec<f32>),
+    Integer { bits_per_sample: u32, streams: usize, samples: Vec<i64> },
+}
+
+impl QuantizedSamples {
+    pub fn len(&self) -> usize {
+        match self {
+            QuantizedSamples::Float(samples) => samples.len(),
+            QuantizedSamples::Integer { samples, .. } => samples.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Second half of `AudioEncoder::encode`, the search for the best predictor of every stream
+    pub fn encode(&self, buf: &mut BytesMut) {
+        let (bits_per_sample, streams, samples) = match self {
+            QuantizedSamples::Float(samples) => {
+                for sample in samples {
+                    buf.put_f32_le(*sample);
+                }
+                return;
+            }
+            QuantizedSamples::Integer { bits_per_sample, streams, samples } => (*bits_per_sample, *streams, samples),
+        };
+
+        let mut bits = BitWriter::default();
+        bits.write((samples.len() / streams) as u64, 16);
+
+        for stream in 0..streams {
+            let ints: Vec<i64> = samples.iter().skip(stream).step_by(streams).copied().collect();
+            write_subframe(&mut bits, &ints, bits_per_sample);
+        }
+
+        buf.put_slice(&bits.into_bytes());
+    }
+}
+
+// Samples back from a payload written by `AudioEncoder::encode`, interleaved by stream. None if it is malformed.
+pub fn decode_samples(codec: Codec, data: &[u8], streams: usize) -> Option<Vec<f32>> {
+    if codec == Codec::PcmF32 {
+        if !data.len().is_multiple_of(4) {
+            return None;
+        }
+        return Some(data.chunks_exact(4).map(|sample| f32::from_le_bytes(sample.try_into().unwrap())).collect());
+    }
+
+    let streams = streams.clamp(1, MAX_STREAMS);
+    let bits_per_sample = codec.sample_bits();
+
+    let mut bits = BitReader::new(data);
+    let frames = bits.read(16)? as usize;
+
+    let mut samples = vec![0.0; frames * streams];
+    for stream in 0..streams {
+        let ints = read_subframe(&mut bits, frames, bits_per_sample)?;
+
+        for (sample, int) in samples[stream..].iter_mut().step_by(streams).zip(ints) {
+            *sample = match codec {
+                Codec::LosslessF32 => f32::from_bits(float_key(f32::from_bits(int as u32)) as u32),
+                _ => (int as f64 / (1i64 << (bits_per_sample - 1)) as f64) as f32,
+            };
+        }
+    }
+
+    Some(samples)
+}
+
+// Maps the float's bits to an i32 that orders the same way as the float, so neighbouring values stay close.
+// Applied twice it gives the bits back.
+fn float_key(sample: f32) -> i64 {
+    let bits = sample.to_bits() as i32;
+    (if bits < 0 { bits ^ 0x7FFF_FFFF } else { bits }) as i64
+}
+
+// MSB first, the counterpart to `BitWriter`
+struct BitReader<'a> {
+    data: &'a [u8],
+    bit: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, bit: 0 }
+    }
+
+    fn read(&mut self, bits: u32) -> Option<u64> {
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = *self.data.get(self.bit / 8)?;
+            value = (value << 1) | ((byte >> (7 - self.bit % 8)) & 1) as u64;
+            self.bit += 1;
+        }
+        Some(value)
+    }
+
+    fn read_signed(&mut self, bits: u32) -> Option<i64> {
+        let value = self.read(bits)?;
+        let shift = 64 - bits;
+        Some(((value << shift) as i64) >> shift)
+    }
+
+    fn read_unary(&mut self) -> Option<u64> {
+        let mut zeros = 0;
+        while self.read(1)? == 0 {
+            zeros += 1;
+        }
+        Some(zeros)
+    }
+
+    fn read_rice(&mut self, parameter: u32) -> Option<i64> {
+        let folded = (self.read_unary()? << parameter) | self.read(parameter)?;
+        Some((folded >> 1) as i64 ^ -((folded & 1) as i64))
+    }
+}
+
+// Quantized linear predictor: prediction = sum(coefficients[j] * sample[i - 1 - j]) >> shift
+struct Lpc {
+    coefficients: Vec<i64>,
+    shift: u32,
+}
+
+impl Lpc {
+    fn residual(&self, samples: &[i64]) -> Vec<i64> {
+        (self.coefficients.len()..samples.len()).map(|i| samples[i] - self.predict(&samples[..i])).collect()
+    }
+
+    // From the samples before the one to predict, newest last
+    fn predict(&self, history: &[i64]) -> i64 {
+        let sum: i64 = self.coefficients.iter().zip(history.iter().rev()).map(|(coefficient, sample)| coefficient * sample).sum();
+        sum >> self.shift
+    }
+}
+
+// Levinson-Durbin on the autocorrelation gives the predictor of every order up to `max_order` at once
+fn lpc_candidates(samples: &[i64], max_order: usize) -> Vec<Lpc> {
+    let autocorrelation: Vec<f64> = (0..=max_order)
+        .map(|lag| (lag..samples.len()).map(|i| samples[i] as f64 * samples[i - lag] as f64).sum())
+        .collect();
+
+    let mut candidates = Vec::new();
+    let mut coefficients: Vec<f64> = Vec::new();
+    let mut error = autocorrelation[0];
+
+    for order in 0..max_order {
+        if error <= 0.0 {
+            break;
+        }
+
+        let reflection = (autocorrelation[order + 1] - coefficients.iter().enumerate().map(|(j, c)| c * autocorrelation[order - j]).sum::<f64>()) / error;
+
+        let previous = coefficients.clone();
+        for (j, coefficient) in coefficients.iter_mut().enumerate() {
+            *coefficient -= reflection * previous[order - 1 - j];
+        }
+        coefficients.push(reflection);
+        error *= 1.0 - reflection * reflection;
+
+        candidates.extend(quantize_lpc(&coefficients));
+    }
+
+    candidates
+}
+
+// Fixed point coefficients, with the rounding error of each carried into the next
+fn quantize_lpc(coefficients: &[f64]) -> Option<Lpc> {
+    let largest = coefficients.iter().fold(0.0f64, |largest, c| largest.max(c.abs()));
+    if !largest.is_finite() || largest <= 0.0 {
+        return None;
+    }
+
+    let shift = (LPC_PRECISION as i32 - 1 - (largest.log2().floor() as i32 + 1)).clamp(0, MAX_LPC_SHIFT);
+    let limit = (1i64 << (LPC_PRECISION - 1)) - 1;
+
+    let mut error = 0.0;
+    let coefficients = coefficients
+        .iter()
+        .map(|c| {
+            error += c * (1i64 << shift) as f64;
+            let quantized = (error.round() as i64).clamp(-limit - 1, limit);
+            error -= quantized as f64;
+            quantized
+        })
+        .collect();
+
+    Some(Lpc { coefficients, shift: shift as u32 })
+}
+
+enum Predictor {
+    Verbatim,
+    Constant,
+    Fixed(usize),
+    Lpc(Lpc),
+}
+
+fn write_subframe(bits: &mut BitWriter, samples: &[i64], bits_per_sample: u32) {
+    let sample_bits = bits_per_sample as u64;
+
+    let mut best = (Predictor::Verbatim, samples.len() as u64 * sample_bits, Vec::new());
+
+    if samples.len() > 1 && samples.iter().all(|sample| *sample == samples[0]) {
+        best = (Predictor::Constant, sample_bits, Vec::new());
+    } else {
+        // Predictors are compared on a single partition, the winner's partitioning is tuned afterwards
+        let mut consider = |predictor: Predictor, header_bits: u64, residual: Vec<i64>| {
+            let total = header_bits + 8 + rice_parameter(&residual, MAX_RICE5_PARAMETER).1;
+            if total < best.1 {
+                best = (predictor, total, residual);
+            }
+        };
+
+        for order in 0..=MAX_FIXED_ORDER.min(samples.len().saturating_sub(1)) {
+            consider(Predictor::Fixed(order), 3 + order as u64 * sample_bits, fixed_residual(samples, order));
+        }
+
+        for lpc in lpc_candidates(samples, MAX_LPC_ORDER.min(samples.len().saturating_sub(1))) {
+            let order = lpc.coefficients.len() as u64;
+            let residual = lpc.residual(samples);
+            consider(Predictor::Lpc(lpc), 3 + 4 + order * (LPC_PRECISION as u64 + sample_bits), residual);
+        }
+    }
+
+    match best {
+        (Predictor::Verbatim, ..) => {
+            bits.write(VERBATIM, 2);
+            for sample in samples {
+                bits.write_signed(*sample, bits_per_sample);
+            }
+        }
+        (Predictor::Constant, ..) => {
+            bits.write(CONSTANT, 2);
+            bits.write_signed(samples[0], bits_per_sample);
+        }
+        (Predictor::Fixed(order), _, residual) => {
+            bits.write(FIXED, 2);
+            bits.write(order as u64, 3);
+            for sample in &samples[..order] {
+                bits.write_signed(*sample, bits_per_sample);
+            }
+            write_residual(bits, &residual, order, samples.len());
+        }
+        (Predictor::Lpc(lpc), _, residual) => {
+            let order = lpc.coefficients.len();
+
+            bits.write(LPC, 2);
+            bits.write(order as u64 - 1, 3);
+            bits.write(lpc.shift as u64, 4);
+            for coefficient in &lpc.coefficients {
+                bits.write_signed(*coefficient, LPC_PRECISION);
+            }
+            for sample in &samples[..order] {
+                bits.write_signed(*sample, bits_per_sample);
+            }
+            write_residual(bits, &residual, order, samples.len());
+        }
+    }
+}
+
+fn read_subframe(bits: &mut BitReader, frames: usize, bits_per_sample: u32) -> Option<Vec<i64>> {
+    let mut samples = Vec::with_capacity(frames);
+
+    match bits.read(2)? {
+        VERBATIM => {
+            for _ in 0..frames {
+                samples.push(bits.read_signed(bits_per_sample)?);
+            }
+        }
+        CONSTANT => {
+            let value = bits.read_signed(bits_per_sample)?;
+            samples.resize(frames, value);
+        }
+        FIXED => {
+            let order = bits.read(3)? as usize;
+            if order > MAX_FIXED_ORDER || order > frames {
+                return None;
+            }
+
+            for _ in 0..order {
+                samples.push(bits.read_signed(bits_per_sample)?);
+            }
+
+            for residual in read_residual(bits, order, frames)? {
+                let s = |back: usize| samples[samples.len() - back];
+                let prediction = match order {
+                    0 => 0,
+                    1 => s(1),
+                    2 => 2 * s(1) - s(2),
+                    3 => 3 * s(1) - 3 * s(2) + s(3),
+                    _ => 4 * s(1) - 6 * s(2) + 4 * s(3) - s(4),
+                };
+                samples.push(in_range(prediction + residual, bits_per_sample)?);
+            }
+        }
+        _ => {
+            let order = bits.read(3)? as usize + 1;
+            if order > frames {
+                return None;
+            }
+
+            let shift = bits.read(4)? as u32;
+            let coefficients = (0..order).map(|_| bits.read_signed(LPC_PRECISION)).collect::<Option<Vec<_>>>()?;
+            let lpc = Lpc { coefficients, shift };
+
+            for _ in 0..order {
+                samples.push(bits.read_signed(bits_per_sample)?);
+            }
+
+            for residual in read_residual(bits, order, frames)? {
+                let prediction = lpc.predict(&samples);
+                samples.push(in_range(prediction + residual, bits_per_sample)?);
+            }
+        }
+    }
+
+    Some(samples)
+}
+
+// Anything wider than the samples did not come from an encoder, and would only grow from there
+fn in_range(sample: i64, bits_per_sample: u32) -> Option<i64> {
+    let limit = 1i64 << (bits_per_sample - 1);
+    (-limit..limit).contains(&sample).then_some(sample)
+}
+
+// Like FLAC: 2^p partitions of frames >> p samples, the first one short by the warm-up
+fn partitions_fit(partition_order: u32, order: usize, frames: usize) -> bool {
+    frames.is_multiple_of(1 << partition_order) && frames >> partition_order >= order
+}
+
+fn partition_bounds(partition_order: u32, order: usize, frames: usize) -> impl Iterator<Item = (usize, usize)> {
+    let size = frames >> partition_order;
+    (0..1usize << partition_order).map(move |partition| {
+        let start = if partition == 0 { 0 } else { partition * size - order };
+        (start, (partition + 1) * size - order)
+    })
+}
+
+fn write_residual(bits: &mut BitWriter, residual: &[i64], order: usize, frames: usize) {
+    let (partition_order, parameters, _) = (0..=MAX_PARTITION_ORDER)
+        .filter(|partition_order| partitions_fit(*partition_order, order, frames))
+        .map(|partition_order| {
+            let parameters: Vec<(u32, u64)> = partition_bounds(partition_order, order, frames)
+                .map(|(start, end)| rice_parameter(&residual[start..end], MAX_RICE5_PARAMETER))
+                .collect();
+            let total: u64 = parameters.iter().map(|(_, bits)| bits + 5).sum();
+            (partition_order, parameters, total)
+        })
+        .min_by_key(|(.., total)| *total)
+        .expect("a single partition always fits");
+
+    bits.write(partition_order as u64, 3);
+
+    for ((start, end), (parameter, _)) in partition_bounds(partition_order, order, frames).zip(parameters) {
+        bits.write(parameter as u64, 5);
+        for value in &residual[start..end] {
+            bits.write_rice(*value, parameter);
+        }
+    }
+}
+
+fn read_residual(bits: &mut BitReader, order: usize, frames: usize) -> Option<Vec<i64>> {
+    let partition_order = bits.read(3)? as u32;
+    if partition_order > MAX_PARTITION_ORDER || !partitions_fit(partition_order, order, frames) {
+        return None;
+    }
+
+    let mut residual = Vec::with_capacity(frames - order);
+    for (start, end) in partition_bounds(partition_order, order, frames) {
+        let parameter = bits.read(5)? as u32;
+        for _ in start..end {
+            residual.push(bits.read_rice(parameter)?);
+        }
+    }
+
+    Some(residual)
+}
+
@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::monotonic_ns;
+
+// Bottom of the meter scale, anything quieter reads as this
+pub const METER_FLOOR_DB: f32 = -96.0;
+
+// Peaks above this count as signal being present
+pub const SIGNAL_THRESHOLD_DB: f32 = -60.0;
+
+// How long peaks take to fall back by a factor of e, and how long the signal light stays on after the last peak
+const RELEASE_SECS: f32 = 0.3;
+const SIGNAL_HOLD_NS: u64 = 500_000_000;
+
+pub fn to_db(level: f32) -> f32 {
+    if level <= 0.0 {
+        return METER_FLOOR_DB;
+    }
+
+    (20.0 * level.log10()).max(METER_FLOOR_DB)
+}
+
+// Peak and RMS level with meter ballistics. The audio thread updates it once per block through atomics only,
+// the editor reads whenever it draws.
+#[derive(Default)]
+pub struct LevelMeter {
+    // f32 bits
+    peak: AtomicU32,
+    mean_square: AtomicU32,
+    // 0 until signal was ever seen
+    last_signal_ns: AtomicU64,
+}
+
+impl LevelMeter {
+    pub fn update(&self, samples: &[f32], sample_rate: f32) {
+        let peak = samples.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        let sum_squares = samples.iter().map(|sample| sample * sample).sum();
+
+        self.update_from(peak, sum_squares, samples.len(), sample_rate);
+    }
+
+    // For callers that already walk the samples, so they are not collected just for the meter
+    pub fn update_from(&self, peak: f32, sum_squares: f32, samples: usize, sample_rate: f32) {
+        if samples == 0 || sample_rate <= 0.0 {
+            return;
+        }
+
+        let release = (-(samples as f32 / sample_rate) / RELEASE_SECS).exp();
+
+        let held_peak = f32::from_bits(self.peak.load(Ordering::Relaxed)) * release;
+        self.peak.store(peak.max(held_peak).to_bits(), Ordering::Relaxed);
+
+        let mean_square = sum_squares / samples as f32;
+        let previous = f32::from_bits(self.mean_square.load(Ordering::Relaxed));
+        self.mean_square.store((mean_square + (previous - mean_square) * release).to_bits(), Ordering::Relaxed);
+
+        if to_db(peak) > SIGNAL_THRESHOLD_DB {
+            self.last_signal_ns.store(monotonic_ns().max(1), Ordering::Relaxed);
+        }
+    }
+
+    pub fn peak_db(&self) -> f32 {
+        to_db(f32::from_bits(self.peak.load(Ordering::Relaxed)))
+    }
+
+    pub fn rms_db(&self) -> f32 {
+        to_db(f32::from_bits(self.mean_square.load(Ordering::Relaxed)).sqrt())
+    }
+
+    pub fn signal_present(&self) -> bool {
+        let last = self.last_signal_ns.load(Ordering::Relaxed);
+        last != 0 && monotonic_ns().saturating_sub(last) < SIGNAL_HOLD_NS
+    }
+
+    pub fn reset(&self) {
+        self.peak.store(0, Ordering::Relaxed);
+        self.mean_square.store(0, Ordering::Relaxed);
+        self.last_signal_ns.store(0, Ordering::Relaxed);
+    }
+}
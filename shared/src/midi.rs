@@ -0,0 +1,68 @@
+use bytes::{Buf, BufMut};
+
+// Size of one encoded MidiEvent
+pub const MIDI_EVENT_LEN: usize = 11;
+
+// The subset of nih_plug's NoteEvent that maps onto plain MIDI, with values normalized the same way
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MidiMessage {
+    NoteOn { channel: u8, note: u8, velocity: f32 },
+    NoteOff { channel: u8, note: u8, velocity: f32 },
+    PolyPressure { channel: u8, note: u8, pressure: f32 },
+    ChannelPressure { channel: u8, pressure: f32 },
+    PitchBend { channel: u8, value: f32 },
+    ControlChange { channel: u8, cc: u8, value: f32 },
+    ProgramChange { channel: u8, program: u8 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MidiEvent {
+    // Sample offset from the first sample of the packet carrying this event
+    pub timing: u32,
+    pub message: MidiMessage,
+}
+
+impl MidiEvent {
+    pub fn write(&self, buf: &mut impl BufMut) {
+        let (kind, channel, data, value) = match self.message {
+            MidiMessage::NoteOn { channel, note, velocity } => (0, channel, note, velocity),
+            MidiMessage::NoteOff { channel, note, velocity } => (1, channel, note, velocity),
+            MidiMessage::PolyPressure { channel, note, pressure } => (2, channel, note, pressure),
+            MidiMessage::ChannelPressure { channel, pressure } => (3, channel, 0, pressure),
+            MidiMessage::PitchBend { channel, value } => (4, channel, 0, value),
+            MidiMessage::ControlChange { channel, cc, value } => (5, channel, cc, value),
+            MidiMessage::ProgramChange { channel, program } => (6, channel, program, 0.0),
+        };
+
+        buf.put_u32_le(self.timing);
+        buf.put_u8(kind);
+        buf.put_u8(channel);
+        buf.put_u8(data);
+        buf.put_f32_le(value);
+    }
+
+    pub fn read(buf: &mut impl Buf) -> Option<Self> {
+        if buf.remaining() < MIDI_EVENT_LEN {
+            return None;
+        }
+
+        let timing = buf.get_u32_le();
+        let kind = buf.get_u8();
+        let channel = buf.get_u8();
+        let data = buf.get_u8();
+        let value = buf.get_f32_le();
+
+        let message = match kind {
+            0 => MidiMessage::NoteOn { channel, note: data, velocity: value },
+            1 => MidiMessage::NoteOff { channel, note: data, velocity: value },
+            2 => MidiMessage::PolyPressure { channel, note: data, pressure: value },
+            3 => MidiMessage::ChannelPressure { channel, pressure: value },
+            4 => MidiMessage::PitchBend { channel, value },
+            5 => MidiMessage::ControlChange { channel, cc: data, value },
+            6 => MidiMessage::ProgramChange { channel, program: data },
+            _ => return None,
+        };
+
+        Some(Self { timing, message })
+    }
+}
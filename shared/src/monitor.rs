@@ -0,0 +1,105 @@
+// Longest the sender's local monitor can be held back to line up with the round trip
+pub const MAX_MONITOR_DELAY_SECS: f32 = 2.0;
+
+// RTT changes smaller than this leave the monitor delay alone, every jump in the delay is audible
+pub const MONITOR_DELAY_TOLERANCE_MS: f32 = 5.0;
+
+// How long a change in the monitor delay takes, fading from the old read position to the new one
+pub const MONITOR_CROSSFADE_MS: f32 = 20.0;
+
+// Ring buffer delay for one channel, allocated up front so the audio thread never has to
+pub struct DelayLine {
+    buffer: Vec<f32>,
+    write: usize,
+}
+
+impl DelayLine {
+    pub fn new(max_delay: usize) -> Self {
+        Self { buffer: vec![0.0; max_delay + 1], write: 0 }
+    }
+
+    pub fn max_delay(&self) -> usize {
+        self.buffer.len() - 1
+    }
+
+    // Stores `input` and returns what was stored `delay` samples ago, longer delays are cut to the capacity
+    pub fn process(&mut self, input: f32, delay: usize) -> f32 {
+        self.process_crossfade(input, delay, delay, 1.0)
+    }
+
+    // Same with two read positions, `fade` going from 0 (only `from`) to 1 (only `to`)
+    pub fn process_crossfade(&mut self, input: f32, from: usize, to: usize, fade: f32) -> f32 {
+        self.buffer[self.write] = input;
+
+        let from = self.buffer[self.read_index(from)];
+        let to = self.buffer[self.read_index(to)];
+        self.write = (self.write + 1) % self.buffer.len();
+
+        from + (to - from) * fade
+    }
+
+    fn read_index(&self, delay: usize) -> usize {
+        let len = self.buffer.len();
+        (self.write + len - delay.min(len - 1)) % len
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.fill(0.0);
+    }
+}
+
+// The delay every channel's line is read at. A new delay is faded in over a crossfade rather than jumped to,
+// and changes that arrive during one wait until it is over.
+pub struct MonitorDelay {
+    from: usize,
+    to: usize,
+    crossfade: usize,
+    faded: usize,
+}
+
+impl MonitorDelay {
+    pub fn new(crossfade: usize) -> Self {
+        let crossfade = crossfade.max(1);
+        Self { from: 0, to: 0, crossfade, faded: crossfade }
+    }
+
+    // Where the delay is headed
+    pub fn delay(&self) -> usize {
+        self.to
+    }
+
+    pub fn fading(&self) -> bool {
+        self.faded < self.crossfade
+    }
+
+    pub fn set(&mut self, delay: usize) {
+        if self.fading() || delay == self.to {
+            return;
+        }
+
+        self.from = self.to;
+        self.to = delay;
+        self.faded = 0;
+    }
+
+    // Jumps straight to `delay`, for when the lines were cleared anyway
+    pub fn reset(&mut self, delay: usize) {
+        self.from = delay;
+        self.to = delay;
+        self.faded = self.crossfade;
+    }
+
+    // Read positions and fade for the next sample, see `DelayLine::process_crossfade`
+    pub fn advance(&mut self) -> (usize, usize, f32) {
+        self.faded = (self.faded + 1).min(self.crossfade);
+        (self.from, self.to, self.faded as f32 / self.crossfade as f32)
+    }
+}
+
+// Monitor delay in samples for the round trip, None while it is within tolerance of `current`
+pub fn monitor_delay(rtt_ns: u64, sample_rate: f32, current: usize) -> Option<usize> {
+    let target = (rtt_ns as f64 / 1_000_000_000.0 * sample_rate as f64).round() as usize;
+    let tolerance = (MONITOR_DELAY_TOLERANCE_MS * sample_rate / 1000.0) as usize;
+
+    (target.abs_diff(current) > tolerance).then_some(target)
+}
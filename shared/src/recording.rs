@@ -0,0 +1,335 @@
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicU64, Ordering}, mpsc, Arc},
+    thread::JoinHandle,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config_dir, AudioFormat, FlacWriter, ReorderBuffer, Reordered, MAX_STREAMS};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordingFormat {
+    // 32-bit float, exactly what arrived
+    #[default]
+    Wav,
+    // 24-bit, about half the size
+    Flac,
+}
+
+impl RecordingFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RecordingFormat::Wav => "wav",
+            RecordingFormat::Flac => "flac",
+        }
+    }
+}
+
+impl std::fmt::Display for RecordingFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordingFormat::Wav => write!(f, "WAV (32-bit float)"),
+            RecordingFormat::Flac => write!(f, "FLAC (24-bit)"),
+        }
+    }
+}
+
+// Where recordings go when the session does not name a folder
+pub fn default_recording_dir() -> Option<PathBuf> {
+    Some(config_dir()?.join("recordings"))
+}
+
+// "<session>-<UTC date>_<time>.<ext>", with anything a file system might choke on in the session name replaced
+pub fn recording_file_name(session: &str, started: SystemTime, format: RecordingFormat) -> String {
+    let session: String = session
+        .trim()
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    let session = if session.is_empty() { "session".to_owned() } else { session };
+
+    let seconds = started.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let time_of_day = seconds % 86400;
+
+    format!(
+        "{session}-{year:04}-{month:02}-{day:02}_{:02}-{:02}-{:02}.{}",
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60,
+        format.extension()
+    )
+}
+
+// Days since 1970-01-01 to (year, month, day), from Howard Hinnant's date algorithms
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+
+    (year_of_era + era * 400 + i64::from(month <= 2), month, day)
+}
+
+// 32-bit float WAV with a cue point per marker. Sizes are filled in by `finish`, so the output has to be seekable.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    channels: u16,
+    data_bytes: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        let block_align = channels as u32 * 4;
+
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(b"WAVE")?;
+
+        // IEEE float, with the extra size field non-PCM formats need
+        out.write_all(b"fmt ")?;
+        out.write_all(&18u32.to_le_bytes())?;
+        out.write_all(&3u16.to_le_bytes())?;
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * block_align).to_le_bytes())?;
+        out.write_all(&(block_align as u16).to_le_bytes())?;
+        out.write_all(&32u16.to_le_bytes())?;
+        out.write_all(&0u16.to_le_bytes())?;
+
+        out.write_all(b"fact")?;
+        out.write_all(&4u32.to_le_bytes())?;
+        out.write_all(&0u32.to_le_bytes())?;
+
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self { out, channels, data_bytes: 0 })
+    }
+
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+
+        self.data_bytes = self.data_bytes.saturating_add(samples.len() as u32 * 4);
+        Ok(())
+    }
+
+    // `markers` are sample frame positions
+    pub fn finish(mut self, markers: &[u64]) -> io::Result<W> {
+        let frames = self.data_bytes / (self.channels as u32 * 4);
+
+        if !markers.is_empty() {
+            self.out.write_all(b"cue ")?;
+            self.out.write_all(&(4 + 24 * markers.len() as u32).to_le_bytes())?;
+            self.out.write_all(&(markers.len() as u32).to_le_bytes())?;
+
+            for (id, position) in markers.iter().enumerate() {
+                let position = (*position).min(u32::MAX as u64) as u32;
+
+                self.out.write_all(&(id as u32 + 1).to_le_bytes())?;
+                self.out.write_all(&position.to_le_bytes())?;
+                self.out.write_all(b"data")?;
+                self.out.write_all(&0u32.to_le_bytes())?;
+                self.out.write_all(&0u32.to_le_bytes())?;
+                self.out.write_all(&position.to_le_bytes())?;
+            }
+        }
+
+        let riff_size = self.out.stream_position()? - 8;
+
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(riff_size.min(u32::MAX as u64) as u32).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(46))?;
+        self.out.write_all(&frames.to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(54))?;
+        self.out.write_all(&self.data_bytes.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+
+        Ok(self.out)
+    }
+}
+
+enum FileWriter {
+    Wav(WavWriter<BufWriter<File>>),
+    Flac(FlacWriter<BufWriter<File>>),
+}
+
+enum RecorderCommand {
+    Samples(Vec<f32>),
+    Gap { frames: u64 },
+}
+
+// What a finished recording left behind
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordingSummary {
+    pub path: PathBuf,
+    pub frames: u64,
+    // Frame positions where lost packets were filled with silence
+    pub gaps: Vec<u64>,
+}
+
+// Records the received stream, one channel per stem, on a thread of its own so neither the network nor the audio
+// thread waits on the disk. Packets are put back in sequence order, lost ones become silence and a marker.
+pub struct Recording {
+    commands: Option<mpsc::Sender<RecorderCommand>>,
+    thread: Option<JoinHandle<io::Result<RecordingSummary>>>,
+    path: PathBuf,
+    sample_rate: f32,
+    channels: usize,
+    frames: Arc<AtomicU64>,
+    reorder: ReorderBuffer<Vec<f32>>,
+    // Frames in the last packet, stands in for the size of lost ones
+    last_frames: u64,
+}
+
+impl Recording {
+    pub fn start(path: &Path, format: RecordingFormat, stream: &AudioFormat) -> io::Result<Self> {
+        let channels = (stream.streams as usize).clamp(1, MAX_STREAMS);
+        let sample_rate = stream.sample_rate.round() as u32;
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let file = BufWriter::new(File::create(path)?);
+        let mut writer = match format {
+            RecordingFormat::Wav => FileWriter::Wav(WavWriter::new(file, sample_rate, channels as u16)?),
+            RecordingFormat::Flac => FileWriter::Flac(FlacWriter::new(file, sample_rate, channels)?),
+        };
+
+        let (commands, received) = mpsc::channel();
+        let frames = Arc::new(AtomicU64::new(0));
+        let frames_clone = frames.clone();
+        let summary_path = path.to_owned();
+
+        let thread = std::thread::Builder::new().name("live-collab-recorder".to_owned()).spawn(move || {
+            let mut gaps = Vec::new();
+
+            for command in received {
+                let samples = match command {
+                    RecorderCommand::Samples(samples) => samples,
+                    RecorderCommand::Gap { frames } => {
+                        gaps.push(frames_clone.load(Ordering::Relaxed));
+                        vec![0.0; frames as usize * channels]
+                    }
+                };
+
+                match &mut writer {
+                    FileWriter::Wav(wav) => wav.write(&samples)?,
+                    FileWriter::Flac(flac) => flac.write(&samples)?,
+                }
+
+                frames_clone.fetch_add((samples.len() / channels) as u64, Ordering::Relaxed);
+            }
+
+            match writer {
+                FileWriter::Wav(wav) => drop(wav.finish(&gaps)?),
+                FileWriter::Flac(flac) => drop(flac.finish()?),
+            }
+
+            if !gaps.is_empty() {
+                write_labels(&summary_path, &gaps, sample_rate)?;
+            }
+
+            Ok(RecordingSummary { path: summary_path, frames: frames_clone.load(Ordering::Relaxed), gaps })
+        })?;
+
+        Ok(Self {
+            commands: Some(commands),
+            thread: Some(thread),
+            path: path.to_owned(),
+            sample_rate: stream.sample_rate,
+            channels,
+            frames,
+            reorder: ReorderBuffer::default(),
+            last_frames: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+
+    pub fn seconds(&self) -> f32 {
+        self.frames.load(Ordering::Relaxed) as f32 / self.sample_rate.max(1.0)
+    }
+
+    // Interleaved stems of one packet, in whatever order they arrive. A packet still missing once REORDER_WINDOW
+    // later ones are in is counted as lost, and dropped if it shows up after all.
+    pub fn write_packet(&mut self, seq: u32, samples: &[f32]) {
+        let samples = samples[..samples.len() - samples.len() % self.channels].to_vec();
+
+        let Self { commands, channels, reorder, last_frames, .. } = self;
+        reorder.push(seq, samples, |reordered| write_reordered(commands, *channels, last_frames, reordered));
+    }
+
+    // Waits for the writer to get everything to disk
+    pub fn stop(mut self) -> io::Result<RecordingSummary> {
+        self.flush();
+        self.commands.take();
+        let thread = self.thread.take().expect("recording stopped twice");
+        thread.join().map_err(|_| io::Error::other("recorder thread panicked"))?
+    }
+
+    // Packets held back waiting for a missing one are written, the holes as gaps
+    fn flush(&mut self) {
+        let Self { commands, channels, reorder, last_frames, .. } = self;
+        reorder.flush(|reordered| write_reordered(commands, *channels, last_frames, reordered));
+    }
+}
+
+fn write_reordered(commands: &Option<mpsc::Sender<RecorderCommand>>, channels: usize, last_frames: &mut u64, reordered: Reordered<Vec<f32>>) {
+    let command = match reordered {
+        Reordered::Packet(_, samples) => {
+            *last_frames = (samples.len() / channels) as u64;
+            RecorderCommand::Samples(samples)
+        }
+        Reordered::Lost(packets) if *last_frames > 0 => RecorderCommand::Gap { frames: packets as u64 * *last_frames },
+        _ => return,
+    };
+
+    if let Some(commands) = commands {
+        let _ = commands.send(command);
+    }
+}
+
+// A recording dropped without `stop` still gets a valid file
+impl Drop for Recording {
+    fn drop(&mut self) {
+        self.flush();
+        self.commands.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Gaps as an Audacity label track next to the recording, FLAC has no cue points to hold them
+fn write_labels(path: &Path, gaps: &[u64], sample_rate: u32) -> io::Result<()> {
+    let mut labels = String::new();
+    for position in gaps {
+        let seconds = *position as f64 / sample_rate.max(1) as f64;
+        let _ = writeln!(labels, "{seconds:.6}\t{seconds:.6}\tpacket loss");
+    }
+
+    let mut file_name = path.file_name().unwrap_or_default().to_owned();
+    file_name.push(".labels.txt");
+    std::fs::write(path.with_file_name(file_name), labels)
+}
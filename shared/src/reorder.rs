@@ -0,0 +1,114 @@
+use std::collections::VecDeque;
+
+// Packets held back waiting for an earlier one, about 10 ms of audio at the default frame size
+pub const REORDER_WINDOW: usize = 4;
+
+#[derive(Debug, PartialEq)]
+pub enum Reordered<T> {
+    Packet(u32, T),
+    // This many packets right before the next one never arrived in time
+    Lost(u32),
+    // Came after the window had moved past its sequence number
+    Late(u32, T),
+}
+
+// Puts packets from the unordered audio channel back into sequence order. Once a packet is `window` ahead
+// of a missing one, the missing one is given up as lost. Nothing comes out until the window first fills up,
+// so the stream starts at the lowest sequence number seen rather than whichever packet arrived first.
+pub struct ReorderBuffer<T> {
+    slots: VecDeque<Option<T>>,
+    next_seq: Option<u32>,
+    lost: u32,
+    filling: bool,
+}
+
+impl<T> Default for ReorderBuffer<T> {
+    fn default() -> Self {
+        Self::new(REORDER_WINDOW)
+    }
+}
+
+impl<T> ReorderBuffer<T> {
+    pub fn new(window: usize) -> Self {
+        let window = window.max(1);
+        Self { slots: (0..window).map(|_| None).collect(), next_seq: None, lost: 0, filling: true }
+    }
+
+    // Hands everything that is now in order to `out`
+    pub fn push(&mut self, seq: u32, packet: T, mut out: impl FnMut(Reordered<T>)) {
+        let next_seq = *self.next_seq.get_or_insert(seq);
+        let mut ahead = seq.wrapping_sub(next_seq) as i32;
+
+        // Still filling, an earlier packet moves the start back as long as everything held still fits
+        if ahead < 0 && self.filling {
+            let highest = self.slots.iter().rposition(Option::is_some).unwrap_or_default();
+            if highest + (ahead.unsigned_abs() as usize) < self.slots.len() {
+                for _ in 0..ahead.unsigned_abs() {
+                    self.slots.pop_back();
+                    self.slots.push_front(None);
+                }
+                self.next_seq = Some(seq);
+                ahead = 0;
+            }
+        }
+
+        if ahead < 0 {
+            out(Reordered::Late(seq, packet));
+            return;
+        }
+
+        let mut ahead = ahead as usize;
+
+        while ahead >= self.slots.len() {
+            self.filling = false;
+
+            // Nothing held, a long stretch of loss is skipped in one go
+            if self.slots.iter().all(Option::is_none) {
+                let skipped = ahead - self.slots.len() + 1;
+                self.lost = self.lost.saturating_add(skipped as u32);
+                self.next_seq = self.next_seq.map(|next_seq| next_seq.wrapping_add(skipped as u32));
+                ahead -= skipped;
+                break;
+            }
+
+            self.advance(&mut out);
+            ahead -= 1;
+        }
+
+        self.slots[ahead] = Some(packet);
+
+        if self.filling && self.slots.iter().any(Option::is_none) {
+            return;
+        }
+        self.filling = false;
+
+        while self.slots[0].is_some() {
+            self.advance(&mut out);
+        }
+    }
+
+    // Gives out whatever is still held, the holes between counted as lost
+    pub fn flush(&mut self, mut out: impl FnMut(Reordered<T>)) {
+        self.filling = false;
+        while self.slots.iter().any(Option::is_some) {
+            self.advance(&mut out);
+        }
+    }
+
+    fn advance(&mut self, out: &mut impl FnMut(Reordered<T>)) {
+        let Some(seq) = self.next_seq else { return };
+
+        match self.slots.pop_front().flatten() {
+            Some(packet) => {
+                if self.lost > 0 {
+                    out(Reordered::Lost(std::mem::take(&mut self.lost)));
+                }
+                out(Reordered::Packet(seq, packet));
+            }
+            None => self.lost = self.lost.saturating_add(1),
+        }
+
+        self.slots.push_back(None);
+        self.next_seq = Some(seq.wrapping_add(1));
+    }
+}
@@ -0,0 +1,73 @@
+use std::{ops::Deref, sync::{Arc, Mutex, Weak}, time::Duration};
+
+use tokio::runtime::{Builder, Handle, Runtime};
+
+// Longest the host is kept waiting while a plugin says goodbye and its network tasks wind down
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Networking is mostly waiting, a few workers serve any number of plugin instances
+pub const MAX_WORKER_THREADS: usize = 4;
+// Blocking work is key derivation and the like, rare and short
+pub const MAX_BLOCKING_THREADS: usize = 8;
+
+// The runtime every instance in this process shares, alive while at least one PluginRuntime is
+static SHARED_RUNTIME: Mutex<Weak<Runtime>> = Mutex::new(Weak::new());
+
+// A plugin instance's handle on the shared tokio runtime. The first handle creates the runtime and the last one
+// shuts it down. Dropping a plain `Runtime` blocks until every task is done, and panics when that happens on one
+// of its own threads; the last handle gives up after SHUTDOWN_TIMEOUT instead.
+pub struct PluginRuntime {
+    runtime: Option<Arc<Runtime>>,
+}
+
+impl PluginRuntime {
+    pub fn new() -> std::io::Result<Self> {
+        let mut shared = SHARED_RUNTIME.lock().unwrap();
+
+        if let Some(runtime) = shared.upgrade() {
+            return Ok(Self { runtime: Some(runtime) });
+        }
+
+        let worker_threads = std::thread::available_parallelism().map_or(1, |cores| cores.get()).min(MAX_WORKER_THREADS);
+        let runtime = Arc::new(
+            Builder::new_multi_thread()
+                .worker_threads(worker_threads)
+                .max_blocking_threads(MAX_BLOCKING_THREADS)
+                .thread_name("live-collab")
+                .enable_all()
+                .build()?,
+        );
+
+        *shared = Arc::downgrade(&runtime);
+        Ok(Self { runtime: Some(runtime) })
+    }
+}
+
+impl Deref for PluginRuntime {
+    type Target = Runtime;
+
+    fn deref(&self) -> &Runtime {
+        self.runtime.as_ref().expect("runtime is only taken on drop")
+    }
+}
+
+impl Drop for PluginRuntime {
+    fn drop(&mut self) {
+        let Some(runtime) = self.runtime.take() else { return };
+
+        // Checked under the lock, so a new instance either shares this runtime or starts a fresh one after it
+        let last = {
+            let _shared = SHARED_RUNTIME.lock().unwrap();
+            Arc::try_unwrap(runtime).ok()
+        };
+
+        let Some(runtime) = last else { return };
+
+        // The last reference to a plugin can go away inside one of our own tasks, which must not block
+        if Handle::try_current().is_ok() {
+            runtime.shutdown_background();
+        } else {
+            runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+        }
+    }
+}
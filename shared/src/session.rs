@@ -0,0 +1,97 @@
+use std::{path::PathBuf, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{default_recording_dir, recording_file_name, Codec, Dither, Hello, IceConfig, Identity, RecordingFormat, MAX_FRAME_SIZE, MAX_STREAMS, SUPPORTED_CODECS};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionRole {
+    // Creates the session and streams audio
+    Sender,
+    // Answers the session token and plays the stream
+    Receiver,
+}
+
+// Everything about a session worth keeping with the project, restored when it is reopened.
+// Tokens and passphrases are deliberately not part of it.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    pub role: SessionRole,
+    pub ice: IceConfig,
+    // Who is on the other end, used when trusting their certificate
+    pub peer_name: String,
+    // Codec the sender offers, receivers accept every supported one
+    pub codec: Codec,
+    // Used when the codec rounds the stream to 16 or 24 bits
+    pub dither: Dither,
+    pub frame_size: u32,
+    // Streams the sender sends, the main input plus this many minus one aux stems
+    pub streams: u16,
+    pub recording_format: RecordingFormat,
+    // Folder for recordings of the received stream, blank for the default one
+    pub recording_dir: String,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self::new(SessionRole::Sender)
+    }
+}
+
+impl SessionConfig {
+    pub fn new(role: SessionRole) -> Self {
+        Self {
+            role,
+            ice: IceConfig::default(),
+            peer_name: String::new(),
+            codec: SUPPORTED_CODECS[0],
+            dither: Dither::default(),
+            frame_size: MAX_FRAME_SIZE,
+            streams: 1,
+            recording_format: RecordingFormat::default(),
+            recording_dir: String::new(),
+        }
+    }
+
+    // Blank lines left over from editing are not servers
+    pub fn ice_config(&self, identity: Option<&Identity>) -> IceConfig {
+        IceConfig {
+            stun_urls: self.ice.stun_urls.iter().map(|url| url.trim()).filter(|url| !url.is_empty()).map(str::to_owned).collect(),
+            include_loopback: self.ice.include_loopback,
+            certificate: identity.map(|identity| identity.certificate.clone()),
+            trusted_fingerprints: identity.map(|identity| identity.trusted_peers().into_iter().map(|peer| peer.fingerprint).collect()).unwrap_or_default(),
+            passphrase: None,
+        }
+    }
+
+    pub fn hello(&self, plugin_version: &str, sample_rate: f32, channels: u16) -> Hello {
+        let mut hello = Hello::new(plugin_version, self.role == SessionRole::Sender, sample_rate, channels);
+
+        // A codec this build no longer supports falls back to the defaults
+        if self.role == SessionRole::Sender && SUPPORTED_CODECS.contains(&self.codec) {
+            hello.codecs = vec![self.codec];
+        }
+
+        hello.max_frame_size = self.frame_size.clamp(1, MAX_FRAME_SIZE);
+
+        // Receivers have an output for every stem
+        hello.streams = match self.role {
+            SessionRole::Sender => self.streams.clamp(1, MAX_STREAMS as u16),
+            SessionRole::Receiver => MAX_STREAMS as u16,
+        };
+
+        hello
+    }
+
+    // File for a recording started at `started`, named after the peer
+    pub fn recording_path(&self, started: SystemTime) -> Option<PathBuf> {
+        let dir = match self.recording_dir.trim() {
+            "" => default_recording_dir()?,
+            dir => PathBuf::from(dir),
+        };
+
+        Some(dir.join(recording_file_name(&self.peer_name, started, self.recording_format)))
+    }
+}
@@ -0,0 +1,208 @@
+use std::{collections::VecDeque, sync::{atomic::AtomicU64, Arc, Mutex}, time::Duration};
+
+use webrtc::{peer_connection::{peer_connection_state::RTCPeerConnectionState, RTCPeerConnection}, stats::StatsReportType};
+
+use crate::{monotonic_ns, LatencyTracker, WebRTCConnection};
+
+pub const STATS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Polls kept for the latency graph, one minute at the poll interval
+pub const STATS_HISTORY_LEN: usize = 120;
+
+// Transport figures from `RTCPeerConnection::get_stats`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NetworkStats {
+    // Candidate types (host, srflx, prflx, relay) of the local and remote end of the selected pair
+    pub candidate_pair: Option<(String, String)>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+}
+
+pub async fn network_stats(peer: &RTCPeerConnection) -> NetworkStats {
+    let reports = peer.get_stats().await.reports;
+
+    let candidate_type = |id: &str| match reports.get(id) {
+        Some(StatsReportType::LocalCandidate(candidate) | StatsReportType::RemoteCandidate(candidate)) => candidate.candidate_type.to_string(),
+        _ => "unknown".to_owned(),
+    };
+
+    // Only the nominated pair carries the traffic
+    let selected = reports.values().find_map(|report| match report {
+        StatsReportType::CandidatePair(pair) if pair.nominated => Some(pair),
+        _ => None,
+    });
+
+    let mut stats = NetworkStats {
+        candidate_pair: selected.map(|pair| (candidate_type(&pair.local_candidate_id), candidate_type(&pair.remote_candidate_id))),
+        ..Default::default()
+    };
+
+    // webrtc-rs leaves the pair's counters at zero, the ICE transport and data channels keep the real ones
+    for report in reports.values() {
+        match report {
+            StatsReportType::Transport(transport) => {
+                stats.bytes_sent = transport.bytes_sent as u64;
+                stats.bytes_received = transport.bytes_received as u64;
+            }
+            StatsReportType::DataChannel(channel) => {
+                stats.packets_sent += channel.messages_sent as u64;
+                stats.packets_received += channel.messages_received as u64;
+            }
+            _ => {}
+        }
+    }
+
+    stats
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StatsSample {
+    pub at_ns: u64,
+    pub rtt_ms: f32,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+// Latest network stats plus a short history of them, for bitrates and the latency graph
+#[derive(Default)]
+pub struct StatsHistory {
+    pub latest: NetworkStats,
+    samples: VecDeque<StatsSample>,
+}
+
+impl StatsHistory {
+    pub fn push(&mut self, network: NetworkStats, rtt_ms: f32, at_ns: u64) {
+        self.samples.push_back(StatsSample { at_ns, rtt_ms, bytes_sent: network.bytes_sent, bytes_received: network.bytes_received });
+        while self.samples.len() > STATS_HISTORY_LEN {
+            self.samples.pop_front();
+        }
+
+        self.latest = network;
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn latency_ms(&self) -> Vec<f32> {
+        self.samples.iter().map(|sample| sample.rtt_ms).collect()
+    }
+
+    // (sent, received) in kbit/s between the last two polls
+    pub fn bitrate_kbps(&self) -> (f64, f64) {
+        let mut recent = self.samples.iter().rev();
+        let (Some(last), Some(previous)) = (recent.next(), recent.next()) else { return (0.0, 0.0) };
+
+        let seconds = last.at_ns.saturating_sub(previous.at_ns) as f64 / 1_000_000_000.0;
+        if seconds <= 0.0 {
+            return (0.0, 0.0);
+        }
+
+        let kbps = |now: u64, before: u64| now.saturating_sub(before) as f64 * 8.0 / 1000.0 / seconds;
+        (kbps(last.bytes_sent, previous.bytes_sent), kbps(last.bytes_received, previous.bytes_received))
+    }
+}
+
+// Arrival side of the audio stream: loss from gaps in the sequence numbers, jitter from how far
+// consecutive packets stray from the spacing their sample counts call for (RFC 3550 style)
+#[derive(Default)]
+pub struct StreamMonitor {
+    first_seq: Option<u64>,
+    highest_seq: u64,
+    received: u64,
+    bytes: u64,
+    jitter_ms: f64,
+    // (seq, arrival, samples) of the last packet
+    last: Option<(u32, u64, usize)>,
+}
+
+impl StreamMonitor {
+    pub fn on_packet(&mut self, seq: u32, samples: usize, bytes: usize, sample_rate: f32, arrival_ns: u64) {
+        self.received += 1;
+        self.bytes += bytes as u64;
+
+        // Sequence numbers wrap, extend them so the count of expected packets keeps growing
+        match self.first_seq {
+            None => {
+                self.first_seq = Some(seq as u64);
+                self.highest_seq = seq as u64;
+            }
+            Some(_) => {
+                let ahead = seq.wrapping_sub(self.highest_seq as u32) as i32;
+                if ahead > 0 {
+                    self.highest_seq += ahead as u64;
+                }
+            }
+        }
+
+        if let Some((last_seq, last_arrival, last_samples)) = self.last
+            && sample_rate > 0.0
+            && seq == last_seq.wrapping_add(1)
+        {
+            let expected_ms = last_samples as f64 * 1000.0 / sample_rate as f64;
+            let actual_ms = arrival_ns.saturating_sub(last_arrival) as f64 / 1_000_000.0;
+
+            self.jitter_ms += ((actual_ms - expected_ms).abs() - self.jitter_ms) / 16.0;
+        }
+
+        self.last = Some((seq, arrival_ns, samples));
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    // Late packets that did arrive in the end are not lost
+    pub fn lost(&self) -> u64 {
+        let expected = self.first_seq.map_or(0, |first| self.highest_seq - first + 1);
+        expected.saturating_sub(self.received)
+    }
+
+    pub fn loss_percent(&self) -> f32 {
+        let expected = self.lost() + self.received;
+        if expected == 0 { 0.0 } else { self.lost() as f32 * 100.0 / expected as f32 }
+    }
+
+    pub fn jitter_ms(&self) -> f32 {
+        self.jitter_ms as f32
+    }
+}
+
+// Counted on the receiver's audio thread
+#[derive(Default)]
+pub struct PlaybackCounters {
+    // Times the buffer ran dry while the stream was playing
+    pub underruns: AtomicU64,
+    // Times the buffer was trimmed because it grew past the target latency
+    pub overruns: AtomicU64,
+    // Samples played as silence in place of audio that was not there in time
+    pub concealed_samples: AtomicU64,
+}
+
+impl WebRTCConnection {
+    // Polls get_stats every `interval` until the connection closes, together with the latest RTT from `tracker`
+    pub fn start_stats(&self, runtime: &tokio::runtime::Runtime, interval: Duration, tracker: Arc<LatencyTracker>, history: Arc<Mutex<StatsHistory>>) {
+        let peer = Arc::downgrade(&self.peer);
+        runtime.spawn(async move {
+            loop {
+                let Some(peer) = peer.upgrade() else { break };
+
+                if matches!(peer.connection_state(), RTCPeerConnectionState::Closed | RTCPeerConnectionState::Failed) {
+                    break;
+                }
+
+                let network = network_stats(&peer).await;
+                history.lock().unwrap().push(network, tracker.estimate().smoothed_rtt_ms, monotonic_ns());
+
+                drop(peer);
+                tokio::time::sleep(interval).await;
+            }
+        });
+    }
+}
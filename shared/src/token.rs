@@ -0,0 +1,234 @@
+use std::{sync::{atomic::{AtomicBool, Ordering}, Mutex}, time::{SystemTime, UNIX_EPOCH}};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{derive_key, SALT_LEN};
+
+// How long a session token can be answered after it was created
+pub const TOKEN_LIFETIME_SECS: u64 = 15 * 60;
+
+const SESSION_NONCE_LEN: usize = SALT_LEN;
+// Stands in for the second salt, so the signing key is never the key the audio is sealed with
+const TOKEN_KEY_SALT: [u8; SALT_LEN] = *b"live-collab-tokn";
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Wall clock seconds, for token expiry and chat timestamps
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize)]
+struct TokenBody {
+    // Serialized connect info (SDP and ICE candidates)
+    info: String,
+    nonce: [u8; SESSION_NONCE_LEN],
+    expires_at: u64,
+    is_offer: bool,
+}
+
+// The signature covers the body exactly as it was serialized, and is only there with a passphrase
+#[derive(Serialize, Deserialize)]
+struct SignedToken {
+    body: String,
+    #[serde(default)]
+    signature: Option<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub enum TokenError {
+    Malformed(String),
+    BadSignature,
+    PassphraseRequired,
+    Expired,
+    WrongSession,
+    AlreadyAnswered,
+    UntrustedPeer(String),
+}
+
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenError::Malformed(err) => write!(f, "not a valid session token: {err}"),
+            TokenError::BadSignature => write!(f, "session token is not signed with this passphrase, it was altered or the peer uses another one"),
+            TokenError::PassphraseRequired => write!(f, "session token is signed, set the same passphrase as the peer"),
+            TokenError::Expired => write!(f, "session token expired, create a new session"),
+            TokenError::WrongSession => write!(f, "answer belongs to a different session"),
+            TokenError::AlreadyAnswered => write!(f, "this session was already answered, create a new session"),
+            TokenError::UntrustedPeer(fingerprint) => write!(f, "peer certificate {fingerprint} is not on the allow-list"),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+// Argon2 over the passphrase and the session nonce, slow on purpose like the audio key
+fn signing_key(passphrase: &str, nonce: &[u8; SESSION_NONCE_LEN]) -> [u8; 32] {
+    derive_key(passphrase, nonce, &TOKEN_KEY_SALT)
+}
+
+fn signature(key: &[u8; 32], body: &str) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("hmac takes any key length");
+    mac.update(body.as_bytes());
+    mac
+}
+
+fn decode(token: &str) -> Result<(SignedToken, TokenBody), TokenError> {
+    let json = STANDARD.decode(token.trim()).map_err(|err| TokenError::Malformed(err.to_string()))?;
+    let signed: SignedToken = serde_json::from_slice(&json).map_err(|err| TokenError::Malformed(err.to_string()))?;
+    let body = serde_json::from_str(&signed.body).map_err(|err| TokenError::Malformed(err.to_string()))?;
+
+    Ok((signed, body))
+}
+
+// A token is signed exactly when this side has a passphrase
+fn verify(key: Option<&[u8; 32]>, signed: &SignedToken) -> Result<(), TokenError> {
+    match (key, &signed.signature) {
+        (Some(key), Some(tag)) => signature(key, &signed.body).verify_slice(tag).map_err(|_| TokenError::BadSignature),
+        (Some(_), None) => Err(TokenError::BadSignature),
+        (None, Some(_)) => Err(TokenError::PassphraseRequired),
+        (None, None) => Ok(()),
+    }
+}
+
+// "a=fingerprint:" lines of an SDP, normalized to "sha-256 AB:CD:..."
+pub fn sdp_fingerprints(sdp: &str) -> Vec<String> {
+    sdp.lines()
+        .filter_map(|line| line.trim().strip_prefix("a=fingerprint:"))
+        .map(normalize_fingerprint)
+        .collect()
+}
+
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    match fingerprint.trim().split_once(' ') {
+        Some((algorithm, value)) => format!("{} {}", algorithm.to_lowercase(), value.trim().to_uppercase()),
+        None => fingerprint.trim().to_uppercase(),
+    }
+}
+
+// One offer and the answer to it: an answer has to belong to this session, arrive before it expires and be the only one.
+// With a passphrase both tokens are signed with a key derived from it and the session nonce, so nobody without the
+// passphrase can write an answer, even having seen the offer. Without one tokens are not secret and whoever answers
+// first gets in; the peer's DTLS fingerprint is then all that stands in the way, on the allow-list or confirmed by the user.
+pub struct SessionToken {
+    nonce: [u8; SESSION_NONCE_LEN],
+    key: Option<[u8; 32]>,
+    // Set by the offer, answers carry the same one and cannot extend it
+    expires_at: u64,
+    answered: AtomicBool,
+    allowed_fingerprints: Mutex<Vec<String>>,
+}
+
+impl SessionToken {
+    // Derives the signing key with a passphrase, so call off the audio thread
+    pub fn generate(now: u64, passphrase: Option<&str>) -> Self {
+        let nonce = rand::random();
+
+        Self {
+            nonce,
+            key: passphrase.filter(|passphrase| !passphrase.is_empty()).map(|passphrase| signing_key(passphrase, &nonce)),
+            expires_at: now + TOKEN_LIFETIME_SECS,
+            answered: AtomicBool::new(false),
+            allowed_fingerprints: Default::default(),
+        }
+    }
+
+    // Empty means any peer holding the token may answer
+    pub fn set_allowed_fingerprints(&self, fingerprints: Vec<String>) {
+        *self.allowed_fingerprints.lock().unwrap() = fingerprints.iter().map(|fingerprint| normalize_fingerprint(fingerprint)).collect();
+    }
+
+    pub fn allowed_fingerprints(&self) -> Vec<String> {
+        self.allowed_fingerprints.lock().unwrap().clone()
+    }
+
+    pub fn token(&self, info: String, is_offer: bool) -> String {
+        let body = TokenBody {
+            info,
+            nonce: self.nonce,
+            expires_at: self.expires_at,
+            is_offer,
+        };
+
+        let body = serde_json::to_string(&body).unwrap_or_default();
+        let signature = self.key.map(|key| signature(&key, &body).finalize().into_bytes().to_vec());
+
+        STANDARD.encode(serde_json::to_vec(&SignedToken { body, signature }).unwrap_or_default())
+    }
+
+    // Reads an offer and joins its session, returns the connect info inside. The passphrase has to be the offer's.
+    pub fn open_offer(token: &str, now: u64, passphrase: Option<&str>) -> Result<(Self, String), TokenError> {
+        let (signed, body) = decode(token)?;
+
+        if !body.is_offer {
+            return Err(TokenError::Malformed("expected an offer, got an answer".to_owned()));
+        }
+
+        let key = passphrase.filter(|passphrase| !passphrase.is_empty()).map(|passphrase| signing_key(passphrase, &body.nonce));
+        verify(key.as_ref(), &signed)?;
+
+        if now > body.expires_at {
+            return Err(TokenError::Expired);
+        }
+
+        let session = Self {
+            nonce: body.nonce,
+            key,
+            expires_at: body.expires_at,
+            answered: AtomicBool::new(false),
+            allowed_fingerprints: Default::default(),
+        };
+
+        Ok((session, body.info))
+    }
+
+    // Checks an answer's session and expiry, returns the connect info inside. Does not use up the session,
+    // see `accept_answer`.
+    pub fn open_answer(&self, token: &str, now: u64) -> Result<String, TokenError> {
+        let (signed, body) = decode(token)?;
+
+        if body.is_offer {
+            return Err(TokenError::Malformed("expected an answer, got an offer".to_owned()));
+        }
+
+        verify(self.key.as_ref(), &signed)?;
+
+        if body.nonce != self.nonce {
+            return Err(TokenError::WrongSession);
+        }
+
+        if now > self.expires_at {
+            return Err(TokenError::Expired);
+        }
+
+        if self.answered.load(Ordering::Acquire) {
+            return Err(TokenError::AlreadyAnswered);
+        }
+
+        Ok(body.info)
+    }
+
+    // Last step before the answer's SDP is applied: the peer's certificate must be allowed, and only one answer ever gets through
+    pub fn accept_answer(&self, sdp: &str) -> Result<(), TokenError> {
+        let allowed = self.allowed_fingerprints();
+
+        if !allowed.is_empty() {
+            let fingerprints = sdp_fingerprints(sdp);
+
+            if fingerprints.is_empty() {
+                return Err(TokenError::UntrustedPeer("(none)".to_owned()));
+            }
+
+            if let Some(untrusted) = fingerprints.iter().find(|fingerprint| !allowed.contains(fingerprint)) {
+                return Err(TokenError::UntrustedPeer(untrusted.clone()));
+            }
+        }
+
+        self.answered.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire).map_err(|_| TokenError::AlreadyAnswered)?;
+
+        Ok(())
+    }
+}
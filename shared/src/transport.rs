@@ -0,0 +1,48 @@
+use serde::{Deserialize, Serialize};
+
+// Snapshot of the sending host's transport, sent a few times per second and whenever play state changes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TransportSnapshot {
+    pub playing: bool,
+    pub tempo: Option<f64>,
+    pub time_signature: Option<(i32, i32)>,
+    pub pos_samples: Option<i64>,
+    pub pos_beats: Option<f64>,
+    pub sample_rate: f32,
+}
+
+impl TransportSnapshot {
+    // 1-based bar number and beat within that bar, counted in the time signature's beat unit
+    pub fn bar_and_beat(&self) -> Option<(i64, f64)> {
+        let (numerator, denominator) = self.time_signature.unwrap_or((4, 4));
+        if numerator <= 0 || denominator <= 0 {
+            return None;
+        }
+
+        // pos_beats is in quarter notes, convert to the signature's beat unit
+        let beats = self.pos_beats? * denominator as f64 / 4.0;
+        let bar = (beats / numerator as f64).floor();
+
+        Some((bar as i64 + 1, beats - bar * numerator as f64 + 1.0))
+    }
+}
+
+impl std::fmt::Display for TransportSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", if self.playing { "Playing" } else { "Stopped" })?;
+
+        if let Some(tempo) = self.tempo {
+            write!(f, " | {tempo:.1} BPM")?;
+        }
+
+        if let Some((numerator, denominator)) = self.time_signature {
+            write!(f, " | {numerator}/{denominator}")?;
+        }
+
+        if let Some((bar, beat)) = self.bar_and_beat() {
+            write!(f, " | Bar {bar} Beat {beat:.2}")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,8 @@
+use std::path::PathBuf;
+
+// A fresh directory per test run, gone again from any earlier run with the same name
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("live-collab-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
@@ -0,0 +1,116 @@
+use bytes::Bytes;
+use shared::*;
+
+// Runs the passphrase handshake between two sides the way the control channel would
+fn pair(ours: &str, theirs: &str) -> (Encryption, Encryption) {
+    let a = Encryption::new(Some(ours.to_owned()), true);
+    let b = Encryption::new(Some(theirs.to_owned()), false);
+
+    let Some(ControlMessage::AuthHello { salt: a_salt, nonce: a_nonce }) = a.auth_hello() else { panic!("no auth hello") };
+    let Some(ControlMessage::AuthHello { salt: b_salt, nonce: b_nonce }) = b.auth_hello() else { panic!("no auth hello") };
+
+    // b's proof reaches a before a has seen b's salt
+    let Some(ControlMessage::AuthProof { mac: b_proof }) = b.on_auth_hello(a_salt, a_nonce) else { panic!("no proof") };
+    a.on_auth_proof(b_proof);
+
+    let Some(ControlMessage::AuthProof { mac: a_proof }) = a.on_auth_hello(b_salt, b_nonce) else { panic!("no proof") };
+    b.on_auth_proof(a_proof);
+
+    (a, b)
+}
+
+#[test]
+fn matching_passphrases_verify_and_carry_audio() {
+    let (a, b) = pair("open sesame", "open sesame");
+    assert_eq!(a.state(), EncryptionState::Verified);
+    assert_eq!(b.state(), EncryptionState::Verified);
+
+    let frame = AudioPacket { seq: 1, song_position: None, midi: Vec::new(), samples: vec![0.25; 128] }.to_bytes();
+
+    let sealed = a.seal(frame.clone()).unwrap();
+    assert_ne!(sealed, frame);
+    assert_eq!(b.open(sealed), Some(frame.clone()));
+
+    // Each direction has its own key, a side cannot open what it sealed itself
+    let sealed = b.seal(frame.clone()).unwrap();
+    assert_eq!(b.open(sealed.clone()), None);
+    assert_eq!(a.open(sealed), Some(frame));
+}
+
+#[test]
+fn mismatched_passphrases_fail_on_both_sides() {
+    let (a, b) = pair("open sesame", "open sesame!");
+    assert!(matches!(a.state(), EncryptionState::Failed(_)));
+    assert!(matches!(b.state(), EncryptionState::Failed(_)));
+
+    assert_eq!(a.seal(Bytes::from_static(b"audio")), None);
+    assert!(!a.ready());
+}
+
+#[test]
+fn tampered_frames_are_dropped() {
+    let (a, b) = pair("hunter2", "hunter2");
+
+    let sealed = a.seal(Bytes::from_static(b"some audio")).unwrap();
+
+    let mut flipped = sealed.to_vec();
+    *flipped.last_mut().unwrap() ^= 1;
+    assert_eq!(b.open(flipped.into()), None);
+
+    // Replaying the ciphertext under another counter does not authenticate either
+    let mut recounted = sealed.to_vec();
+    recounted[0] ^= 1;
+    assert_eq!(b.open(recounted.into()), None);
+
+    assert_eq!(b.open(sealed.slice(..4)), None);
+    assert_eq!(b.open(sealed), Some(Bytes::from_static(b"some audio")));
+}
+
+#[test]
+fn without_a_passphrase_frames_pass_through() {
+    let encryption = Encryption::new(None, true);
+    assert_eq!(encryption.state(), EncryptionState::Off);
+    assert!(encryption.ready());
+    assert_eq!(encryption.auth_hello(), None);
+
+    let frame = Bytes::from_static(b"plain");
+    assert_eq!(encryption.seal(frame.clone()), Some(frame.clone()));
+    assert_eq!(encryption.open(frame.clone()), Some(frame));
+
+    // An empty passphrase counts as none
+    assert!(!Encryption::new(Some(String::new()), true).enabled());
+}
+
+#[test]
+fn reflected_handshakes_are_rejected() {
+    // Our own hello sent back
+    let a = Encryption::new(Some("open sesame".to_owned()), true);
+    let Some(ControlMessage::AuthHello { salt, nonce }) = a.auth_hello() else { panic!("no auth hello") };
+    assert_eq!(a.on_auth_hello(salt, nonce), None);
+    assert!(matches!(a.state(), EncryptionState::Failed(_)));
+
+    // Our own proof sent back, for a nonce the reflector picked
+    let a = Encryption::new(Some("open sesame".to_owned()), true);
+    let Some(ControlMessage::AuthProof { mac }) = a.on_auth_hello([7; SALT_LEN], [9; NONCE_LEN]) else { panic!("no proof") };
+    a.on_auth_proof(mac);
+    assert!(matches!(a.state(), EncryptionState::Failed(_)));
+
+    // Two sides that both think they made the offer do not verify each other either
+    let b = Encryption::new(Some("open sesame".to_owned()), true);
+    let c = Encryption::new(Some("open sesame".to_owned()), true);
+    let Some(ControlMessage::AuthHello { salt: b_salt, nonce: b_nonce }) = b.auth_hello() else { panic!("no auth hello") };
+    let Some(ControlMessage::AuthHello { salt: c_salt, nonce: c_nonce }) = c.auth_hello() else { panic!("no auth hello") };
+    let Some(ControlMessage::AuthProof { mac }) = c.on_auth_hello(b_salt, b_nonce) else { panic!("no proof") };
+    b.on_auth_hello(c_salt, c_nonce);
+    b.on_auth_proof(mac);
+    assert!(matches!(b.state(), EncryptionState::Failed(_)));
+}
+
+#[test]
+fn key_does_not_depend_on_which_side_derives_it() {
+    let (a, b) = ([1u8; SALT_LEN], [2u8; SALT_LEN]);
+
+    assert_eq!(derive_key("pass", &a, &b), derive_key("pass", &b, &a));
+    assert_ne!(derive_key("pass", &a, &b), derive_key("pass", &a, &a));
+    assert_ne!(derive_key("pass", &a, &b), derive_key("Pass", &a, &b));
+}
@@ -0,0 +1,110 @@
+use shared::*;
+
+fn sender() -> Hello {
+    Hello::new("1.0.0", true, 48000.0, 1)
+}
+
+fn receiver() -> Hello {
+    Hello::new("1.0.0", false, 44100.0, 2)
+}
+
+#[test]
+fn both_sides_agree_on_the_same_format() {
+    let format = AudioFormat { codec: Codec::PcmF32, sample_rate: 48000.0, channels: 1, frame_size: MAX_FRAME_SIZE, streams: 1 };
+
+    assert_eq!(negotiate(&sender(), &receiver()), Ok(format));
+    assert_eq!(negotiate(&receiver(), &sender()), Ok(format));
+}
+
+#[test]
+fn frame_size_is_the_smaller_of_the_two() {
+    let mut receiver = receiver();
+    receiver.max_frame_size = 256;
+
+    assert_eq!(negotiate(&sender(), &receiver).unwrap().frame_size, 256);
+}
+
+#[test]
+fn stems_are_limited_by_both_sides() {
+    let mut sender = sender();
+    sender.streams = 3;
+
+    let mut receiver = receiver();
+    assert_eq!(negotiate(&sender, &receiver).unwrap().streams, 1);
+
+    receiver.streams = MAX_STREAMS as u16;
+    assert_eq!(negotiate(&sender, &receiver).unwrap().streams, 3);
+
+    // Formats announced by older senders carry one stream
+    let format: AudioFormat = serde_json::from_str(r#"{"codec":"pcm_f32","sample_rate":48000.0,"channels":1,"frame_size":512}"#).unwrap();
+    assert_eq!(format.streams, 1);
+}
+
+#[test]
+fn incompatible_peers_are_refused() {
+    assert!(negotiate(&sender(), &sender()).unwrap_err().contains("both peers are senders"));
+    assert!(negotiate(&receiver(), &receiver()).unwrap_err().contains("both peers are receivers"));
+
+    let mut newer = receiver();
+    newer.protocol_version = PROTOCOL_VERSION + 1;
+    newer.plugin_version = "2.0.0".to_owned();
+    let reason = negotiate(&sender(), &newer).unwrap_err();
+    assert!(reason.contains("v2.0.0") && reason.contains(&format!("protocol v{}", PROTOCOL_VERSION + 1)));
+
+    let mut no_codecs = receiver();
+    no_codecs.codecs.clear();
+    assert!(negotiate(&sender(), &no_codecs).unwrap_err().contains("no common codec"));
+
+    let mut encrypted = receiver();
+    encrypted.encrypted = true;
+    assert!(negotiate(&sender(), &encrypted).unwrap_err().contains("requires a passphrase"));
+    assert!(negotiate(&encrypted, &sender()).unwrap_err().contains("no passphrase set"));
+
+    let mut silent = receiver();
+    silent.channels = 0;
+    assert!(negotiate(&sender(), &silent).is_err());
+}
+
+#[test]
+fn handshake_follows_hello_and_ack() {
+    let ours = Handshake::default();
+    ours.set_local(sender());
+
+    let theirs = Handshake::default();
+    theirs.set_local(receiver());
+
+    assert_eq!(ours.state(), HandshakeState::Pending);
+    assert_eq!(theirs.remote_sample_rate(), None);
+
+    let ack = theirs.on_hello(sender()).unwrap();
+    assert_eq!(theirs.remote_sample_rate(), Some(48000.0));
+    assert!(ack.result.is_ok());
+
+    ours.on_hello_ack(ack);
+    assert_eq!(ours.agreed_format(), theirs.agreed_format());
+    assert!(ours.agreed_format().is_some());
+}
+
+#[test]
+fn refusals_stick() {
+    let ours = Handshake::default();
+    ours.set_local(sender());
+
+    let ack = ours.on_hello(sender()).unwrap();
+    assert!(ack.result.is_err());
+    assert!(matches!(ours.state(), HandshakeState::Refused(_)));
+
+    // A late agreement from the peer does not undo it
+    ours.on_hello(receiver());
+    ours.on_hello_ack(HelloAck { plugin_version: "1.0.0".to_owned(), result: Ok(negotiate(&sender(), &receiver()).unwrap()) });
+    assert!(matches!(ours.state(), HandshakeState::Refused(_)));
+
+    // And the peer refusing an agreed session does
+    let other = Handshake::default();
+    other.set_local(sender());
+    other.on_hello(receiver());
+    assert!(other.agreed_format().is_some());
+    other.on_hello_ack(HelloAck { plugin_version: "0.9.0".to_owned(), result: Err("no common codec".to_owned()) });
+    assert_eq!(other.state(), HandshakeState::Refused("peer (v0.9.0) refused: no common codec".to_owned()));
+    assert_eq!(other.agreed_format(), None);
+}
@@ -0,0 +1,77 @@
+mod common;
+
+use common::temp_dir;
+use shared::*;
+
+#[test]
+fn identity_survives_a_restart() {
+    let dir = temp_dir("identity");
+
+    let first = Identity::load_or_create(&dir).unwrap();
+    let second = Identity::load_or_create(&dir).unwrap();
+
+    assert!(first.fingerprint.starts_with("sha-256 "));
+    assert_eq!(first.fingerprint, second.fingerprint);
+
+    // Another installation gets another identity
+    let other_dir = temp_dir("identity-other");
+    assert_ne!(Identity::load_or_create(&other_dir).unwrap().fingerprint, first.fingerprint);
+
+    let _ = std::fs::remove_dir_all(dir);
+    let _ = std::fs::remove_dir_all(other_dir);
+}
+
+#[test]
+fn trusted_peers_are_remembered() {
+    let dir = temp_dir("trusted-peers");
+
+    let identity = Identity::load_or_create(&dir).unwrap();
+    assert_eq!(identity.trusted_peer("sha-256 AB:CD"), None);
+
+    identity.trust("Alex".to_owned(), "sha-256 ab:cd").unwrap();
+    identity.trust("Sam".to_owned(), "sha-256 12:34").unwrap();
+    identity.trust("Alex (studio)".to_owned(), "SHA-256 AB:CD").unwrap();
+
+    let reloaded = Identity::load_or_create(&dir).unwrap();
+    assert_eq!(reloaded.trusted_peers().len(), 2);
+    assert_eq!(reloaded.trusted_peer("sha-256 AB:CD").unwrap().name, "Alex (studio)");
+
+    reloaded.forget("sha-256 12:34").unwrap();
+    assert_eq!(Identity::load_or_create(&dir).unwrap().trusted_peer("sha-256 12:34"), None);
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn an_unreadable_certificate_is_not_replaced() {
+    let dir = temp_dir("identity-unreadable");
+
+    // Not a file at all, so reading it fails with something other than "not found"
+    std::fs::create_dir_all(dir.join("identity.pem")).unwrap();
+    assert!(Identity::load_or_create(&dir).is_err());
+    assert!(dir.join("identity.pem").is_dir());
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn instances_sharing_the_list_keep_each_others_peers() {
+    let dir = temp_dir("trusted-peers-shared");
+
+    let first = Identity::load_or_create(&dir).unwrap();
+    let second = Identity::load_or_create(&dir).unwrap();
+
+    first.trust("Alex".to_owned(), "sha-256 ab:cd").unwrap();
+    second.trust("Sam".to_owned(), "sha-256 12:34").unwrap();
+    assert_eq!(second.trusted_peers().len(), 2);
+
+    first.forget("sha-256 ab:cd").unwrap();
+    assert_eq!(Identity::load_or_create(&dir).unwrap().trusted_peers(), vec![TrustedPeer { name: "Sam".to_owned(), fingerprint: "sha-256 12:34".to_owned() }]);
+
+    // A list that cannot be read is not overwritten
+    std::fs::write(dir.join("trusted_peers.json"), "not json").unwrap();
+    assert!(first.trust("Alex".to_owned(), "sha-256 ab:cd").is_err());
+    assert_eq!(std::fs::read_to_string(dir.join("trusted_peers.json")).unwrap(), "not json");
+
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -0,0 +1,97 @@
+use std::time::Duration;
+
+use shared::*;
+
+fn run(config: &ImpairmentConfig, packets: usize) -> Vec<Vec<Duration>> {
+    let mut impairment = Impairment::new(config.clone());
+
+    (0..packets)
+        .map(|i| impairment.schedule(1024, Duration::from_millis(i as u64 * 5)))
+        .collect()
+}
+
+#[test]
+fn default_config_passes_everything_through() {
+    let fates = run(&ImpairmentConfig::default(), 1000);
+
+    assert!(fates.iter().all(|delays| delays == &[Duration::ZERO]));
+}
+
+#[test]
+fn same_seed_gives_same_fates() {
+    let config: ImpairmentConfig = "loss=10,burst=50,burst_length=4,delay=20,jitter=15,reorder=5,reorder_delay=30,dup=3,seed=42".parse().unwrap();
+
+    assert_eq!(run(&config, 5000), run(&config, 5000));
+
+    let other_seed = ImpairmentConfig { seed: 43, ..config.clone() };
+    assert_ne!(run(&config, 5000), run(&other_seed, 5000));
+}
+
+#[test]
+fn loss_rate_matches_config() {
+    let config = ImpairmentConfig { loss_percent: 10.0, seed: 1, ..Default::default() };
+    let lost = run(&config, 20_000).iter().filter(|delays| delays.is_empty()).count();
+
+    assert!((1_600..2_400).contains(&lost), "lost {lost} of 20000 packets");
+}
+
+#[test]
+fn bursts_drop_consecutive_packets() {
+    let config = ImpairmentConfig { loss_percent: 1.0, burst_percent: 100.0, burst_length: 8, seed: 9, ..Default::default() };
+    let fates = run(&config, 20_000);
+
+    // Every loss starts a burst, so losses come in runs of exactly burst_length (except when bursts touch)
+    let mut run_length = 0;
+    for delays in &fates {
+        if delays.is_empty() {
+            run_length += 1;
+        } else if run_length > 0 {
+            assert_eq!(run_length % 8, 0);
+            run_length = 0;
+        }
+    }
+}
+
+#[test]
+fn delay_and_jitter_stay_in_range() {
+    let config = ImpairmentConfig { delay_ms: 40, jitter_ms: 10, seed: 5, ..Default::default() };
+
+    for delays in run(&config, 1000) {
+        assert_eq!(delays.len(), 1);
+        assert!((Duration::from_millis(40)..=Duration::from_millis(50)).contains(&delays[0]));
+    }
+}
+
+#[test]
+fn duplicates_and_reordering_happen_at_configured_rate() {
+    let config = ImpairmentConfig { duplicate_percent: 5.0, reorder_percent: 5.0, reorder_delay_ms: 100, seed: 11, ..Default::default() };
+    let fates = run(&config, 20_000);
+
+    let duplicated = fates.iter().filter(|delays| delays.len() == 2).count();
+    let reordered = fates.iter().flatten().filter(|delay| **delay == Duration::from_millis(100)).count();
+
+    assert!((700..1_300).contains(&duplicated), "duplicated {duplicated} of 20000 packets");
+    assert!((700..1_300).contains(&reordered), "held back {reordered} of 20000 packets");
+}
+
+#[test]
+fn bandwidth_cap_queues_then_drops() {
+    // 1024 byte packets every 5 ms is ~1.6 Mbit/s, four times what the link allows
+    let config = ImpairmentConfig { bandwidth_kbps: 400, ..Default::default() };
+    let fates = run(&config, 2000);
+
+    let delivered: Vec<Duration> = fates.iter().flatten().copied().collect();
+
+    assert!(delivered.windows(2).take(20).all(|pair| pair[1] > pair[0]), "queueing delay should grow while the link is saturated");
+    assert!(delivered.iter().all(|delay| *delay <= Duration::from_millis(1100)));
+    assert!(fates.iter().any(|delays| delays.is_empty()), "a saturated link should eventually drop packets");
+}
+
+#[test]
+fn parses_env_style_config() {
+    let config: ImpairmentConfig = "loss=2.5, delay=40, kbps=512, seed=7".parse().unwrap();
+
+    assert_eq!(config, ImpairmentConfig { loss_percent: 2.5, delay_ms: 40, bandwidth_kbps: 512, seed: 7, ..Default::default() });
+    assert!("loss".parse::<ImpairmentConfig>().is_err());
+    assert!("speed=1".parse::<ImpairmentConfig>().is_err());
+}
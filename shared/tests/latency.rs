@@ -0,0 +1,111 @@
+use shared::*;
+
+const MS: u64 = 1_000_000;
+
+// Builds the pong a peer with a clock `offset` ahead of ours would send back, given the one-way delays
+fn pong(seq: u32, sent_ns: u64, offset: u64, forward_ms: u64, processing_ms: u64) -> Pong {
+    Pong {
+        seq,
+        ping_sent_ns: sent_ns,
+        ping_received_ns: sent_ns + forward_ms * MS + offset,
+        sent_ns: sent_ns + (forward_ms + processing_ms) * MS + offset,
+        delay_ns: 15 * MS,
+    }
+}
+
+#[test]
+fn estimates_rtt_and_clock_offset() {
+    let tracker = LatencyTracker::default();
+    let offset = 5_000 * MS;
+
+    // 20 ms each way, with 1 ms spent on the remote side that must not count towards the RTT
+    let t1 = 1_000 * MS;
+    tracker.on_pong(pong(0, t1, offset, 20, 1), t1 + 41 * MS);
+
+    let estimate = tracker.estimate();
+    assert_eq!(estimate.rtt_ms, 40.0);
+    assert_eq!(estimate.smoothed_rtt_ms, 40.0);
+    assert_eq!(estimate.clock_offset_ms, 5_000.0);
+    assert_eq!(estimate.one_way_ms, 20.0);
+    assert_eq!(estimate.samples, 1);
+    assert_eq!(tracker.smoothed_rtt_ns(), 40 * MS);
+}
+
+#[test]
+fn asymmetric_spikes_do_not_move_the_offset() {
+    let tracker = LatencyTracker::default();
+    let offset = 300 * MS;
+
+    tracker.on_pong(pong(0, 0, offset, 10, 0), 20 * MS);
+
+    // A 200 ms spike on the forward path skews this sample's offset by 100 ms, but its RTT is worse so it is ignored
+    tracker.on_pong(pong(1, 500 * MS, offset, 210, 0), 720 * MS);
+
+    let estimate = tracker.estimate();
+    assert_eq!(estimate.rtt_ms, 220.0);
+    assert_eq!(estimate.clock_offset_ms, 300.0);
+    assert!(estimate.jitter_ms > 0.0);
+    assert!(estimate.smoothed_rtt_ms > 20.0 && estimate.smoothed_rtt_ms < 220.0);
+}
+
+#[test]
+fn stale_pongs_are_ignored() {
+    let tracker = LatencyTracker::default();
+
+    tracker.on_pong(pong(5, 0, 0, 10, 0), 20 * MS);
+    tracker.on_pong(pong(4, 0, 0, 100, 0), 200 * MS);
+    tracker.on_pong(pong(5, 0, 0, 100, 0), 200 * MS);
+
+    let estimate = tracker.estimate();
+    assert_eq!(estimate.samples, 1);
+    assert_eq!(estimate.rtt_ms, 20.0);
+}
+
+#[test]
+fn reset_forgets_the_previous_connection() {
+    let tracker = LatencyTracker::default();
+
+    tracker.set_local_delay(960, 48_000.0);
+    tracker.on_pong(pong(5, 0, 0, 10, 0), 20 * MS);
+    tracker.reset();
+
+    assert_eq!(tracker.smoothed_rtt_ns(), 0);
+    assert_eq!(tracker.next_ping().seq, 0);
+
+    let estimate = tracker.estimate();
+    assert_eq!(estimate.samples, 0);
+    assert_eq!(estimate.mouth_to_ear_ms, 20.0);
+
+    // Sequence numbers start over too, the first pong of the new connection counts
+    tracker.on_pong(pong(0, 0, 0, 10, 0), 20 * MS);
+    assert_eq!(tracker.estimate().samples, 1);
+}
+
+#[test]
+fn mouth_to_ear_adds_both_sides_buffering() {
+    let tracker = LatencyTracker::default();
+
+    // 960 samples at 48 kHz is 20 ms
+    tracker.set_local_delay(960, 48_000.0);
+    tracker.on_pong(pong(0, 0, 0, 10, 0), 20 * MS);
+
+    let estimate = tracker.estimate();
+    assert_eq!(estimate.local_delay_ms, 20.0);
+    assert_eq!(estimate.remote_delay_ms, 15.0);
+    assert_eq!(estimate.mouth_to_ear_ms, 20.0 + 10.0 + 15.0);
+}
+
+#[test]
+fn messages_round_trip_through_bytes() {
+    let tracker = LatencyTracker::default();
+
+    let ping = tracker.next_ping();
+    assert_eq!(tracker.next_ping().seq, ping.seq + 1);
+    assert_eq!(ControlMessage::parse(&ControlMessage::Ping(ping).to_bytes()).ok(), Some(ControlMessage::Ping(ping)));
+
+    let pong = tracker.answer_ping(ping, monotonic_ns());
+    assert_eq!(ControlMessage::parse(&ControlMessage::Pong(pong).to_bytes()).ok(), Some(ControlMessage::Pong(pong)));
+
+    // The old 16 byte millisecond timestamps are not mistaken for pings
+    assert!(ControlMessage::parse(&0u128.to_le_bytes()).is_err());
+}
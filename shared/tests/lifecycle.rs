@@ -0,0 +1,14 @@
+use shared::*;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+
+#[test]
+fn only_failed_and_closed_connections_count_as_closed() {
+    assert_eq!(Lifecycle::from(RTCPeerConnectionState::New), Lifecycle::Created);
+    assert_eq!(Lifecycle::from(RTCPeerConnectionState::Connected), Lifecycle::Connected);
+
+    // A dropout may still recover
+    assert_eq!(Lifecycle::from(RTCPeerConnectionState::Disconnected), Lifecycle::Connecting);
+
+    assert_eq!(Lifecycle::from(RTCPeerConnectionState::Failed), Lifecycle::Closed);
+    assert_eq!(Lifecycle::from(RTCPeerConnectionState::Closed), Lifecycle::Closed);
+}
@@ -0,0 +1,456 @@
+use std::{sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, thread, time::{Duration, Instant}};
+
+use shared::*;
+use tokio::runtime::Runtime;
+use webrtc::{data_channel::data_channel_state::RTCDataChannelState, peer_connection::peer_connection_state::RTCPeerConnectionState};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// No STUN servers, so gathering finishes as soon as the host candidates are known
+fn loopback_config() -> IceConfig {
+    IceConfig {
+        stun_urls: Vec::new(),
+        include_loopback: true,
+        certificate: None,
+        trusted_fingerprints: Vec::new(),
+        passphrase: None,
+    }
+}
+
+fn with_passphrase(passphrase: &str) -> IceConfig {
+    IceConfig { passphrase: Some(passphrase.to_owned()), ..loopback_config() }
+}
+
+fn wait_until(mut condition: impl FnMut() -> bool) -> bool {
+    let started = Instant::now();
+
+    while started.elapsed() < TIMEOUT {
+        if condition() {
+            return true;
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    false
+}
+
+fn connect_pair(runtime: &Runtime) -> (WebRTCConnection, WebRTCConnection) {
+    connect_pair_with(runtime, &loopback_config(), &loopback_config())
+}
+
+fn connect_pair_with(runtime: &Runtime, offerer_config: &IceConfig, answerer_config: &IceConfig) -> (WebRTCConnection, WebRTCConnection) {
+    let offerer = create_offerer_with_config(runtime, offerer_config).unwrap();
+    let answerer = create_answerer_with_config(runtime, answerer_config, offerer.connect_info.clone()).unwrap();
+
+    offerer.set_answer(runtime, answerer.connect_info.clone()).unwrap();
+
+    assert!(wait_until(|| {
+        offerer.peer.connection_state() == RTCPeerConnectionState::Connected
+            && answerer.peer.connection_state() == RTCPeerConnectionState::Connected
+    }), "peers never reached the connected state");
+
+    assert!(wait_until(|| {
+        [&offerer.channel, &offerer.tcp_channel, &answerer.channel, &answerer.tcp_channel]
+            .iter()
+            .all(|channel| channel.ready_state() == RTCDataChannelState::Open)
+    }), "data channels never opened");
+
+    (offerer, answerer)
+}
+
+fn test_block(index: usize) -> Vec<f32> {
+    let mut block: Vec<f32> = (0..256).map(|i| ((index * 256 + i) as f32 * 0.001).sin()).collect();
+
+    // Values that would not survive any lossy conversion along the way
+    block[0] = index as f32;
+    block[1] = -0.0;
+    block[2] = f32::MIN_POSITIVE / 2.0;
+    block[3] = f32::MAX;
+
+    block
+}
+
+fn to_bytes(samples: &[f32]) -> Vec<u8> {
+    samples.iter().flat_map(|f| f.to_le_bytes()).collect()
+}
+
+#[test]
+fn connects_over_loopback_without_stun() {
+    let runtime = Runtime::new().unwrap();
+    let (offerer, answerer) = connect_pair(&runtime);
+
+    runtime.block_on(async {
+        let _ = offerer.peer.close().await;
+        let _ = answerer.peer.close().await;
+    });
+}
+
+#[test]
+fn sessions_only_take_one_answer() {
+    let runtime = Runtime::new().unwrap();
+    let (offerer, answerer) = connect_pair(&runtime);
+
+    let err = offerer.set_answer(&runtime, answerer.connect_info.clone()).unwrap_err();
+    assert!(err.to_string().contains("already answered"));
+
+    runtime.block_on(async {
+        let _ = offerer.peer.close().await;
+        let _ = answerer.peer.close().await;
+    });
+}
+
+#[test]
+fn answers_from_unlisted_certificates_are_refused() {
+    let runtime = Runtime::new().unwrap();
+
+    let offerer = create_offerer_with_config(&runtime, &loopback_config()).unwrap();
+    offerer.session.set_allowed_fingerprints(vec!["sha-256 00:11:22".to_owned()]);
+
+    let answerer = create_answerer_with_config(&runtime, &loopback_config(), offerer.connect_info.clone()).unwrap();
+    let err = offerer.set_answer(&runtime, answerer.connect_info.clone()).unwrap_err();
+    assert!(err.to_string().contains("not on the allow-list"));
+
+    // The rejected answer did not use up the session
+    let answer_sdp = runtime.block_on(answerer.peer.local_description()).unwrap().sdp;
+    offerer.session.set_allowed_fingerprints(sdp_fingerprints(&answer_sdp));
+    offerer.set_answer(&runtime, answerer.connect_info.clone()).unwrap();
+
+    runtime.block_on(async {
+        let _ = offerer.peer.close().await;
+        let _ = answerer.peer.close().await;
+    });
+}
+
+#[test]
+fn audio_blocks_arrive_bit_exact() {
+    let runtime = Runtime::new().unwrap();
+    let (offerer, answerer) = connect_pair(&runtime);
+
+    let received: Arc<Mutex<Vec<Vec<u8>>>> = Default::default();
+
+    let received_clone = received.clone();
+    answerer.channel.on_message(Box::new(move |msg| {
+        received_clone.lock().unwrap().push(msg.data.to_vec());
+        Box::pin(async {})
+    }));
+
+    let blocks: Vec<Vec<u8>> = (0..32).map(|i| to_bytes(&test_block(i))).collect();
+
+    for block in &blocks {
+        offerer.send_blocking(&runtime, block).unwrap();
+    }
+
+    assert!(wait_until(|| received.lock().unwrap().len() >= blocks.len()), "not every block arrived");
+
+    // The audio channel is unordered, so compare the blocks by their index sample instead of arrival order
+    let mut received = received.lock().unwrap().clone();
+    received.sort_by_key(|block| f32::from_le_bytes(block[0..4].try_into().unwrap()) as usize);
+
+    assert_eq!(received, blocks);
+
+    runtime.block_on(async {
+        let _ = offerer.peer.close().await;
+        let _ = answerer.peer.close().await;
+    });
+}
+
+#[test]
+fn impaired_link_delivers_what_the_schedule_predicts() {
+    let runtime = Runtime::new().unwrap();
+    let (offerer, answerer) = connect_pair(&runtime);
+
+    let config = ImpairmentConfig { loss_percent: 20.0, duplicate_percent: 10.0, jitter_ms: 20, seed: 3, ..Default::default() };
+    let offerer = offerer.with_impairment(config.clone());
+
+    let received = Arc::new(AtomicUsize::new(0));
+
+    let received_clone = received.clone();
+    answerer.channel.on_message(Box::new(move |_| {
+        received_clone.fetch_add(1, Ordering::Relaxed);
+        Box::pin(async {})
+    }));
+
+    let blocks: Vec<Vec<u8>> = (0..200).map(|i| to_bytes(&test_block(i))).collect();
+
+    // Without a bandwidth cap the send time does not matter, so a fresh simulator predicts every packet's fate
+    let mut expected = Impairment::new(config);
+    let expected: usize = blocks.iter().map(|block| expected.schedule(block.len(), Duration::ZERO).len()).sum();
+
+    for block in &blocks {
+        offerer.send_blocking(&runtime, block).unwrap();
+    }
+
+    assert!(wait_until(|| received.load(Ordering::Relaxed) >= expected));
+
+    // Give stragglers a chance to show up before checking nothing extra arrived
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(received.load(Ordering::Relaxed), expected);
+
+    runtime.block_on(async {
+        let _ = offerer.peer.close().await;
+        let _ = answerer.peer.close().await;
+    });
+}
+
+#[test]
+fn control_channel_carries_pings_transport_and_chat() {
+    let runtime = Runtime::new().unwrap();
+    let (offerer, answerer) = connect_pair(&runtime);
+
+    let offerer_latency = Arc::new(LatencyTracker::default());
+    let answerer_latency = Arc::new(LatencyTracker::default());
+
+    // 480 samples at 48 kHz is 10 ms of buffering on the answering side
+    answerer_latency.set_local_delay(480, 48000.0);
+
+    let transports: Arc<Mutex<Vec<TransportSnapshot>>> = Default::default();
+    let transports_clone = transports.clone();
+
+    let offerer_chat: Arc<Mutex<ChatHistory>> = Default::default();
+    let answerer_chat: Arc<Mutex<ChatHistory>> = Default::default();
+
+    let offerer_chat_clone = offerer_chat.clone();
+    offerer.start_control(&runtime, offerer_latency.clone(), Hello::new("1.0.0", true, 48000.0, 1), move |message| offerer_chat_clone.lock().unwrap().on_control(message));
+
+    let answerer_chat_clone = answerer_chat.clone();
+    answerer.start_control(&runtime, answerer_latency.clone(), Hello::new("1.0.0", false, 48000.0, 2), move |message| {
+        if let ControlMessage::Transport(snapshot) = message {
+            transports_clone.lock().unwrap().push(snapshot);
+            return None;
+        }
+
+        answerer_chat_clone.lock().unwrap().on_control(message)
+    });
+
+    // Both sides land on the same format: the sender's sample rate and its single channel
+    let expected = HandshakeState::Agreed(AudioFormat { codec: Codec::PcmF32, sample_rate: 48000.0, channels: 1, frame_size: MAX_FRAME_SIZE, streams: 1 });
+    assert!(wait_until(|| offerer.handshake.state() == expected && answerer.handshake.state() == expected), "handshake never completed");
+
+    assert!(wait_until(|| offerer_latency.estimate().samples >= 3 && answerer_latency.estimate().samples >= 3), "latency ping never came back");
+
+    let estimate = offerer_latency.estimate();
+
+    // Loopback round trips should be well under a second, and both peers share one clock
+    assert!((0.0..1000.0).contains(&estimate.smoothed_rtt_ms));
+    assert!(estimate.clock_offset_ms.abs() < 50.0);
+    assert!((estimate.remote_delay_ms - 10.0).abs() < 0.001);
+    assert!(estimate.mouth_to_ear_ms >= 10.0);
+
+    // Other control messages share the channel with the pings and reach the handler
+    let snapshot = TransportSnapshot { playing: true, tempo: Some(128.0), pos_samples: Some(48000), ..Default::default() };
+    runtime.block_on(offerer.send_control(ControlMessage::Transport(snapshot)));
+
+    assert!(wait_until(|| transports.lock().unwrap().first() == Some(&snapshot)), "transport snapshot never arrived");
+
+    // Chat is acknowledged by the other side
+    let message = offerer_chat.lock().unwrap().push_outgoing(ChatBody::Text("hello".to_owned()));
+    runtime.block_on(offerer.send_control(ControlMessage::Chat(message)));
+
+    assert!(wait_until(|| offerer_chat.lock().unwrap().entries[0].acknowledged), "chat message was never acknowledged");
+    assert_eq!(answerer_chat.lock().unwrap().entries[0].body, ChatBody::Text("hello".to_owned()));
+
+    runtime.block_on(async {
+        let _ = offerer.peer.close().await;
+        let _ = answerer.peer.close().await;
+    });
+}
+
+#[test]
+fn incompatible_peers_are_refused_on_both_sides() {
+    let runtime = Runtime::new().unwrap();
+    let (offerer, answerer) = connect_pair(&runtime);
+
+    let latency = Arc::new(LatencyTracker::default());
+
+    offerer.start_control(&runtime, latency.clone(), Hello::new("1.0.0", true, 48000.0, 1), |_| None);
+    answerer.start_control(&runtime, latency.clone(), Hello::new("1.1.0", true, 44100.0, 1), |_| None);
+
+    let refused = |connection: &WebRTCConnection| matches!(connection.handshake.state(), HandshakeState::Refused(reason) if reason.contains("both peers are senders"));
+    assert!(wait_until(|| refused(&offerer) && refused(&answerer)), "two senders were not refused");
+
+    runtime.block_on(async {
+        let _ = offerer.peer.close().await;
+        let _ = answerer.peer.close().await;
+    });
+}
+
+#[test]
+fn passphrase_protects_audio_end_to_end() {
+    let runtime = Runtime::new().unwrap();
+    let (offerer, answerer) = connect_pair_with(&runtime, &with_passphrase("correct horse"), &with_passphrase("correct horse"));
+
+    let received: Arc<Mutex<Vec<Vec<u8>>>> = Default::default();
+
+    let received_clone = received.clone();
+    let encryption = answerer.encryption.clone();
+    answerer.channel.on_message(Box::new(move |msg| {
+        if let Some(data) = encryption.open(msg.data) {
+            received_clone.lock().unwrap().push(data.to_vec());
+        }
+        Box::pin(async {})
+    }));
+
+    let latency = Arc::new(LatencyTracker::default());
+    offerer.start_control(&runtime, latency.clone(), Hello::new("1.0.0", true, 48000.0, 1), |_| None);
+    answerer.start_control(&runtime, latency.clone(), Hello::new("1.0.0", false, 48000.0, 2), |_| None);
+
+    assert!(wait_until(|| offerer.encryption.state() == EncryptionState::Verified && answerer.encryption.state() == EncryptionState::Verified), "passphrase was never verified");
+    assert!(wait_until(|| offerer.handshake.agreed_format().is_some()), "handshake never completed");
+
+    let block = to_bytes(&test_block(0));
+    offerer.send_blocking(&runtime, &block).unwrap();
+
+    assert!(wait_until(|| !received.lock().unwrap().is_empty()), "sealed block never arrived");
+    assert_eq!(received.lock().unwrap()[0], block);
+
+    runtime.block_on(async {
+        let _ = offerer.peer.close().await;
+        let _ = answerer.peer.close().await;
+    });
+}
+
+#[test]
+fn wrong_passphrase_refuses_the_offer() {
+    let runtime = Runtime::new().unwrap();
+    let offerer = create_offerer_with_config(&runtime, &with_passphrase("correct horse")).unwrap();
+
+    // The offer is signed, so the answering side finds out before it sets anything up
+    let wrong = create_answerer_with_config(&runtime, &with_passphrase("battery staple"), offerer.connect_info.clone());
+    assert!(wrong.err().unwrap().downcast_ref::<TokenError>().is_some_and(|err| matches!(err, TokenError::BadSignature)));
+
+    let missing = create_answerer_with_config(&runtime, &loopback_config(), offerer.connect_info.clone());
+    assert!(missing.err().unwrap().downcast_ref::<TokenError>().is_some_and(|err| matches!(err, TokenError::PassphraseRequired)));
+
+    runtime.block_on(async {
+        let _ = offerer.peer.close().await;
+    });
+}
+
+#[test]
+fn persistent_identity_shows_up_in_the_peer_sdp() {
+    let runtime = Runtime::new().unwrap();
+
+    let dir = std::env::temp_dir().join(format!("live-collab-loopback-identity-{}", std::process::id()));
+    let identity = Identity::load_or_create(&dir).unwrap();
+
+    let config = IceConfig { certificate: Some(identity.certificate.clone()), ..loopback_config() };
+    let offerer = create_offerer_with_config(&runtime, &config).unwrap();
+    let answerer = create_answerer_with_config(&runtime, &loopback_config(), offerer.connect_info.clone()).unwrap();
+
+    // The answering side sees our long-lived fingerprint, and we see its fresh one once the answer is in
+    assert_eq!(*answerer.remote_fingerprints.lock().unwrap(), vec![identity.fingerprint.clone()]);
+
+    offerer.set_answer(&runtime, answerer.connect_info.clone()).unwrap();
+    assert_eq!(offerer.remote_fingerprints.lock().unwrap().len(), 1);
+    assert_ne!(offerer.remote_fingerprints.lock().unwrap()[0], identity.fingerprint);
+
+    runtime.block_on(async {
+        let _ = offerer.peer.close().await;
+        let _ = answerer.peer.close().await;
+    });
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn connection_slot_tracks_the_lifecycle_and_says_goodbye() {
+    let runtime = Runtime::new().unwrap();
+    let slot = ConnectionSlot::default();
+    assert_eq!(slot.lifecycle(), Lifecycle::Closed);
+
+    let offerer = create_offerer_with_config(&runtime, &loopback_config()).unwrap();
+    slot.replace(&runtime, offerer.clone());
+    assert_eq!(slot.lifecycle(), Lifecycle::Created);
+
+    let answerer = create_answerer_with_config(&runtime, &loopback_config(), offerer.connect_info.clone()).unwrap();
+    offerer.set_answer(&runtime, answerer.connect_info.clone()).unwrap();
+
+    assert!(wait_until(|| slot.lifecycle() == Lifecycle::Connected && answerer.lifecycle() == Lifecycle::Connected), "peers never connected");
+    assert!(wait_until(|| offerer.tcp_channel.ready_state() == RTCDataChannelState::Open && answerer.tcp_channel.ready_state() == RTCDataChannelState::Open), "control channel never opened");
+
+    let goodbye: Arc<Mutex<Option<String>>> = Default::default();
+    let goodbye_clone = goodbye.clone();
+
+    let latency = Arc::new(LatencyTracker::default());
+    offerer.start_control(&runtime, latency.clone(), Hello::new("1.0.0", true, 48000.0, 1), |_| None);
+    answerer.start_control(&runtime, latency.clone(), Hello::new("1.0.0", false, 48000.0, 2), move |message| {
+        if let ControlMessage::Bye { reason } = message {
+            *goodbye_clone.lock().unwrap() = Some(reason);
+        }
+        None
+    });
+
+    slot.close(&runtime, "sender disconnected");
+    assert!(slot.get().is_none());
+
+    assert!(wait_until(|| goodbye.lock().unwrap().as_deref() == Some("sender disconnected")), "peer was never told");
+    assert!(wait_until(|| offerer.lifecycle() == Lifecycle::Closed), "connection stayed open");
+
+    runtime.block_on(async {
+        let _ = answerer.peer.close().await;
+    });
+}
+
+#[test]
+fn stats_report_the_selected_candidate_pair() {
+    let runtime = Runtime::new().unwrap();
+    let (offerer, answerer) = connect_pair(&runtime);
+
+    for index in 0..10 {
+        offerer.send_blocking(&runtime, &to_bytes(&test_block(index))).unwrap();
+    }
+
+    let history: Arc<Mutex<StatsHistory>> = Default::default();
+    answerer.start_stats(&runtime, Duration::from_millis(50), Arc::new(LatencyTracker::default()), history.clone());
+
+    assert!(wait_until(|| history.lock().unwrap().latest.bytes_received > 10 * 1024), "stats never showed the traffic");
+
+    // Loopback never needs STUN or TURN
+    let latest = history.lock().unwrap().latest.clone();
+    assert_eq!(latest.candidate_pair, Some(("host".to_owned(), "host".to_owned())));
+    assert!(latest.packets_received > 0);
+
+    runtime.block_on(async {
+        let _ = offerer.peer.close().await;
+        let _ = answerer.peer.close().await;
+    });
+}
+
+#[test]
+fn trusted_peers_are_confirmed_without_asking() {
+    let runtime = Runtime::new().unwrap();
+
+    let dir = std::env::temp_dir().join(format!("live-collab-loopback-trusted-{}", std::process::id()));
+    let offerer_identity = Identity::load_or_create(&dir.join("offerer")).unwrap();
+    let answerer_identity = Identity::load_or_create(&dir.join("answerer")).unwrap();
+
+    // The answering side trusts the offerer, the offering side has never seen the answerer
+    let offerer_config = IceConfig { certificate: Some(offerer_identity.certificate.clone()), ..loopback_config() };
+    let answerer_config = IceConfig {
+        certificate: Some(answerer_identity.certificate.clone()),
+        trusted_fingerprints: vec![offerer_identity.fingerprint.clone()],
+        ..loopback_config()
+    };
+
+    let offerer = create_offerer_with_config(&runtime, &offerer_config).unwrap();
+    let answerer = create_answerer_with_config(&runtime, &answerer_config, offerer.connect_info.clone()).unwrap();
+    assert!(answerer.peer_confirmed.load(Ordering::Relaxed));
+
+    offerer.set_answer(&runtime, answerer.connect_info.clone()).unwrap();
+    assert!(!offerer.peer_confirmed.load(Ordering::Relaxed));
+
+    // Trusted the other way round as well
+    let offerer_config = IceConfig { trusted_fingerprints: vec![answerer_identity.fingerprint.clone()], ..offerer_config };
+    let offerer = create_offerer_with_config(&runtime, &offerer_config).unwrap();
+    let answerer = create_answerer_with_config(&runtime, &answerer_config, offerer.connect_info.clone()).unwrap();
+    offerer.set_answer(&runtime, answerer.connect_info.clone()).unwrap();
+    assert!(offerer.peer_confirmed.load(Ordering::Relaxed));
+
+    runtime.block_on(async {
+        let _ = offerer.peer.close().await;
+        let _ = answerer.peer.close().await;
+    });
+
+    let _ = std::fs::remove_dir_all(dir);
+}
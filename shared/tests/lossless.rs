@@ -0,0 +1,125 @@
+use shared::*;
+
+// Values a `bits` wide integer stream can hold exactly: a sine, noise, silence and both ends of the range
+fn exact_samples(bits: u32, frames: usize) -> Vec<f32> {
+    let scale = (1i64 << (bits - 1)) as f64;
+    let mut noise = 12345u32;
+
+    (0..frames)
+        .map(|i| {
+            noise = noise.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let int = match i * 5 / frames {
+                0 => ((i as f64 * 0.05).sin() * scale * 0.8).round() as i64,
+                1 => (noise >> (33 - bits)) as i64 - (scale as i64 / 2),
+                2 => 0,
+                3 => if i % 2 == 0 { -(scale as i64) } else { scale as i64 - 1 },
+                _ => ((i as f64 * 0.003).sin() * scale * 0.01).round() as i64,
+            };
+            (int as f64 / scale) as f32
+        })
+        .collect()
+}
+
+fn round_trip(codec: Codec, dither: Dither, samples: &[f32], streams: usize) -> (Vec<f32>, usize) {
+    let mut buf = bytes::BytesMut::new();
+    AudioEncoder::new(codec, dither).encode(samples, streams, &mut buf);
+    (decode_samples(codec, &buf, streams).unwrap(), buf.len())
+}
+
+#[test]
+fn integer_codecs_are_bit_exact() {
+    for (codec, bits) in [(Codec::LosslessI16, 16), (Codec::LosslessI24, 24)] {
+        for frames in [0, 1, 7, 480, 2048] {
+            let samples = exact_samples(bits, frames);
+            let (decoded, _) = round_trip(codec, Dither::None, &samples, 1);
+
+            assert_eq!(decoded.iter().map(|s| s.to_bits()).collect::<Vec<_>>(), samples.iter().map(|s| s.to_bits()).collect::<Vec<_>>(), "{codec} with {frames} frames");
+        }
+    }
+}
+
+#[test]
+fn float_codec_keeps_every_bit() {
+    let mut samples: Vec<f32> = (0..1024).map(|i| (i as f32 * 0.013).sin() * 0.7 + (i as f32 * 0.31).cos() * 1e-3).collect();
+    samples.extend([0.0, -0.0, f32::MIN_POSITIVE / 4.0, -f32::MIN_POSITIVE, f32::INFINITY, f32::NEG_INFINITY, f32::MAX, f32::MIN, 1.5, -3.0]);
+
+    let (decoded, _) = round_trip(Codec::LosslessF32, Dither::None, &samples, 1);
+
+    assert_eq!(decoded.iter().map(|s| s.to_bits()).collect::<Vec<_>>(), samples.iter().map(|s| s.to_bits()).collect::<Vec<_>>());
+}
+
+#[test]
+fn stems_come_back_in_their_places() {
+    // Four streams interleaved, each with a signal of its own
+    let samples: Vec<f32> = (0..512 * 4)
+        .map(|i| {
+            let (frame, stream) = (i / 4, i % 4);
+            ((frame as f64 * 0.01 * (stream + 1) as f64).sin() * 0.5 * 32768.0).round() as f32 / 32768.0
+        })
+        .collect();
+
+    for codec in [Codec::LosslessI16, Codec::LosslessI24, Codec::LosslessF32] {
+        let (decoded, _) = round_trip(codec, Dither::None, &samples, 4);
+        assert_eq!(decoded, samples, "{codec}");
+    }
+}
+
+#[test]
+fn predictable_audio_compresses() {
+    let samples = exact_samples(24, 2048)[..2048 / 5].to_vec();
+    let (_, bytes) = round_trip(Codec::LosslessI24, Dither::None, &samples, 1);
+    assert!(bytes < samples.len() * 3 / 2, "{bytes} bytes for {} samples", samples.len());
+
+    let sine: Vec<f32> = (0..2048).map(|i| (i as f32 * 0.02).sin() * 0.5).collect();
+    let (_, bytes) = round_trip(Codec::LosslessF32, Dither::None, &sine, 1);
+    assert!(bytes < sine.len() * 4);
+
+    let (_, bytes) = round_trip(Codec::LosslessI16, Dither::None, &[0.25; 2048], 1);
+    assert!(bytes < 8);
+}
+
+#[test]
+fn dither_stays_within_a_couple_of_steps() {
+    let samples: Vec<f32> = (0..4096).map(|i| (i as f32 * 0.007).sin() * 0.9).collect();
+
+    for dither in [Dither::None, Dither::Rectangular, Dither::Triangular] {
+        let (decoded, _) = round_trip(Codec::LosslessI16, dither, &samples, 1);
+
+        let worst = samples.iter().zip(&decoded).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max);
+        assert!(worst <= 1.5 / 32768.0, "{dither}: {worst}");
+
+        // Rounding alone is never off by more than half a step, dither has to be
+        if dither != Dither::None {
+            assert!(worst > 0.5 / 32768.0);
+        }
+    }
+}
+
+#[test]
+fn packets_carry_the_session_codec() {
+    let packet = AudioPacket { seq: 7, song_position: Some(480), midi: Vec::new(), samples: exact_samples(24, 960) };
+
+    let mut encoder = AudioEncoder::new(Codec::LosslessI24, Dither::None);
+    let bytes = packet.encode(&mut encoder, 2);
+
+    assert_eq!(AudioPacket::decode(&bytes, Codec::LosslessI24, 2), Some(packet));
+    assert!(bytes.len() < 960 * 4);
+
+    // Cut short anywhere, a packet is refused rather than played as garbage
+    for len in [0, 5, 14, 20, bytes.len() - 1] {
+        assert_eq!(AudioPacket::decode(&bytes[..len], Codec::LosslessI24, 2), None, "{len} bytes");
+    }
+}
+
+#[test]
+fn quantizing_and_encoding_apart_matches_encoding_at_once() {
+    let samples = exact_samples(16, 960);
+    let packet = AudioPacket { seq: 3, song_position: None, midi: Vec::new(), samples: samples.clone() };
+
+    for codec in [Codec::PcmF32, Codec::LosslessI16, Codec::LosslessF32] {
+        let quantized = AudioEncoder::new(codec, Dither::Triangular).quantize(&samples, 2);
+        let bytes = AudioPacket { samples: Vec::new(), ..packet.clone() }.encode_quantized(&quantized);
+
+        assert_eq!(bytes, packet.encode(&mut AudioEncoder::new(codec, Dither::Triangular), 2), "{codec}");
+    }
+}
@@ -0,0 +1,47 @@
+use shared::*;
+
+#[test]
+fn full_scale_sine_reads_0_db_peak_and_minus_3_db_rms() {
+    let meter = LevelMeter::default();
+    // Two seconds for the RMS to settle. 750 Hz fits a whole number of periods in every block, so each block has the same RMS
+    let sine: Vec<f32> = (0..96000).map(|i| (i as f32 * 2.0 * std::f32::consts::PI * 750.0 / 48000.0).sin()).collect();
+
+    for block in sine.chunks(512) {
+        meter.update(block, 48000.0);
+    }
+
+    assert!(meter.peak_db().abs() < 0.1);
+    assert!((meter.rms_db() + 3.01).abs() < 0.1);
+    assert!(meter.signal_present());
+}
+
+#[test]
+fn levels_fall_back_after_the_signal_stops() {
+    let meter = LevelMeter::default();
+    meter.update(&[1.0; 512], 48000.0);
+
+    // A second of silence
+    for _ in 0..94 {
+        meter.update(&[0.0; 512], 48000.0);
+    }
+
+    assert!(meter.peak_db() < -20.0);
+    assert!(meter.rms_db() < -20.0);
+}
+
+#[test]
+fn quiet_noise_is_not_signal() {
+    let meter = LevelMeter::default();
+    assert!(!meter.signal_present());
+    assert_eq!(meter.peak_db(), METER_FLOOR_DB);
+
+    meter.update(&[0.0001; 512], 48000.0);
+    assert!(!meter.signal_present());
+
+    meter.update(&[0.5; 512], 48000.0);
+    assert!(meter.signal_present());
+
+    meter.reset();
+    assert!(!meter.signal_present());
+    assert_eq!(meter.rms_db(), METER_FLOOR_DB);
+}
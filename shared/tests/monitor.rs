@@ -0,0 +1,56 @@
+use shared::*;
+
+#[test]
+fn delay_line_plays_back_late() {
+    let mut line = DelayLine::new(4);
+    assert_eq!(line.max_delay(), 4);
+
+    let output: Vec<f32> = (1..=6).map(|sample| line.process(sample as f32, 2)).collect();
+    assert_eq!(output, vec![0.0, 0.0, 1.0, 2.0, 3.0, 4.0]);
+
+    // No delay is the input itself, too much is the longest it can hold
+    assert_eq!(line.process(7.0, 0), 7.0);
+    assert_eq!(line.process(8.0, 100), 4.0);
+
+    line.clear();
+    assert_eq!(line.process(9.0, 1), 0.0);
+}
+
+#[test]
+fn monitor_delay_ignores_small_rtt_changes() {
+    // 40 ms at 48 kHz
+    assert_eq!(monitor_delay(40_000_000, 48000.0, 0), Some(1920));
+
+    // Within 5 ms of where it is
+    assert_eq!(monitor_delay(43_000_000, 48000.0, 1920), None);
+    assert_eq!(monitor_delay(50_000_000, 48000.0, 1920), Some(2400));
+}
+
+#[test]
+fn monitor_delay_crossfades_to_a_new_delay() {
+    let mut line = DelayLine::new(8);
+    let mut delay = MonitorDelay::new(4);
+    delay.reset(1);
+
+    for sample in 1..=4 {
+        let (from, to, fade) = delay.advance();
+        line.process_crossfade(sample as f32, from, to, fade);
+    }
+
+    // From one sample late to three, the read moves over four samples instead of jumping back
+    delay.set(3);
+    let output: Vec<f32> = (5..=9)
+        .map(|sample| {
+            let (from, to, fade) = delay.advance();
+            line.process_crossfade(sample as f32, from, to, fade)
+        })
+        .collect();
+    assert_eq!(output, vec![3.5, 4.0, 4.5, 5.0, 6.0]);
+
+    // Changes are held off during a crossfade, and picked up once it is over
+    delay.set(5);
+    assert_eq!(delay.delay(), 5);
+    delay.set(2);
+    assert_eq!(delay.delay(), 5);
+    assert!(delay.fading());
+}
@@ -0,0 +1,156 @@
+use shared::*;
+
+#[test]
+fn audio_packet_round_trips() {
+    let packet = AudioPacket {
+        seq: 7,
+        song_position: Some(-1024),
+        midi: Vec::new(),
+        samples: vec![0.5, -0.0, f32::MIN_POSITIVE, f32::MAX],
+    };
+
+    assert_eq!(AudioPacket::parse(&packet.to_bytes()), Some(packet));
+
+    let untagged = AudioPacket { seq: u32::MAX, song_position: None, midi: Vec::new(), samples: vec![1.0; 64] };
+    assert_eq!(AudioPacket::parse(&untagged.to_bytes()), Some(untagged));
+}
+
+#[test]
+fn truncated_audio_packets_are_rejected() {
+    let bytes = AudioPacket {
+        seq: 1,
+        song_position: Some(0),
+        midi: vec![MidiEvent { timing: 0, message: MidiMessage::ProgramChange { channel: 0, program: 5 } }],
+        samples: vec![1.0, 2.0],
+    }.to_bytes();
+
+    assert_eq!(AudioPacket::parse(&bytes[..3]), None);
+    assert_eq!(AudioPacket::parse(&bytes[..8]), None);
+    assert_eq!(AudioPacket::parse(&bytes[..20]), None);
+    assert_eq!(AudioPacket::parse(&bytes[..bytes.len() - 1]), None);
+}
+
+#[test]
+fn stems_are_interleaved_per_frame() {
+    let packet = AudioPacket { samples: vec![0.1, 0.2, 0.3, 1.1, 1.2, 1.3, 2.1], ..Default::default() };
+
+    // The last sample is only part of a frame
+    let frames: Vec<StreamFrame> = packet.frames(3).collect();
+    assert_eq!(frames, vec![[0.1, 0.2, 0.3, 0.0], [1.1, 1.2, 1.3, 0.0]]);
+
+    assert_eq!(packet.frames(1).count(), 7);
+    assert_eq!(packet.frames(0).count(), 7);
+}
+
+#[test]
+fn midi_rides_along_with_audio() {
+    let midi = vec![
+        MidiEvent { timing: 0, message: MidiMessage::NoteOn { channel: 0, note: 60, velocity: 0.8 } },
+        MidiEvent { timing: 12, message: MidiMessage::PolyPressure { channel: 0, note: 60, pressure: 0.25 } },
+        MidiEvent { timing: 40, message: MidiMessage::ControlChange { channel: 3, cc: 64, value: 1.0 } },
+        MidiEvent { timing: 63, message: MidiMessage::PitchBend { channel: 15, value: 0.5 } },
+        MidiEvent { timing: 63, message: MidiMessage::ChannelPressure { channel: 1, pressure: 0.1 } },
+        MidiEvent { timing: 64, message: MidiMessage::NoteOff { channel: 0, note: 60, velocity: 0.0 } },
+    ];
+
+    let packet = AudioPacket { seq: 2, song_position: None, midi, samples: vec![0.25; 64] };
+    assert_eq!(AudioPacket::parse(&packet.to_bytes()), Some(packet));
+}
+
+#[test]
+fn control_messages_round_trip() {
+    let messages = [
+        ControlMessage::Ping(Ping { seq: 3, sent_ns: 99 }),
+        ControlMessage::Pong(Pong { seq: 3, ping_sent_ns: 99, ping_received_ns: u64::MAX, sent_ns: 7, delay_ns: 0 }),
+        ControlMessage::Hello(Hello::new("0.1.0", true, 48000.0, 1)),
+        ControlMessage::HelloAck(HelloAck { plugin_version: "0.1.0".to_owned(), result: Err("no common codec".to_owned()) }),
+        ControlMessage::FormatChange(AudioFormat { codec: Codec::PcmF32, sample_rate: 44_100.0, channels: 1, frame_size: 512, streams: 1 }),
+        ControlMessage::Mute { muted: true },
+        ControlMessage::Stats(StatsReport {
+            buffered_ms: 42.5,
+            packets_received: 1_000_000,
+            packets_lost: 12,
+            jitter_ms: 3.25,
+            underruns: 2,
+            overruns: 1,
+            concealed_samples: 480,
+            signal_present: true,
+        }),
+        ControlMessage::Transport(TransportSnapshot {
+            playing: true,
+            tempo: Some(97.5),
+            time_signature: Some((7, 8)),
+            pos_samples: Some(123_456),
+            pos_beats: Some(12.25),
+            sample_rate: 48000.0,
+        }),
+        ControlMessage::Chat(ChatMessage { id: 4, body: ChatBody::Text("take 3 is the one".to_owned()) }),
+        ControlMessage::Chat(ChatMessage { id: 5, body: ChatBody::Marker { position_samples: 96_000, sample_rate: 48_000.0, text: "vocals come in".to_owned() } }),
+        ControlMessage::Ack { id: u64::MAX },
+        ControlMessage::Bye { reason: "session closed".to_owned() },
+    ];
+
+    for message in messages {
+        assert_eq!(ControlMessage::parse(&message.to_bytes()).ok(), Some(message));
+    }
+}
+
+#[test]
+fn control_frames_are_versioned() {
+    let mut bytes = ControlMessage::Mute { muted: false }.to_bytes().to_vec();
+    assert_eq!(bytes[0], PROTOCOL_VERSION);
+
+    bytes[0] = PROTOCOL_VERSION + 1;
+    assert!(matches!(ControlMessage::parse(&bytes), Err(ControlError::UnsupportedVersion(version)) if version == PROTOCOL_VERSION + 1));
+
+    assert!(matches!(ControlMessage::parse(&[]), Err(ControlError::Empty)));
+    assert!(matches!(ControlMessage::parse(&[PROTOCOL_VERSION, b'{']), Err(ControlError::Malformed(_))));
+}
+
+#[test]
+fn unknown_control_messages_are_tolerated() {
+    let mut bytes = vec![PROTOCOL_VERSION];
+    bytes.extend_from_slice(br#"{"type":"something_from_the_future","payload":[1,2,3]}"#);
+
+    assert_eq!(ControlMessage::parse(&bytes).ok(), Some(ControlMessage::Unknown));
+}
+
+#[test]
+fn bar_and_beat_follow_time_signature() {
+    let four_four = TransportSnapshot { pos_beats: Some(9.5), time_signature: Some((4, 4)), ..Default::default() };
+    assert_eq!(four_four.bar_and_beat(), Some((3, 2.5)));
+
+    // 6/8 counts eighth notes, so 4.5 quarter notes is 9 eighths: bar 2, beat 4
+    let six_eight = TransportSnapshot { pos_beats: Some(4.5), time_signature: Some((6, 8)), ..Default::default() };
+    assert_eq!(six_eight.bar_and_beat(), Some((2, 4.0)));
+
+    assert_eq!(TransportSnapshot::default().bar_and_beat(), None);
+}
+
+#[test]
+fn chat_history_tracks_acknowledgements() {
+    let mut ours = ChatHistory::default();
+    let mut theirs = ChatHistory::default();
+
+    let first = ours.push_outgoing(ChatBody::Text("first".to_owned()));
+    let second = ours.push_outgoing(ChatBody::Text("second".to_owned()));
+    assert_ne!(first.id, second.id);
+
+    let ack = theirs.on_control(ControlMessage::Chat(second));
+    assert_eq!(ack, Some(ControlMessage::Ack { id: 1 }));
+    assert_eq!(ours.on_control(ack.unwrap()), None);
+
+    assert!(!ours.entries[0].acknowledged);
+    assert!(ours.entries[1].acknowledged);
+    assert!(!theirs.entries[0].from_self);
+
+    // Acks only ever refer to our own messages
+    theirs.on_control(ControlMessage::Ack { id: 1 });
+    assert_eq!(theirs.entries.len(), 1);
+}
+
+#[test]
+fn markers_show_their_position() {
+    let marker = ChatBody::Marker { position_samples: 66_150, sample_rate: 44_100.0, text: "drop".to_owned() };
+    assert_eq!(marker.to_string(), "Marker at 1.50 s: drop");
+}
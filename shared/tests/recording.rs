@@ -0,0 +1,130 @@
+mod common;
+
+use std::{io::Cursor, time::{Duration, UNIX_EPOCH}};
+
+use common::temp_dir;
+use shared::*;
+
+fn stereo_stems(sample_rate: f32) -> AudioFormat {
+    AudioFormat { codec: Codec::PcmF32, sample_rate, channels: 1, frame_size: 4, streams: 2 }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+#[test]
+fn recordings_are_named_by_session_and_time() {
+    let started = UNIX_EPOCH + Duration::from_secs(1_792_332_202);
+
+    assert_eq!(recording_file_name("Alex & Sam", started, RecordingFormat::Flac), "Alex___Sam-2026-10-18_14-03-22.flac");
+    assert_eq!(recording_file_name(" ", UNIX_EPOCH, RecordingFormat::Wav), "session-1970-01-01_00-00-00.wav");
+}
+
+#[test]
+fn wav_recording_fills_lost_packets_with_marked_silence() {
+    let path = temp_dir("recording").join("take.wav");
+    let mut recording = Recording::start(&path, RecordingFormat::Wav, &stereo_stems(48000.0)).unwrap();
+
+    // Two frames of two stems per packet. 2 shows up after 3 but in time, 5 never does.
+    recording.write_packet(0, &[0.1, -0.1, 0.2, -0.2]);
+    recording.write_packet(1, &[0.3, -0.3, 0.4, -0.4]);
+    recording.write_packet(3, &[0.7, -0.7, 0.8, -0.8]);
+    recording.write_packet(2, &[0.5, -0.5, 0.6, -0.6]);
+    recording.write_packet(4, &[0.9, -0.9, 1.0, -1.0]);
+    recording.write_packet(6, &[0.25, -0.25, 0.5, -0.5]);
+
+    // Stopping writes out what was still waiting for 5
+    let summary = recording.stop().unwrap();
+    assert_eq!(summary.frames, 14);
+    assert_eq!(summary.gaps, vec![10]);
+
+    let bytes = std::fs::read(&path).unwrap();
+    assert_eq!(&bytes[..4], b"RIFF");
+    assert_eq!(u32_at(&bytes, 4) as usize, bytes.len() - 8);
+    // Float format, two channels, 48 kHz
+    assert_eq!(u32_at(&bytes, 20) & 0xFFFF, 3);
+    assert_eq!(u32_at(&bytes, 20) >> 16, 2);
+    assert_eq!(u32_at(&bytes, 24), 48000);
+    // Frames in the fact chunk, bytes of audio in the data chunk
+    assert_eq!(u32_at(&bytes, 46), 14);
+    assert_eq!(u32_at(&bytes, 54), 112);
+
+    let samples: Vec<f32> = bytes[58..58 + 112].chunks(4).map(|sample| f32::from_le_bytes(sample.try_into().unwrap())).collect();
+    assert_eq!(samples[..8], [0.1, -0.1, 0.2, -0.2, 0.3, -0.3, 0.4, -0.4]);
+    assert_eq!(samples[8..20], [0.5, -0.5, 0.6, -0.6, 0.7, -0.7, 0.8, -0.8, 0.9, -0.9, 1.0, -1.0]);
+    assert_eq!(samples[20..24], [0.0; 4]);
+    assert_eq!(samples[24..], [0.25, -0.25, 0.5, -0.5]);
+
+    // One cue point at the gap
+    assert_eq!(&bytes[170..174], b"cue ");
+    assert_eq!(u32_at(&bytes, 178), 1);
+    assert_eq!(u32_at(&bytes, 186), 10);
+
+    let labels = std::fs::read_to_string(path.with_file_name("take.wav.labels.txt")).unwrap();
+    assert_eq!(labels, "0.000208\t0.000208\tpacket loss\n");
+}
+
+#[test]
+fn flac_stream_info_is_completed_on_finish() {
+    let mut writer = FlacWriter::new(Cursor::new(Vec::new()), 44100, 2).unwrap();
+
+    // A bit more than two blocks of a quiet stereo sine, so the last frame is a short one
+    let samples: Vec<f32> = (0..FLAC_BLOCK_SIZE * 2 + 100)
+        .flat_map(|i| {
+            let sample = (i as f32 * 0.01).sin() * 0.25;
+            [sample, -sample]
+        })
+        .collect();
+    writer.write(&samples).unwrap();
+
+    let bytes = writer.finish().unwrap().into_inner();
+    assert_eq!(&bytes[..4], b"fLaC");
+
+    // Last metadata block, STREAMINFO, 34 bytes
+    assert_eq!(bytes[4..8], [0x80, 0, 0, 34]);
+    assert_eq!(u16::from_be_bytes([bytes[8], bytes[9]]) as usize, FLAC_BLOCK_SIZE);
+
+    // 20 bits of sample rate, 3 of channels - 1, 5 of bits per sample - 1, 36 of total samples
+    let packed = u64::from_be_bytes(bytes[18..26].try_into().unwrap());
+    assert_eq!(packed >> 44, 44100);
+    assert_eq!((packed >> 41) & 0b111, 1);
+    assert_eq!((packed >> 36) & 0b11111, 23);
+    assert_eq!(packed & 0xF_FFFF_FFFF, FLAC_BLOCK_SIZE as u64 * 2 + 100);
+
+    // First frame right after the header, predictable audio takes far less than 24 bits a sample
+    assert_eq!(bytes[42..44], [0xFF, 0xF8]);
+    assert!(bytes.len() < samples.len() * 3 / 2);
+}
+
+#[test]
+fn flac_recording_decodes_to_the_quantized_input() {
+    let path = temp_dir("recording-flac").join("take.flac");
+    let mut recording = Recording::start(&path, RecordingFormat::Flac, &stereo_stems(44100.0)).unwrap();
+
+    // A sine on one stem and noise on the other, so both predicted and verbatim subframes are written,
+    // plus a stretch of silence and samples beyond full scale
+    let mut noise = 1u32;
+    let samples: Vec<f32> = (0..FLAC_BLOCK_SIZE * 2 + 100)
+        .flat_map(|i| {
+            noise = noise.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let sine = if (1000..2000).contains(&i) { 0.0 } else { (i as f32 * 0.01).sin() * 0.5 };
+            [sine, (noise >> 8) as f32 / (1 << 23) as f32 * 2.5 - 1.25]
+        })
+        .collect();
+
+    for (seq, packet) in samples.chunks(256).enumerate() {
+        recording.write_packet(seq as u32, packet);
+    }
+    recording.stop().unwrap();
+
+    let mut reader = claxon::FlacReader::open(&path).unwrap();
+    assert_eq!(reader.streaminfo().sample_rate, 44100);
+    assert_eq!(reader.streaminfo().channels, 2);
+    assert_eq!(reader.streaminfo().samples, Some(samples.len() as u64 / 2));
+
+    let full_scale = ((1 << (FLAC_BITS_PER_SAMPLE - 1)) - 1) as f32;
+    let quantized: Vec<i32> = samples.iter().map(|sample| (sample.clamp(-1.0, 1.0) * full_scale).round() as i32).collect();
+    let decoded: Vec<i32> = reader.samples().map(Result::unwrap).collect();
+    assert_eq!(decoded, quantized);
+}
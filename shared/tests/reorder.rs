@@ -0,0 +1,66 @@
+use shared::*;
+
+fn push_all(buffer: &mut ReorderBuffer<u32>, seqs: &[u32]) -> Vec<Reordered<u32>> {
+    let mut out = Vec::new();
+    for &seq in seqs {
+        buffer.push(seq, seq, |item| out.push(item));
+    }
+    out
+}
+
+#[test]
+fn swapped_packets_come_out_in_order() {
+    let mut buffer = ReorderBuffer::new(4);
+    let out = push_all(&mut buffer, &[10, 12, 11, 13]);
+
+    assert_eq!(out, (10..=13).map(|seq| Reordered::Packet(seq, seq)).collect::<Vec<_>>());
+}
+
+#[test]
+fn missing_packets_are_given_up_after_the_window() {
+    let mut buffer = ReorderBuffer::new(2);
+
+    // 1 is held until 3 pushes the window past the missing 2
+    assert_eq!(push_all(&mut buffer, &[0, 2]), vec![Reordered::Packet(0, 0)]);
+    assert_eq!(push_all(&mut buffer, &[3]), vec![Reordered::Lost(1), Reordered::Packet(2, 2), Reordered::Packet(3, 3)]);
+
+    // Too late now
+    assert_eq!(push_all(&mut buffer, &[1]), vec![Reordered::Late(1, 1)]);
+}
+
+#[test]
+fn long_loss_is_one_gap() {
+    let mut buffer = ReorderBuffer::new(4);
+    let out = push_all(&mut buffer, &[0, 1000]);
+
+    assert_eq!(out, vec![Reordered::Packet(0, 0)]);
+
+    let mut flushed = Vec::new();
+    buffer.flush(|item| flushed.push(item));
+    assert_eq!(flushed, vec![Reordered::Lost(999), Reordered::Packet(1000, 1000)]);
+}
+
+#[test]
+fn sequence_numbers_wrap() {
+    let mut buffer = ReorderBuffer::new(4);
+    let out = push_all(&mut buffer, &[u32::MAX, 1, 0, 2]);
+
+    assert_eq!(out, vec![Reordered::Packet(u32::MAX, u32::MAX), Reordered::Packet(0, 0), Reordered::Packet(1, 1), Reordered::Packet(2, 2)]);
+}
+
+#[test]
+fn the_stream_starts_at_the_lowest_packet_of_the_first_window() {
+    let mut buffer = ReorderBuffer::new(4);
+
+    // Held while the window fills, 4 overtaking 5 is not late
+    assert_eq!(push_all(&mut buffer, &[5, 4, 6]), vec![]);
+    assert_eq!(push_all(&mut buffer, &[7]), (4..=7).map(|seq| Reordered::Packet(seq, seq)).collect::<Vec<_>>());
+
+    // A start further back than the window holds is too late after all
+    let mut buffer = ReorderBuffer::new(4);
+    assert_eq!(push_all(&mut buffer, &[10, 13, 9]), vec![Reordered::Late(9, 9)]);
+
+    let mut flushed = Vec::new();
+    buffer.flush(|item| flushed.push(item));
+    assert_eq!(flushed, vec![Reordered::Packet(10, 10), Reordered::Lost(2), Reordered::Packet(13, 13)]);
+}
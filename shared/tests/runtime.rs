@@ -0,0 +1,77 @@
+use std::{sync::{mpsc, Arc, Mutex}, time::{Duration, Instant}};
+
+use shared::*;
+use tokio::runtime::Runtime;
+
+// Every test here shares the one process-wide runtime, so they take turns
+static SERIAL: Mutex<()> = Mutex::new(());
+
+struct DropSignal(mpsc::Sender<()>);
+
+impl Drop for DropSignal {
+    fn drop(&mut self) {
+        let _ = self.0.send(());
+    }
+}
+
+#[test]
+fn instances_share_one_runtime_until_the_last_goes_away() {
+    let _serial = SERIAL.lock().unwrap();
+
+    let first = PluginRuntime::new().unwrap();
+    let second = PluginRuntime::new().unwrap();
+    assert!(std::ptr::eq::<Runtime>(&*first, &*second));
+    assert!(first.metrics().num_workers() <= MAX_WORKER_THREADS);
+
+    let (signal, dropped) = mpsc::channel();
+    first.spawn(async move {
+        let _signal = DropSignal(signal);
+        tokio::time::sleep(Duration::from_secs(3600)).await;
+    });
+
+    // Still running for the second instance
+    drop(first);
+    assert!(dropped.recv_timeout(Duration::from_millis(200)).is_err());
+
+    drop(second);
+    assert!(dropped.recv_timeout(Duration::from_secs(5)).is_ok());
+
+    // A later instance starts a fresh runtime
+    let third = PluginRuntime::new().unwrap();
+    assert_eq!(third.block_on(async { 1 + 1 }), 2);
+}
+
+#[test]
+fn stuck_tasks_do_not_hold_up_shutdown() {
+    let _serial = SERIAL.lock().unwrap();
+    let runtime = PluginRuntime::new().unwrap();
+
+    runtime.spawn(async { tokio::time::sleep(Duration::from_secs(3600)).await });
+    runtime.spawn_blocking(|| std::thread::sleep(Duration::from_secs(10)));
+
+    let started = Instant::now();
+    drop(runtime);
+
+    assert!(started.elapsed() < SHUTDOWN_TIMEOUT + Duration::from_secs(1));
+}
+
+#[test]
+fn last_reference_can_go_away_inside_a_task() {
+    let _serial = SERIAL.lock().unwrap();
+    let runtime = Arc::new(PluginRuntime::new().unwrap());
+    let (go, wait) = tokio::sync::oneshot::channel::<()>();
+    let (done, finished) = mpsc::channel();
+
+    let runtime_clone = runtime.clone();
+    runtime.spawn(async move {
+        let _ = wait.await;
+        drop(runtime_clone);
+        let _ = done.send(());
+    });
+
+    drop(runtime);
+    go.send(()).unwrap();
+
+    // Dropping a plain runtime here would panic the task before it reports back
+    assert!(finished.recv_timeout(Duration::from_secs(5)).is_ok());
+}
@@ -0,0 +1,70 @@
+use shared::*;
+
+#[test]
+fn session_config_survives_a_save_and_load() {
+    let mut config = SessionConfig::new(SessionRole::Receiver);
+    config.ice.stun_urls = vec!["stun:stun.example.com:3478".to_owned(), "".to_owned()];
+    config.ice.include_loopback = true;
+    config.peer_name = "Alex".to_owned();
+    config.frame_size = 256;
+
+    let restored: SessionConfig = serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+
+    assert_eq!(restored.role, SessionRole::Receiver);
+    assert_eq!(restored.ice.stun_urls, config.ice.stun_urls);
+    assert!(restored.ice.include_loopback);
+    assert_eq!(restored.peer_name, "Alex");
+    assert_eq!(restored.frame_size, 256);
+
+    // Blank lines from the editor are not handed to ICE
+    assert_eq!(restored.ice_config(None).stun_urls, vec!["stun:stun.example.com:3478".to_owned()]);
+}
+
+#[test]
+fn older_state_fills_in_defaults() {
+    // Room settings were dropped, projects saved with them still load
+    let restored: SessionConfig = serde_json::from_str(r#"{"role":"receiver","peer_name":"Sam","room_code":"blue-fox","auto_rejoin":true}"#).unwrap();
+
+    assert_eq!(restored.role, SessionRole::Receiver);
+    assert_eq!(restored.peer_name, "Sam");
+    assert_eq!(restored.frame_size, MAX_FRAME_SIZE);
+    assert_eq!(restored.ice.stun_urls, IceConfig::default().stun_urls);
+    assert_eq!(restored.recording_format, RecordingFormat::Wav);
+    assert_eq!(restored.dither, Dither::None);
+}
+
+#[test]
+fn recordings_go_to_the_chosen_folder() {
+    let mut config = SessionConfig::new(SessionRole::Receiver);
+    config.peer_name = "Alex".to_owned();
+    config.recording_format = RecordingFormat::Flac;
+    config.recording_dir = " /tmp/takes ".to_owned();
+
+    let path = config.recording_path(std::time::UNIX_EPOCH).unwrap();
+    assert_eq!(path, std::path::Path::new("/tmp/takes/Alex-1970-01-01_00-00-00.flac"));
+}
+
+#[test]
+fn hello_follows_the_session_settings() {
+    let mut sender = SessionConfig::new(SessionRole::Sender);
+    sender.frame_size = 128;
+    sender.streams = 3;
+
+    let mut receiver = SessionConfig::new(SessionRole::Receiver);
+    receiver.frame_size = 100_000;
+
+    let sender_hello = sender.hello("1.0.0", 48000.0, 1);
+    let receiver_hello = receiver.hello("1.0.0", 48000.0, 2);
+
+    assert!(sender_hello.sends_audio);
+    assert!(!receiver_hello.sends_audio);
+    assert_eq!(sender_hello.codecs, vec![sender.codec]);
+    assert_eq!(receiver_hello.codecs, SUPPORTED_CODECS.to_vec());
+    assert_eq!(receiver_hello.max_frame_size, MAX_FRAME_SIZE);
+
+    assert_eq!(receiver_hello.streams, MAX_STREAMS as u16);
+
+    let format = negotiate(&sender_hello, &receiver_hello).unwrap();
+    assert_eq!(format.frame_size, 128);
+    assert_eq!(format.streams, 3);
+}
@@ -0,0 +1,74 @@
+use shared::*;
+
+const MS: u64 = 1_000_000;
+
+#[test]
+fn gaps_in_the_sequence_count_as_loss() {
+    let mut monitor = StreamMonitor::default();
+
+    // 4 and 6 never arrive, 3 shows up late
+    for seq in [0, 1, 2, 5, 3, 7] {
+        monitor.on_packet(seq, 480, 1000, 48000.0, seq as u64 * 10 * MS);
+    }
+
+    assert_eq!(monitor.received(), 6);
+    assert_eq!(monitor.bytes(), 6000);
+    assert_eq!(monitor.lost(), 2);
+    assert_eq!(monitor.loss_percent(), 25.0);
+}
+
+#[test]
+fn loss_survives_sequence_wraparound() {
+    let mut monitor = StreamMonitor::default();
+
+    for seq in [u32::MAX - 1, u32::MAX, 1, 2] {
+        monitor.on_packet(seq, 480, 1000, 48000.0, 0);
+    }
+
+    assert_eq!(monitor.lost(), 1);
+}
+
+#[test]
+fn steady_packets_have_no_jitter() {
+    let mut steady = StreamMonitor::default();
+    let mut bursty = StreamMonitor::default();
+
+    // 480 samples at 48 kHz is a packet every 10 ms
+    for seq in 0..50u32 {
+        steady.on_packet(seq, 480, 1000, 48000.0, seq as u64 * 10 * MS);
+        bursty.on_packet(seq, 480, 1000, 48000.0, (seq / 2) as u64 * 20 * MS);
+    }
+
+    assert!(steady.jitter_ms() < 0.001);
+    assert!((5.0..=10.0).contains(&bursty.jitter_ms()));
+}
+
+#[test]
+fn history_keeps_a_bounded_latency_graph_and_bitrates() {
+    let mut history = StatsHistory::default();
+    assert_eq!(history.bitrate_kbps(), (0.0, 0.0));
+
+    for poll in 0..STATS_HISTORY_LEN as u64 + 10 {
+        let network = NetworkStats { bytes_sent: poll * 1000, bytes_received: poll * 500, ..Default::default() };
+        history.push(network, poll as f32, poll * 500 * MS);
+    }
+
+    let latency = history.latency_ms();
+    assert_eq!(latency.len(), STATS_HISTORY_LEN);
+    assert_eq!(latency.last(), Some(&(STATS_HISTORY_LEN as f32 + 9.0)));
+
+    // 1000 bytes every half second is 16 kbit/s
+    assert_eq!(history.bitrate_kbps(), (16.0, 8.0));
+
+    history.clear();
+    assert!(history.latency_ms().is_empty());
+}
+
+#[test]
+fn stats_from_older_receivers_still_parse() {
+    let frame = [&[PROTOCOL_VERSION][..], br#"{"type":"stats","buffered_ms":20.0,"packets_received":7}"#].concat();
+
+    let Ok(ControlMessage::Stats(stats)) = ControlMessage::parse(&frame) else { panic!("stats report did not parse") };
+    assert_eq!(stats.packets_received, 7);
+    assert_eq!(stats.packets_lost, 0);
+}
@@ -0,0 +1,106 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use shared::*;
+
+const NOW: u64 = 1_700_000_000;
+
+const SDP: &str = "v=0\r\no=- 1 2 IN IP4 0.0.0.0\r\na=fingerprint:sha-256 ab:cd:ef\r\nm=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n";
+
+// Offer made by `offerer`, answered by whoever opened it
+fn answer_for(offerer: &SessionToken, info: &str) -> String {
+    let offer = offerer.token("offer info".to_owned(), true);
+    let (answerer, offer_info) = SessionToken::open_offer(&offer, NOW, None).unwrap();
+    assert_eq!(offer_info, "offer info");
+
+    answerer.token(info.to_owned(), false)
+}
+
+#[test]
+fn answers_to_our_offer_are_accepted_once() {
+    let offerer = SessionToken::generate(NOW, None);
+    let answer = answer_for(&offerer, SDP);
+
+    assert_eq!(offerer.open_answer(&answer, NOW + 1).unwrap(), SDP);
+    offerer.accept_answer(SDP).unwrap();
+
+    // The nonce is used up, neither the same answer nor a fresh one gets in
+    assert!(matches!(offerer.open_answer(&answer, NOW + 2), Err(TokenError::AlreadyAnswered)));
+    assert!(matches!(offerer.accept_answer(SDP), Err(TokenError::AlreadyAnswered)));
+}
+
+#[test]
+fn answers_to_another_session_are_rejected() {
+    let offerer = SessionToken::generate(NOW, None);
+    let answer = answer_for(&SessionToken::generate(NOW, None), SDP);
+
+    assert!(matches!(offerer.open_answer(&answer, NOW), Err(TokenError::WrongSession)));
+}
+
+#[test]
+fn tokens_expire() {
+    let offerer = SessionToken::generate(NOW, None);
+    let offer = offerer.token("offer info".to_owned(), true);
+
+    assert!(SessionToken::open_offer(&offer, NOW + TOKEN_LIFETIME_SECS, None).is_ok());
+    assert!(matches!(SessionToken::open_offer(&offer, NOW + TOKEN_LIFETIME_SECS + 1, None), Err(TokenError::Expired)));
+
+    let answer = answer_for(&offerer, SDP);
+    assert!(matches!(offerer.open_answer(&answer, NOW + TOKEN_LIFETIME_SECS + 1), Err(TokenError::Expired)));
+
+    // The offer's deadline holds even for an answer that claims a later one
+    let json = String::from_utf8(STANDARD.decode(&answer).unwrap()).unwrap();
+    let later = (NOW + TOKEN_LIFETIME_SECS).to_string();
+    assert!(json.contains(&later));
+    let extended = STANDARD.encode(json.replace(&later, &(NOW + 10 * TOKEN_LIFETIME_SECS).to_string()));
+    assert!(matches!(offerer.open_answer(&extended, NOW + TOKEN_LIFETIME_SECS + 1), Err(TokenError::Expired)));
+}
+
+#[test]
+fn offers_and_answers_are_not_interchangeable() {
+    let offerer = SessionToken::generate(NOW, None);
+    let offer = offerer.token("offer info".to_owned(), true);
+    let answer = answer_for(&offerer, SDP);
+
+    assert!(matches!(SessionToken::open_offer(&answer, NOW, None), Err(TokenError::Malformed(_))));
+    assert!(matches!(offerer.open_answer(&offer, NOW), Err(TokenError::Malformed(_))));
+    assert!(matches!(offerer.open_answer("not a token", NOW), Err(TokenError::Malformed(_))));
+}
+
+#[test]
+fn with_a_passphrase_only_its_holders_can_answer() {
+    let offerer = SessionToken::generate(NOW, Some("correct horse"));
+    let offer = offerer.token("offer info".to_owned(), true);
+
+    assert!(matches!(SessionToken::open_offer(&offer, NOW, Some("battery staple")), Err(TokenError::BadSignature)));
+    assert!(matches!(SessionToken::open_offer(&offer, NOW, None), Err(TokenError::PassphraseRequired)));
+
+    // Someone who saw the offer knows its nonce, but without the passphrase cannot sign an answer to it
+    let json = String::from_utf8(STANDARD.decode(offerer.token("offer info".to_owned(), false)).unwrap()).unwrap();
+    let unsigned = json.split(",\"signature\"").next().unwrap().to_owned() + "}";
+    assert!(matches!(offerer.open_answer(&STANDARD.encode(unsigned), NOW), Err(TokenError::BadSignature)));
+
+    let (answerer, offer_info) = SessionToken::open_offer(&offer, NOW, Some("correct horse")).unwrap();
+    assert_eq!(offer_info, "offer info");
+
+    // Altering a signed answer, here to stretch its deadline, breaks the signature
+    let answer = answerer.token(SDP.to_owned(), false);
+    let json = String::from_utf8(STANDARD.decode(&answer).unwrap()).unwrap();
+    let later = (NOW + TOKEN_LIFETIME_SECS).to_string();
+    let extended = STANDARD.encode(json.replace(&later, &(NOW + 10 * TOKEN_LIFETIME_SECS).to_string()));
+    assert!(matches!(offerer.open_answer(&extended, NOW), Err(TokenError::BadSignature)));
+
+    assert_eq!(offerer.open_answer(&answer, NOW).unwrap(), SDP);
+    offerer.accept_answer(SDP).unwrap();
+}
+
+#[test]
+fn allow_list_checks_the_peer_certificate() {
+    assert_eq!(sdp_fingerprints(SDP), vec!["sha-256 AB:CD:EF".to_owned()]);
+
+    let offerer = SessionToken::generate(NOW, None);
+    offerer.set_allowed_fingerprints(vec!["SHA-256 12:34:56".to_owned()]);
+    assert!(matches!(offerer.accept_answer(SDP), Err(TokenError::UntrustedPeer(fingerprint)) if fingerprint == "sha-256 AB:CD:EF"));
+
+    // Rejection does not use up the session, and case does not matter
+    offerer.set_allowed_fingerprints(vec!["sha-256 12:34:56".to_owned(), "sha-256 ab:cd:ef".to_owned()]);
+    offerer.accept_answer(SDP).unwrap();
+}